@@ -79,6 +79,8 @@ impl Command {
 pub struct USBBackend {
     close_chan: tokio::sync::broadcast::Sender<()>,
     command_tx: tokio::sync::mpsc::Sender<Command>,
+    /// 这个连接上是否已经激活了 `> 0x80` 的命令
+    pub(crate) high_commands: tokio::sync::OnceCell<()>,
 }
 
 impl USBBackend {
@@ -445,8 +447,22 @@ impl USBBackend {
         Ok(USBBackend {
             close_chan,
             command_tx: cmd_tx,
+            high_commands: tokio::sync::OnceCell::new(),
         })
     }
+
+    /// 不连接设备的后端, 发送的命令从返回的通道里读出, 用于测试
+    #[cfg(test)]
+    pub(crate) fn mock() -> (Self, tokio::sync::mpsc::Receiver<Command>) {
+        let (close_chan, _) = tokio::sync::broadcast::channel(1);
+        let (command_tx, command_rx) = tokio::sync::mpsc::channel(16);
+        let b = USBBackend {
+            close_chan,
+            command_tx,
+            high_commands: tokio::sync::OnceCell::new(),
+        };
+        (b, command_rx)
+    }
     pub async fn new(selector: USBSelector) -> Result<Self, BackendError> {
         let x = tokio::task::spawn_blocking(|| Self::new_usb_backend_blocking(selector)).await?;
         x
@@ -0,0 +1,115 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! 条码直接画成位图, 不经过抖动
+//!
+//! 模块宽度取整到打印点, 留白按模块数计算, 所以也是整数个点;
+//! 一维条码只在左右留白, 方便和其它位图拼接
+
+use super::Barcode;
+use crate::image_proc::Bitmap;
+
+/// 位图尺寸, 单位都是点
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BitmapOptions {
+    /// 每个模块的点数
+    pub module: u32,
+    /// 一维条码的高度, 二维条码忽略
+    pub height: u32,
+    /// 是否包括留白
+    pub quiet_zone: bool,
+}
+
+impl BitmapOptions {
+    pub fn new(module: u32, height: u32) -> Self {
+        BitmapOptions {
+            module: module.max(1),
+            height: height.max(1),
+            quiet_zone: true,
+        }
+    }
+
+    /// 按毫米和打印机的分辨率 (dpi) 创建, 模块宽度取最接近的整数点, 至少 1 点
+    pub fn from_mm(module: f32, height: f32, dpi: u16) -> Self {
+        let dots = |mm: f32| (mm / 25.4 * dpi as f32).round().max(1.0) as u32;
+        Self::new(dots(module), dots(height))
+    }
+
+    pub fn without_quiet_zone(mut self) -> Self {
+        self.quiet_zone = false;
+        self
+    }
+}
+
+impl Barcode {
+    /// 画成位图, 黑色是深色模块
+    pub fn to_bitmap(&self, opts: &BitmapOptions) -> Bitmap {
+        let m = opts.module;
+        let q = if opts.quiet_zone {
+            self.quiet_zone() * m
+        } else {
+            0
+        };
+        let (qy, row) = if self.is_linear() {
+            (0, opts.height)
+        } else {
+            (q, m)
+        };
+        let mut out = Bitmap::new(self.width() * m + 2 * q, self.height() * row + 2 * qy);
+        for (x, y, len) in self.runs() {
+            let left = q + x * m;
+            for py in qy + y * row..qy + (y + 1) * row {
+                for px in left..left + len * m {
+                    out.set_pixel(px, py, true);
+                }
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::BitmapOptions;
+    use crate::{
+        barcode::{Barcode, QrEcLevel, Symbology},
+        image_proc::{Bitmap, BlitOp},
+    };
+
+    #[test]
+    fn test_options() {
+        // DP27P: 300 dpi 时 0.25mm 是 3 点
+        assert_eq!(
+            BitmapOptions::from_mm(0.25, 10.0, 300),
+            BitmapOptions::new(3, 118)
+        );
+        assert_eq!(BitmapOptions::from_mm(0.25, 10.0, 203).module, 2);
+        assert_eq!(BitmapOptions::from_mm(0.01, 10.0, 203).module, 1);
+    }
+
+    #[test]
+    fn test_to_bitmap() {
+        let code = Barcode::encode(Symbology::Code128, "A").unwrap();
+        let bm = code.to_bitmap(&BitmapOptions::new(3, 20));
+        assert_eq!((bm.width(), bm.height()), ((46 + 20) * 3, 20));
+        // 留白之后是两个模块宽的起始条
+        assert!(!bm.get_pixel(29, 0) && bm.get_pixel(30, 0) && bm.get_pixel(35, 19));
+        assert!(!bm.get_pixel(36, 0));
+        // 每一行都一样
+        assert!((1..20).all(|y| bm.same_lines(0, y)));
+
+        let qr = Barcode::encode(Symbology::QrCode(QrEcLevel::M), "dz-print").unwrap();
+        let opts = BitmapOptions::new(2, 0).without_quiet_zone();
+        let bm = qr.to_bitmap(&opts);
+        assert_eq!((bm.width(), bm.height()), (42, 42));
+        for (x, y) in [(0, 0), (6, 6), (1, 1), (2, 2), (20, 8)] {
+            assert_eq!(bm.get_pixel(x * 2 + 1, y * 2 + 1), qr.get(x, y));
+        }
+
+        // 和其它位图拼接
+        let mut label = Bitmap::new(100, 100);
+        label.blit(&bm, 10, 10, BlitOp::Or);
+        assert!(label.get_pixel(10, 10) && !label.get_pixel(9, 9));
+    }
+}
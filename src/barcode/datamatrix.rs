@@ -0,0 +1,402 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Data Matrix (ECC200), 只支持正方形和 ASCII 编码, 最大 104x104
+//!
+//! 码字排布按照 ISO/IEC 16022 附录 F 的算法
+
+use super::BarcodeError;
+
+/// (边长, 数据区边长, 数据码字, 纠错码字, 交错块数)
+const SIZES: &[(u32, u32, usize, usize, usize)] = &[
+    (10, 8, 3, 5, 1),
+    (12, 10, 5, 7, 1),
+    (14, 12, 8, 10, 1),
+    (16, 14, 12, 12, 1),
+    (18, 16, 18, 14, 1),
+    (20, 18, 22, 18, 1),
+    (22, 20, 30, 20, 1),
+    (24, 22, 36, 24, 1),
+    (26, 24, 44, 28, 1),
+    (32, 14, 62, 36, 1),
+    (36, 16, 86, 42, 1),
+    (40, 18, 114, 48, 1),
+    (44, 20, 144, 56, 1),
+    (48, 22, 174, 68, 1),
+    (52, 24, 204, 84, 2),
+    (64, 14, 280, 112, 2),
+    (72, 16, 368, 144, 4),
+    (80, 18, 456, 192, 4),
+    (88, 20, 576, 224, 4),
+    (96, 22, 696, 272, 4),
+    (104, 24, 816, 336, 6),
+];
+
+/// 填充码字
+const PAD: u8 = 129;
+/// 下一个字符是扩展 ASCII (128..=255)
+const UPPER_SHIFT: u8 = 235;
+
+/// ASCII 编码, 两个连续的数字合成一个码字
+pub fn encode_ascii(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![];
+    let mut i = 0;
+    while i < data.len() {
+        let c = data[i];
+        match data.get(i + 1) {
+            Some(d) if c.is_ascii_digit() && d.is_ascii_digit() => {
+                out.push(130 + (c - b'0') * 10 + (d - b'0'));
+                i += 2;
+                continue;
+            }
+            _ => {}
+        }
+        if c >= 128 {
+            out.push(UPPER_SHIFT);
+            out.push(c - 127);
+        } else {
+            out.push(c + 1);
+        }
+        i += 1;
+    }
+    out
+}
+
+/// 填充到 `capacity` 个码字, 第一个之后的填充码字要随机化
+fn pad(codewords: &mut Vec<u8>, capacity: usize) {
+    if codewords.len() < capacity {
+        codewords.push(PAD);
+    }
+    while codewords.len() < capacity {
+        let pos = codewords.len() + 1;
+        let r = (149 * pos) % 253 + 1;
+        let v = PAD as usize + r;
+        codewords.push(if v > 254 { v - 254 } else { v } as u8);
+    }
+}
+
+/// GF(256), 本原多项式 x^8 + x^5 + x^3 + x^2 + 1
+struct Gf256 {
+    exp: [u8; 512],
+    log: [u8; 256],
+}
+
+impl Gf256 {
+    fn new() -> Self {
+        let mut exp = [0; 512];
+        let mut log = [0; 256];
+        let mut x: u16 = 1;
+        for (i, e) in exp.iter_mut().enumerate() {
+            *e = x as u8;
+            if i < 255 {
+                log[x as usize] = i as u8;
+            }
+            x <<= 1;
+            if x & 0x100 != 0 {
+                x ^= 0x12d;
+            }
+        }
+        Gf256 { exp, log }
+    }
+
+    fn mul(&self, a: u8, b: u8) -> u8 {
+        if a == 0 || b == 0 {
+            return 0;
+        }
+        self.exp[self.log[a as usize] as usize + self.log[b as usize] as usize]
+    }
+}
+
+/// Reed-Solomon 纠错码字, 生成多项式的根是 α^1 ..= α^n
+pub fn reed_solomon(data: &[u8], n: usize) -> Vec<u8> {
+    let gf = Gf256::new();
+    // 系数从最高次开始, g[0] = 1
+    let mut g = vec![1u8];
+    for i in 1..=n {
+        let root = gf.exp[i];
+        let mut next = vec![0; g.len() + 1];
+        for (j, c) in g.iter().enumerate() {
+            next[j] ^= c;
+            next[j + 1] ^= gf.mul(*c, root);
+        }
+        g = next;
+    }
+    let mut ecc = vec![0u8; n];
+    for d in data {
+        let m = d ^ ecc[0];
+        ecc.rotate_left(1);
+        ecc[n - 1] = 0;
+        for (e, c) in ecc.iter_mut().zip(&g[1..]) {
+            *e ^= gf.mul(*c, m);
+        }
+    }
+    ecc
+}
+
+/// 数据和纠错码字, 多个块时交错排列
+fn codewords(mut data: Vec<u8>, capacity: usize, ecc_len: usize, blocks: usize) -> Vec<u8> {
+    pad(&mut data, capacity);
+    let mut out = data.clone();
+    out.resize(capacity + ecc_len, 0);
+    let block_ecc = ecc_len / blocks;
+    for b in 0..blocks {
+        let block: Vec<u8> = data.iter().skip(b).step_by(blocks).copied().collect();
+        for (j, e) in reed_solomon(&block, block_ecc).into_iter().enumerate() {
+            out[capacity + j * blocks + b] = e;
+        }
+    }
+    out
+}
+
+/// 码字在映射矩阵里的排布, 值为 `码字序号 * 10 + 位 (1 是最高位)`, 1 表示固定的深色
+struct Placement {
+    nrow: i32,
+    ncol: i32,
+    array: Vec<u32>,
+}
+
+impl Placement {
+    fn new(nrow: i32, ncol: i32) -> Self {
+        let mut p = Placement {
+            nrow,
+            ncol,
+            array: vec![0; (nrow * ncol) as usize],
+        };
+        p.place();
+        p
+    }
+
+    fn get(&self, row: i32, col: i32) -> u32 {
+        self.array[(row * self.ncol + col) as usize]
+    }
+
+    fn module(&mut self, mut row: i32, mut col: i32, chr: u32, bit: u32) {
+        if row < 0 {
+            row += self.nrow;
+            col += 4 - (self.nrow + 4) % 8;
+        }
+        if col < 0 {
+            col += self.ncol;
+            row += 4 - (self.ncol + 4) % 8;
+        }
+        self.array[(row * self.ncol + col) as usize] = 10 * chr + bit;
+    }
+
+    /// 一个码字的 8 个模块, 位置相对于右下角
+    fn utah(&mut self, row: i32, col: i32, chr: u32) {
+        let cells = [
+            (row - 2, col - 2),
+            (row - 2, col - 1),
+            (row - 1, col - 2),
+            (row - 1, col - 1),
+            (row - 1, col),
+            (row, col - 2),
+            (row, col - 1),
+            (row, col),
+        ];
+        for (bit, (r, c)) in cells.into_iter().enumerate() {
+            self.module(r, c, chr, bit as u32 + 1);
+        }
+    }
+
+    fn corner(&mut self, cells: [(i32, i32); 8], chr: u32) {
+        for (bit, (r, c)) in cells.into_iter().enumerate() {
+            self.module(r, c, chr, bit as u32 + 1);
+        }
+    }
+
+    fn place(&mut self) {
+        let (nrow, ncol) = (self.nrow, self.ncol);
+        let mut chr = 1;
+        let mut row = 4;
+        let mut col = 0;
+        loop {
+            if row == nrow && col == 0 {
+                let cells = [
+                    (nrow - 1, 0),
+                    (nrow - 1, 1),
+                    (nrow - 1, 2),
+                    (0, ncol - 2),
+                    (0, ncol - 1),
+                    (1, ncol - 1),
+                    (2, ncol - 1),
+                    (3, ncol - 1),
+                ];
+                self.corner(cells, chr);
+                chr += 1;
+            }
+            if row == nrow - 2 && col == 0 && ncol % 4 != 0 {
+                let cells = [
+                    (nrow - 3, 0),
+                    (nrow - 2, 0),
+                    (nrow - 1, 0),
+                    (0, ncol - 4),
+                    (0, ncol - 3),
+                    (0, ncol - 2),
+                    (0, ncol - 1),
+                    (1, ncol - 1),
+                ];
+                self.corner(cells, chr);
+                chr += 1;
+            }
+            if row == nrow - 2 && col == 0 && ncol % 8 == 4 {
+                let cells = [
+                    (nrow - 3, 0),
+                    (nrow - 2, 0),
+                    (nrow - 1, 0),
+                    (0, ncol - 2),
+                    (0, ncol - 1),
+                    (1, ncol - 1),
+                    (2, ncol - 1),
+                    (3, ncol - 1),
+                ];
+                self.corner(cells, chr);
+                chr += 1;
+            }
+            if row == nrow + 4 && col == 2 && ncol % 8 == 0 {
+                let cells = [
+                    (nrow - 1, 0),
+                    (nrow - 1, ncol - 1),
+                    (0, ncol - 3),
+                    (0, ncol - 2),
+                    (0, ncol - 1),
+                    (1, ncol - 3),
+                    (1, ncol - 2),
+                    (1, ncol - 1),
+                ];
+                self.corner(cells, chr);
+                chr += 1;
+            }
+            // 向右上
+            loop {
+                if row < nrow && col >= 0 && self.get(row, col) == 0 {
+                    self.utah(row, col, chr);
+                    chr += 1;
+                }
+                row -= 2;
+                col += 2;
+                if !(row >= 0 && col < ncol) {
+                    break;
+                }
+            }
+            row += 1;
+            col += 3;
+            // 向左下
+            loop {
+                if row >= 0 && col < ncol && self.get(row, col) == 0 {
+                    self.utah(row, col, chr);
+                    chr += 1;
+                }
+                row += 2;
+                col -= 2;
+                if !(row < nrow && col >= 0) {
+                    break;
+                }
+            }
+            row += 3;
+            col += 1;
+            if !(row < nrow || col < ncol) {
+                break;
+            }
+        }
+        // 右下角没有用到的 2x2
+        let n = (nrow * ncol) as usize;
+        if self.array[n - 1] == 0 {
+            self.array[n - 1] = 1;
+            self.array[n - ncol as usize - 2] = 1;
+        }
+    }
+}
+
+/// 编码, 返回 `(边长, 模块)`
+pub fn encode(data: &[u8]) -> Result<(u32, Vec<bool>), BarcodeError> {
+    let data = encode_ascii(data);
+    let &(size, region, capacity, ecc_len, blocks) = SIZES
+        .iter()
+        .find(|x| x.2 >= data.len())
+        .ok_or(BarcodeError::TooLong("Data Matrix"))?;
+    let words = codewords(data, capacity, ecc_len, blocks);
+
+    let regions = size / (region + 2);
+    let n = (regions * region) as i32;
+    let placement = Placement::new(n, n);
+    let mut modules = vec![false; (size * size) as usize];
+    let mut set = |x: u32, y: u32, v: bool| modules[(y * size + x) as usize] = v;
+    // 每个数据区的定位图案: 左边和下边实线, 上边和右边虚线
+    let step = region + 2;
+    for i in 0..size {
+        for j in 0..size {
+            let (x, y) = (i % step, j % step);
+            if x == 0 || y == step - 1 {
+                set(i, j, true);
+            } else if y == 0 {
+                set(i, j, x % 2 == 0);
+            } else if x == step - 1 {
+                set(i, j, y % 2 == 1);
+            }
+        }
+    }
+    for row in 0..n {
+        for col in 0..n {
+            let v = placement.get(row, col);
+            let dark = match v {
+                0 => false,
+                1 => true,
+                v => {
+                    let (chr, bit) = (v / 10 - 1, v % 10);
+                    words[chr as usize] >> (8 - bit) & 1 == 1
+                }
+            };
+            let x = col as u32 / region * step + 1 + col as u32 % region;
+            let y = row as u32 / region * step + 1 + row as u32 % region;
+            set(x, y, dark);
+        }
+    }
+    Ok((size, modules))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{codewords, encode, encode_ascii, reed_solomon, Placement, SIZES};
+
+    #[test]
+    fn test_codewords() {
+        assert_eq!(encode_ascii(b"123456"), vec![142, 164, 186]);
+        assert_eq!(encode_ascii(b"A1\xe9"), vec![66, 50, 235, 106]);
+        // ISO/IEC 16022 的例子
+        assert_eq!(reed_solomon(&[142, 164, 186], 5), vec![114, 25, 5, 88, 102]);
+        let w = codewords(vec![66], 3, 5, 1);
+        assert_eq!(&w[..3], &[66, 129, 70]);
+    }
+
+    #[test]
+    fn test_placement() {
+        // 每个码字的 8 位都要放进去, 没有空位
+        for &(size, region, capacity, ecc, _) in SIZES {
+            let n = (size / (region + 2) * region) as i32;
+            let p = Placement::new(n, n);
+            let mut seen = vec![0; capacity + ecc];
+            for v in p.array.iter().filter(|v| **v > 1) {
+                seen[(v / 10 - 1) as usize] |= 1 << (v % 10 - 1);
+            }
+            assert!(seen.iter().all(|x| *x == 0xff), "{size}");
+            let fixed = p.array.iter().filter(|v| **v <= 1).count();
+            assert!(fixed == 0 || fixed == 4, "{size}");
+        }
+    }
+
+    #[test]
+    fn test_encode() {
+        let (size, m) = encode(b"123456").unwrap();
+        assert_eq!(size, 10);
+        let get = |x: u32, y: u32| m[(y * size + x) as usize];
+        // 定位图案
+        assert!((0..10).all(|i| get(0, i) && get(i, 9)));
+        assert!((0..10).all(|i| get(i, 0) == (i % 2 == 0) && get(9, i) == (i % 2 == 1)));
+
+        let (size, _) = encode(&[b'x'; 300]).unwrap();
+        assert_eq!(size, 72);
+        assert!(encode(&[b'x'; 900]).is_err());
+    }
+}
@@ -0,0 +1,393 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! 一维条码, 返回每个模块是否为深色
+
+use super::BarcodeError;
+
+/// Code 128 的条空宽度, 从条开始, 序号是码字的值, 103..=105 是起始符
+const CODE128_PATTERNS: [&[u8; 6]; 106] = [
+    b"212222", b"222122", b"222221", b"121223", b"121322", b"131222", b"122213", b"122312",
+    b"132212", b"221213", b"221312", b"231212", b"112232", b"122132", b"122231", b"113222",
+    b"123122", b"123221", b"223211", b"221132", b"221231", b"213212", b"223112", b"312131",
+    b"311222", b"321122", b"321221", b"312212", b"322112", b"322211", b"212123", b"212321",
+    b"232121", b"111323", b"131123", b"131321", b"112313", b"132113", b"132311", b"211313",
+    b"231113", b"231311", b"112133", b"112331", b"132131", b"113123", b"113321", b"133121",
+    b"313121", b"211331", b"231131", b"213113", b"213311", b"213131", b"311123", b"311321",
+    b"331121", b"312113", b"312311", b"332111", b"314111", b"221411", b"431111", b"111224",
+    b"111422", b"121124", b"121421", b"141122", b"141221", b"112214", b"112412", b"122114",
+    b"122411", b"142112", b"142211", b"241211", b"221114", b"413111", b"241112", b"134111",
+    b"111242", b"121142", b"121241", b"114212", b"124112", b"124211", b"411212", b"421112",
+    b"421211", b"212141", b"214121", b"412121", b"111143", b"111341", b"131141", b"114113",
+    b"114311", b"411113", b"411311", b"113141", b"114131", b"311141", b"411131", b"211412",
+    b"211214", b"211232",
+];
+const CODE128_STOP: &[u8; 7] = b"2331112";
+const CODE128_START_B: u8 = 104;
+const CODE128_START_C: u8 = 105;
+const CODE128_CODE_B: u8 = 100;
+const CODE128_CODE_C: u8 = 99;
+
+/// EAN-13 左侧奇校验 (L) 的数字编码, R 是 L 取反, G 是 R 倒序
+const EAN_L: [u8; 10] = [
+    0b0001101, 0b0011001, 0b0010011, 0b0111101, 0b0100011, 0b0110001, 0b0101111, 0b0111011,
+    0b0110111, 0b0001011,
+];
+/// 第一位数字决定左侧六位用 L 还是 G, 1 表示 G
+const EAN_PARITY: [u8; 10] = [
+    0b000000, 0b001011, 0b001101, 0b001110, 0b010011, 0b011001, 0b011100, 0b010101, 0b010110,
+    0b011010,
+];
+
+/// Code 39 的字符和宽窄图案, 从条开始, 1 是宽
+const CODE39: [(char, u16); 44] = [
+    ('0', 0b000110100),
+    ('1', 0b100100001),
+    ('2', 0b001100001),
+    ('3', 0b101100000),
+    ('4', 0b000110001),
+    ('5', 0b100110000),
+    ('6', 0b001110000),
+    ('7', 0b000100101),
+    ('8', 0b100100100),
+    ('9', 0b001100100),
+    ('A', 0b100001001),
+    ('B', 0b001001001),
+    ('C', 0b101001000),
+    ('D', 0b000011001),
+    ('E', 0b100011000),
+    ('F', 0b001011000),
+    ('G', 0b000001101),
+    ('H', 0b100001100),
+    ('I', 0b001001100),
+    ('J', 0b000011100),
+    ('K', 0b100000011),
+    ('L', 0b001000011),
+    ('M', 0b101000010),
+    ('N', 0b000010011),
+    ('O', 0b100010010),
+    ('P', 0b001010010),
+    ('Q', 0b000000111),
+    ('R', 0b100000110),
+    ('S', 0b001000110),
+    ('T', 0b000010110),
+    ('U', 0b110000001),
+    ('V', 0b011000001),
+    ('W', 0b111000000),
+    ('X', 0b010010001),
+    ('Y', 0b110010000),
+    ('Z', 0b011010000),
+    ('-', 0b010000101),
+    ('.', 0b110000100),
+    (' ', 0b011000100),
+    ('$', 0b010101000),
+    ('/', 0b010100010),
+    ('+', 0b010001010),
+    ('%', 0b000101010),
+    ('*', 0b010010100),
+];
+/// Code 39 和 ITF 宽条的模块数, 标准要求宽窄比 2:1 到 3:1
+const WIDE: usize = 3;
+
+/// ITF 的数字, 五个元素里两个宽
+const ITF_DIGITS: [u8; 10] = [
+    0b00110, 0b10001, 0b01001, 0b11000, 0b00101, 0b10100, 0b01100, 0b00011, 0b10010, 0b01010,
+];
+
+/// 把条空宽度展开成模块
+fn widths_to_bars(widths: &[u8], bars: &mut Vec<bool>) {
+    for (i, w) in widths.iter().enumerate() {
+        bars.extend(std::iter::repeat_n(i % 2 == 0, (w - b'0') as usize));
+    }
+}
+
+/// 把 `len` 个元素的宽窄图案展开成模块, 从条开始, 最高位是第一个元素
+fn push_wide_narrow(bits: u16, len: u32, bars: &mut Vec<bool>) {
+    for i in (0..len).rev() {
+        let w = if bits >> i & 1 == 1 { WIDE } else { 1 };
+        bars.extend(std::iter::repeat_n((len - 1 - i).is_multiple_of(2), w));
+    }
+}
+
+fn push_bits(bits: u8, len: u32, bars: &mut Vec<bool>) {
+    bars.extend((0..len).rev().map(|i| bits >> i & 1 == 1));
+}
+
+/// Code 128 码字, 包括起始符和校验符
+///
+/// 只用 B 和 C 字符集, 连续 4 个以上的数字切换到 C
+pub fn code128_values(data: &str) -> Result<Vec<u8>, BarcodeError> {
+    let bytes = data.as_bytes();
+    if let Some(c) = data.chars().find(|c| !(' '..='\x7f').contains(c)) {
+        return Err(BarcodeError::InvalidCharacter("Code 128", c));
+    }
+    let digits = |i: usize| bytes[i..].iter().take_while(|x| x.is_ascii_digit()).count();
+    let mut c_set = !bytes.is_empty() && digits(0) >= 4;
+    let mut values = vec![if c_set {
+        CODE128_START_C
+    } else {
+        CODE128_START_B
+    }];
+    let mut i = 0;
+    while i < bytes.len() {
+        let run = digits(i);
+        if c_set {
+            if run >= 2 {
+                values.push((bytes[i] - b'0') * 10 + bytes[i + 1] - b'0');
+                i += 2;
+            } else {
+                values.push(CODE128_CODE_B);
+                c_set = false;
+            }
+        } else if run >= 4 && run % 2 == 0 {
+            values.push(CODE128_CODE_C);
+            c_set = true;
+        } else {
+            values.push(bytes[i] - b' ');
+            i += 1;
+        }
+    }
+    let sum = values
+        .iter()
+        .enumerate()
+        .map(|(i, v)| i.max(1) * *v as usize)
+        .sum::<usize>();
+    values.push((sum % 103) as u8);
+    Ok(values)
+}
+
+/// Code 128, 不包括留白
+pub fn code128(data: &str) -> Result<Vec<bool>, BarcodeError> {
+    let mut bars = vec![];
+    for v in code128_values(data)? {
+        widths_to_bars(CODE128_PATTERNS[v as usize], &mut bars);
+    }
+    widths_to_bars(CODE128_STOP, &mut bars);
+    Ok(bars)
+}
+
+/// EAN 和 UPC 的校验位, `digits` 不包括校验位
+pub fn ean_check_digit(digits: &[u8]) -> u8 {
+    let sum: u32 = digits
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(i, d)| *d as u32 * if i % 2 == 0 { 3 } else { 1 })
+        .sum();
+    ((10 - sum % 10) % 10) as u8
+}
+
+/// 解析数字, 没有校验位时补上, 有校验位时检查
+pub(crate) fn digits_with_check(
+    name: &'static str,
+    data: &str,
+    len: usize,
+) -> Result<Vec<u8>, BarcodeError> {
+    if let Some(c) = data.chars().find(|c| !c.is_ascii_digit()) {
+        return Err(BarcodeError::InvalidCharacter(name, c));
+    }
+    let mut digits: Vec<u8> = data.bytes().map(|x| x - b'0').collect();
+    let check = match digits.len() {
+        x if x == len - 1 => ean_check_digit(&digits),
+        x if x == len => ean_check_digit(&digits[..len - 1]),
+        x => {
+            let expected = match len {
+                12 => "11 or 12",
+                13 => "12 or 13",
+                _ => "13 or 14",
+            };
+            return Err(BarcodeError::InvalidLength(name, expected, x));
+        }
+    };
+    match digits.get(len - 1) {
+        None => digits.push(check),
+        Some(x) if *x != check => return Err(BarcodeError::CheckDigit(check)),
+        _ => {}
+    }
+    Ok(digits)
+}
+
+/// Code 39, 不加校验字符, 字符之间隔一个窄空
+pub fn code39(data: &str) -> Result<Vec<bool>, BarcodeError> {
+    let pattern = |c: char| CODE39.iter().find(|(x, _)| *x == c).map(|(_, p)| *p);
+    let mut patterns = vec![pattern('*').unwrap()];
+    for c in data.chars() {
+        match pattern(c) {
+            Some(p) if c != '*' => patterns.push(p),
+            _ => return Err(BarcodeError::InvalidCharacter("Code 39", c)),
+        }
+    }
+    patterns.push(pattern('*').unwrap());
+    let mut bars = vec![];
+    for (i, p) in patterns.into_iter().enumerate() {
+        if i > 0 {
+            bars.push(false);
+        }
+        push_wide_narrow(p, 9, &mut bars);
+    }
+    Ok(bars)
+}
+
+/// EAN-13, 12 位数字时自动补上校验位
+pub fn ean13(data: &str) -> Result<Vec<bool>, BarcodeError> {
+    Ok(ean13_bars(&digits_with_check("EAN-13", data, 13)?))
+}
+
+/// UPC-A, 11 位数字时自动补上校验位; 等于第一位是 0 的 EAN-13
+pub fn upca(data: &str) -> Result<Vec<bool>, BarcodeError> {
+    let mut digits = digits_with_check("UPC-A", data, 12)?;
+    digits.insert(0, 0);
+    Ok(ean13_bars(&digits))
+}
+
+/// ITF-14 (交叉二五码), 13 位数字时自动补上校验位, 没有保护框
+pub fn itf14(data: &str) -> Result<Vec<bool>, BarcodeError> {
+    let digits = digits_with_check("ITF-14", data, 14)?;
+    let mut bars = vec![];
+    // 起始符: 窄条 窄空 窄条 窄空
+    push_wide_narrow(0b0000, 4, &mut bars);
+    for pair in digits.chunks(2) {
+        let (a, b) = (ITF_DIGITS[pair[0] as usize], ITF_DIGITS[pair[1] as usize]);
+        for i in (0..5).rev() {
+            let bit = |x: u8| (x >> i & 1) as u16;
+            push_wide_narrow(bit(a) << 1 | bit(b), 2, &mut bars);
+        }
+    }
+    // 终止符: 宽条 窄空 窄条
+    push_wide_narrow(0b100, 3, &mut bars);
+    Ok(bars)
+}
+
+fn ean13_bars(digits: &[u8]) -> Vec<bool> {
+    let parity = EAN_PARITY[digits[0] as usize];
+    let mut bars = vec![];
+    push_bits(0b101, 3, &mut bars);
+    for (i, d) in digits[1..7].iter().enumerate() {
+        let l = EAN_L[*d as usize];
+        let code = if parity >> (5 - i) & 1 == 1 {
+            // G: R 倒序
+            (!l & 0x7f).reverse_bits() >> 1
+        } else {
+            l
+        };
+        push_bits(code, 7, &mut bars);
+    }
+    push_bits(0b01010, 5, &mut bars);
+    for d in &digits[7..] {
+        push_bits(!EAN_L[*d as usize] & 0x7f, 7, &mut bars);
+    }
+    push_bits(0b101, 3, &mut bars);
+    bars
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        code128, code128_values, code39, ean13, ean_check_digit, itf14, upca, CODE128_PATTERNS,
+        CODE39, ITF_DIGITS,
+    };
+    use crate::barcode::BarcodeError;
+
+    fn to_string(bars: &[bool]) -> String {
+        bars.iter().map(|x| if *x { '1' } else { '0' }).collect()
+    }
+
+    #[test]
+    fn test_code128_table() {
+        for (i, p) in CODE128_PATTERNS.iter().enumerate() {
+            let w: Vec<u32> = p.iter().map(|x| (x - b'0') as u32).collect();
+            assert_eq!(w.iter().sum::<u32>(), 11, "{i}");
+            // 条的总宽度是偶数
+            assert_eq!((w[0] + w[2] + w[4]) % 2, 0, "{i}");
+            assert_eq!(CODE128_PATTERNS.iter().filter(|x| *x == p).count(), 1);
+        }
+    }
+
+    #[test]
+    fn test_code128() {
+        // 起始符 B, "PJJ123C", 校验符
+        assert_eq!(
+            code128_values("PJJ123C").unwrap(),
+            vec![104, 48, 42, 42, 17, 18, 19, 35, 55]
+        );
+        // 数字用 C
+        assert_eq!(code128_values("123456").unwrap(), vec![105, 12, 34, 56, 44]);
+        assert_eq!(
+            code128_values("AB12345").unwrap(),
+            vec![104, 33, 34, 17, 99, 23, 45, 7]
+        );
+        assert_eq!(
+            code128_values("1234A").unwrap(),
+            vec![105, 12, 34, 100, 33, 102]
+        );
+        assert_eq!(
+            code128("é"),
+            Err(BarcodeError::InvalidCharacter("Code 128", 'é'))
+        );
+        let bars = code128("A").unwrap();
+        // 起始符 + 字符 + 校验符 + 终止符
+        assert_eq!(bars.len(), 11 * 3 + 13);
+        assert!(to_string(&bars).ends_with("1100011101011"));
+    }
+
+    #[test]
+    fn test_code39() {
+        for (c, p) in CODE39 {
+            assert_eq!(p.count_ones(), 3, "{c}");
+            // 第 0, 2, 4, 6, 8 位是条
+            let bars = (p & 0b101010101).count_ones();
+            assert_eq!(bars, if "$/+%".contains(c) { 0 } else { 2 }, "{c}");
+        }
+        let bars = code39("A-1").unwrap();
+        // 每个字符 6 窄 3 宽, 字符之间一个窄空
+        assert_eq!(bars.len(), 5 * (6 + 3 * 3) + 4);
+        assert!(to_string(&bars).starts_with("1000101110111010"));
+        assert_eq!(
+            code39("a"),
+            Err(BarcodeError::InvalidCharacter("Code 39", 'a'))
+        );
+        assert!(code39("*").is_err());
+    }
+
+    #[test]
+    fn test_ean13() {
+        assert_eq!(ean_check_digit(&[4, 0, 0, 6, 3, 8, 1, 3, 3, 3, 9, 3]), 1);
+        let bars = ean13("400638133393").unwrap();
+        assert_eq!(bars, ean13("4006381333931").unwrap());
+        assert_eq!(bars.len(), 95);
+        assert_eq!(
+            to_string(&bars[..17]),
+            // 保护符, 0 (L), 0 (G)
+            "10100011010100111"
+        );
+        assert_eq!(ean13("4006381333932"), Err(BarcodeError::CheckDigit(1)));
+        assert_eq!(
+            ean13("123"),
+            Err(BarcodeError::InvalidLength("EAN-13", "12 or 13", 3))
+        );
+    }
+
+    #[test]
+    fn test_upca() {
+        // 03600029145 的校验位是 2
+        let bars = upca("03600029145").unwrap();
+        assert_eq!(bars, upca("036000291452").unwrap());
+        assert_eq!(bars, ean13("0036000291452").unwrap());
+        assert_eq!(upca("036000291453"), Err(BarcodeError::CheckDigit(2)));
+    }
+
+    #[test]
+    fn test_itf14() {
+        for d in ITF_DIGITS {
+            assert_eq!(d.count_ones(), 2);
+        }
+        let bars = itf14("1540014128876").unwrap();
+        assert_eq!(bars, itf14("15400141288763").unwrap());
+        // 起始符 4 + 每对数字 2 * (3 窄 2 宽) + 终止符 (宽 窄 窄)
+        assert_eq!(bars.len(), 4 + 7 * 2 * (3 + 2 * 3) + 5);
+        // 起始符, 然后 1 和 5 交错: 条 宽窄窄窄宽, 空 宽窄宽窄窄
+        assert!(to_string(&bars).starts_with("1010111000101000101110"));
+        assert!(to_string(&bars).ends_with("11101"));
+    }
+}
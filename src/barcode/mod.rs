@@ -0,0 +1,184 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! 条码生成, 结果是模块 (最窄的条/方块) 组成的矩阵, 一维条码只有一行
+//!
+//! 画到页面上时每个模块应该是整数个打印点, 否则扫描不可靠
+
+pub mod bitmap;
+pub mod datamatrix;
+pub mod linear;
+
+use qrcode::{EcLevel, QrCode};
+use thiserror::Error;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum BarcodeError {
+    #[error("{0} cannot encode {1:?}")]
+    InvalidCharacter(&'static str, char),
+    #[error("{0} needs {1} digits, got {2}")]
+    InvalidLength(&'static str, &'static str, usize),
+    #[error("wrong check digit, expected {0}")]
+    CheckDigit(u8),
+    #[error("data too long for {0}")]
+    TooLong(&'static str),
+}
+
+/// 二维码纠错等级
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum QrEcLevel {
+    L,
+    #[default]
+    M,
+    Q,
+    H,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Symbology {
+    Code128,
+    Code39,
+    Ean13,
+    UpcA,
+    Itf14,
+    QrCode(QrEcLevel),
+    DataMatrix,
+}
+
+impl Symbology {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Symbology::Code128 => "Code 128",
+            Symbology::Code39 => "Code 39",
+            Symbology::Ean13 => "EAN-13",
+            Symbology::UpcA => "UPC-A",
+            Symbology::Itf14 => "ITF-14",
+            Symbology::QrCode(_) => "QR Code",
+            Symbology::DataMatrix => "Data Matrix",
+        }
+    }
+
+    /// 四周需要留白的模块数
+    pub fn quiet_zone(&self) -> u32 {
+        match self {
+            Symbology::Code128 | Symbology::Code39 | Symbology::Itf14 => 10,
+            Symbology::Ean13 => 11,
+            Symbology::UpcA => 9,
+            Symbology::QrCode(_) => 4,
+            Symbology::DataMatrix => 1,
+        }
+    }
+}
+
+/// 编码后的条码
+#[derive(Debug, Clone, PartialEq)]
+pub struct Barcode {
+    symbology: Symbology,
+    width: u32,
+    height: u32,
+    /// 按行排列, `true` 是深色
+    modules: Vec<bool>,
+}
+
+impl Barcode {
+    pub fn encode(symbology: Symbology, data: &str) -> Result<Self, BarcodeError> {
+        let row = |bars: Vec<bool>| (bars.len() as u32, bars);
+        let (width, modules) = match symbology {
+            Symbology::Code128 => row(linear::code128(data)?),
+            Symbology::Code39 => row(linear::code39(data)?),
+            Symbology::Ean13 => row(linear::ean13(data)?),
+            Symbology::UpcA => row(linear::upca(data)?),
+            Symbology::Itf14 => row(linear::itf14(data)?),
+            Symbology::QrCode(level) => qr(data, level)?,
+            Symbology::DataMatrix => datamatrix::encode(data.as_bytes())?,
+        };
+        Ok(Barcode {
+            symbology,
+            width,
+            height: modules.len() as u32 / width,
+            modules,
+        })
+    }
+
+    pub fn symbology(&self) -> Symbology {
+        self.symbology
+    }
+
+    /// 宽度, 不包括留白
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    /// 高度, 一维条码是 1
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn is_linear(&self) -> bool {
+        self.height == 1
+    }
+
+    pub fn quiet_zone(&self) -> u32 {
+        self.symbology.quiet_zone()
+    }
+
+    pub fn get(&self, x: u32, y: u32) -> bool {
+        self.modules[(y * self.width + x) as usize]
+    }
+
+    /// 每行连续的深色模块 `(x, y, 长度)`, 画图时可以少画一些矩形
+    pub fn runs(&self) -> Vec<(u32, u32, u32)> {
+        let mut out = vec![];
+        for y in 0..self.height {
+            let mut x = 0;
+            while x < self.width {
+                if !self.get(x, y) {
+                    x += 1;
+                    continue;
+                }
+                let start = x;
+                while x < self.width && self.get(x, y) {
+                    x += 1;
+                }
+                out.push((start, y, x - start));
+            }
+        }
+        out
+    }
+}
+
+fn qr(data: &str, level: QrEcLevel) -> Result<(u32, Vec<bool>), BarcodeError> {
+    let level = match level {
+        QrEcLevel::L => EcLevel::L,
+        QrEcLevel::M => EcLevel::M,
+        QrEcLevel::Q => EcLevel::Q,
+        QrEcLevel::H => EcLevel::H,
+    };
+    let code = QrCode::with_error_correction_level(data, level)
+        .map_err(|_| BarcodeError::TooLong("QR Code"))?;
+    let modules = code
+        .to_colors()
+        .into_iter()
+        .map(|c| c == qrcode::Color::Dark)
+        .collect();
+    Ok((code.width() as u32, modules))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Barcode, QrEcLevel, Symbology};
+
+    #[test]
+    fn test_encode() {
+        let qr = Barcode::encode(Symbology::QrCode(QrEcLevel::M), "dz-print").unwrap();
+        assert_eq!((qr.width(), qr.height()), (21, 21));
+        // 左上角的定位图案
+        assert!(qr.get(0, 0) && qr.get(6, 6) && !qr.get(1, 1) && qr.get(2, 2));
+        assert!(!qr.is_linear());
+
+        let bars = Barcode::encode(Symbology::Code128, "A").unwrap();
+        assert!(bars.is_linear());
+        assert_eq!(bars.runs()[0], (0, 0, 2));
+    }
+}
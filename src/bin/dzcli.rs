@@ -2,7 +2,36 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use clap::{Args, Parser, Subcommand};
+use std::{
+    io::BufRead,
+    ops::ControlFlow,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use dz_print::{
+    backend,
+    command::{self, HostCommand},
+    frontend::scale::PrintArea,
+    image_proc::{
+        cmd_parser::{ExperimentalOpcode, PrintCommand},
+        cmd_render::render_commands,
+        label::{LabelGeometry, MIN_GAP},
+        Bitmap, DitherMode,
+    },
+    pipeline::{
+        battery::{read_battery, read_battery_count, BatteryCurve, BatteryLimits},
+        calibrate::{calibrate, LabelProfile},
+        collect_bitmap, print_stream_with, probe, send_print_command,
+        sensor::{poll_sensors, read_sensors, SensorReading},
+        source::{GrayImageSource, RowSource, TextSource},
+        statistics::{lines_to_metres, read_dpi, statistics},
+        thermal::ThermalLimits,
+        PrintOptions, StreamEncoder,
+    },
+};
+use num_traits::FromPrimitive;
 
 #[derive(Parser, Debug)]
 #[command(version, about = "dz-print Command-Line Interface")]
@@ -35,19 +64,533 @@ enum Subcommands {
     Config,
 
     /// Print a document
-    Print,
+    Print(PrintArgs),
 
     /// List all printers avaliable
     List,
+
+    /// Render a captured print command stream to images
+    Preview(PreviewArgs),
+
+    /// Read head temperature, battery and gap sensor values
+    Sensors(SensorsArgs),
+
+    /// Show battery voltage, estimated charge and charging state
+    Battery,
+
+    /// Show lifetime print statistics (lines, paper length and pages)
+    Stats(StatsArgs),
+
+    /// Measure label length and gap by feeding label stock, save the gap to the printer and
+    /// optionally both to a label profile
+    Calibrate(CalibrateArgs),
+
+    /// (Experimental) Send undocumented print opcodes with sample payloads and record the status
+    Probe(ProbeArgs),
+}
+
+#[derive(Args, Debug)]
+struct PrintArgs {
+    /// Image to print (PNG/JPEG/..., PBM, or raw 1-bpp data with `--raw-width`),
+    /// or text with `--text` (`-` reads from stdin)
+    file: PathBuf,
+
+    /// Dry run: write the bitmap sent to the print head to this file (.png or .pbm)
+    #[arg(long, short)]
+    output: Option<PathBuf>,
+
+    /// Read the input as raw packed 1-bpp rows of this width in dots
+    #[arg(long)]
+    raw_width: Option<u32>,
+
+    /// Dither mode for grayscale images
+    #[arg(long, value_enum, default_value_t = DitherArg::FloydSteinberg)]
+    dither: DitherArg,
+
+    /// Print the input as text, line by line
+    #[arg(long, requires = "font")]
+    text: bool,
+
+    /// Font file for `--text`
+    #[arg(long)]
+    font: Option<PathBuf>,
+
+    /// Font size in dots for `--text`
+    #[arg(long, default_value_t = 24.0)]
+    font_size: f32,
+
+    /// Print head width in dots, used by `--text` and to centre and clip `--label`
+    #[arg(long, default_value_t = 576)]
+    width: u32,
+
+    /// Label size in millimetres as `WIDTHxHEIGHT`, e.g. `40x30` (`40` or height 0 for
+    /// continuous paper)
+    #[arg(long, value_parser = parse_label_size)]
+    label: Option<(f32, f32)>,
+
+    /// Gap between labels in millimetres, at least 0.5
+    #[arg(long, default_value_t = 2.0, requires = "label", value_parser = parse_gap)]
+    gap: f32,
+
+    /// Printer resolution in dots per inch, used to convert `--label` and `--gap` to dots;
+    /// read from the printer by default, 300 for `--output`
+    #[arg(long)]
+    dpi: Option<u16>,
+
+    /// Label length and gap measured by `calibrate --profile`, replacing the height of `--label`
+    /// and `--gap`
+    #[arg(long, requires = "label", conflicts_with = "gap")]
+    profile: Option<PathBuf>,
+
+    /// Label offset from the left of the print head in dots, centred by default
+    #[arg(long, requires = "label")]
+    label_offset: Option<u32>,
+
+    /// Do not check the print head temperature at status checks
+    #[arg(long)]
+    no_thermal: bool,
+
+    /// Pause printing above this head temperature in °C (model default otherwise)
+    #[arg(long)]
+    pause_above: Option<f32>,
+
+    /// Resume a paused job below this head temperature in °C (model default otherwise)
+    #[arg(long)]
+    resume_below: Option<f32>,
+
+    /// Do not check the battery before printing
+    #[arg(long)]
+    no_battery_check: bool,
+
+    /// Refuse to start below this estimated battery charge in percent, unless charging
+    #[arg(long, default_value_t = BatteryLimits::default().min_percent)]
+    min_battery: u8,
+
+    /// Insert a status check every N lines (50 slowest ... 120 fastest)
+    #[arg(long, default_value_t = 100)]
+    breakpoint: u32,
+}
+
+#[derive(Args, Debug)]
+struct PreviewArgs {
+    /// Raw print commands (`0x1b 0x40`, `0x1f 0x2a`, ..., `0x0c`)
+    file: PathBuf,
+
+    /// Output file (.png or .pbm), pages after the first get a `-N` suffix
+    #[arg(long, short)]
+    output: PathBuf,
+
+    /// Print head width in dots
+    #[arg(long, default_value_t = 576)]
+    width: u32,
+}
+
+#[derive(Args, Debug)]
+struct SensorsArgs {
+    /// Keep reading every N seconds until interrupted
+    #[arg(long, value_parser = parse_seconds)]
+    watch: Option<Duration>,
+}
+
+#[derive(Args, Debug)]
+struct StatsArgs {
+    /// Print resolution used to convert lines to metres, read from the printer by default
+    #[arg(long)]
+    dpi: Option<u16>,
+}
+
+#[derive(Args, Debug)]
+struct CalibrateArgs {
+    /// Read the sensor every N dots of feed
+    #[arg(long, default_value_t = 4)]
+    step: u32,
+
+    /// Maximum paper length to feed in millimetres, should cover at least two labels
+    #[arg(long, default_value_t = 120.0)]
+    length: f32,
+
+    /// Only report the result, do not change the paper gap setting
+    #[arg(long)]
+    no_save: bool,
+
+    /// Write the measured label length and gap to this file, for `print --profile`
+    #[arg(long)]
+    profile: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+struct ProbeArgs {
+    /// Opcodes to probe in hex (e.g. `2c`), all undocumented opcodes by default
+    #[arg(long, value_parser = parse_opcode)]
+    opcode: Vec<ExperimentalOpcode>,
+
+    /// Seconds to wait for the printer status after each probe
+    #[arg(long, default_value = "2", value_parser = parse_seconds)]
+    timeout: Duration,
+
+    /// Blank lines fed between probes, so the printed output can be told apart
+    #[arg(long, default_value_t = 24)]
+    feed: u32,
+
+    /// Write the results to this file as tab-separated values
+    #[arg(long, short)]
+    report: Option<PathBuf>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum DitherArg {
+    Threshold,
+    FloydSteinberg,
+}
+
+impl From<DitherArg> for DitherMode {
+    fn from(value: DitherArg) -> Self {
+        match value {
+            DitherArg::Threshold => DitherMode::Threshold,
+            DitherArg::FloydSteinberg => DitherMode::FloydSteinberg,
+        }
+    }
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     tracing_subscriber::fmt::init();
-    let _args = CommandArgs::parse();
-    main_fn().await
+    let args = CommandArgs::parse();
+    main_fn(args).await
+}
+
+async fn main_fn(args: CommandArgs) -> anyhow::Result<()> {
+    match args.command {
+        Subcommands::Print(p) => print(&args.selector, p).await,
+        Subcommands::Preview(p) => preview(p).await,
+        Subcommands::Sensors(p) => show_sensors(&args.selector, p).await,
+        Subcommands::Battery => show_battery(&args.selector).await,
+        Subcommands::Stats(p) => show_statistics(&args.selector, p).await,
+        Subcommands::Calibrate(p) => calibrate_labels(&args.selector, p).await,
+        Subcommands::Probe(p) => probe_opcodes(&args.selector, p).await,
+        _ => Ok(()),
+    }
 }
 
-async fn main_fn() -> anyhow::Result<()> {
+async fn print(selector: &SelectorArgs, args: PrintArgs) -> anyhow::Result<()> {
+    let source = load_source(&args).await?;
+    let mode = args.dither.into();
+    let label = match &args.profile {
+        Some(path) => {
+            let p = LabelProfile::from_json(&tokio::fs::read_to_string(path).await?)?;
+            args.label.map(|(w, _)| (w, p.label_length, p.gap))
+        }
+        None => args.label.map(|(w, h)| (w, h, args.gap)),
+    };
+    let geometry = |(w, h, gap): (f32, f32, f32), dpi: u16| {
+        let g = LabelGeometry::from_mm(w, h, gap, dpi);
+        match args.label_offset {
+            Some(offset) => g.with_offset(offset).within(args.width),
+            None => g.centred(args.width),
+        }
+    };
+    if let Some(output) = &args.output {
+        let mut bitmap = collect_bitmap(source, mode);
+        if let Some(label) = label {
+            let dpi = args.dpi.unwrap_or(PrintArea::default().dpi);
+            bitmap = geometry(label, dpi).fit(&bitmap);
+        }
+        return write_bitmap(output, &bitmap).await;
+    }
+    let b = backend::USBBackend::new(usb_selector(selector)?).await?;
+    let mut encoder = StreamEncoder::new(source, mode, args.breakpoint);
+    if let Some(label) = label {
+        let dpi = match args.dpi {
+            Some(dpi) => dpi,
+            None => read_dpi(&b).await?,
+        };
+        let g = geometry(label, dpi);
+        let (cmd, chan) = backend::Command::without_response(
+            command::Command::new_host(HostCommand::GetSetPrintPaperGap)
+                .package(g.gap_setting(dpi).to_be_bytes().to_vec(), false),
+        );
+        b.push(cmd).await?;
+        chan.await?;
+        encoder = encoder.with_geometry(g);
+    }
+    let model = selector.sn.as_deref().unwrap_or_default();
+    let mut options = PrintOptions::default();
+    if !args.no_thermal {
+        let limits = ThermalLimits::for_model(model);
+        options.thermal = Some(limits.with_pause(
+            args.pause_above.unwrap_or(limits.pause_above),
+            args.resume_below.unwrap_or(limits.resume_below),
+        )?);
+    }
+    if !args.no_battery_check {
+        options.battery = Some(BatteryLimits {
+            min_percent: args.min_battery,
+            ..BatteryLimits::for_model(model)
+        });
+    }
+    let stats = print_stream_with(&b, encoder, &options).await?;
+    println!("printed {} lines, sent {} bytes", stats.lines, stats.bytes);
+    if !stats.throttled.is_zero() {
+        println!(
+            "waited {:.1} s for the print head to cool down",
+            stats.throttled.as_secs_f32()
+        );
+    }
+    println!(
+        "saved {} of {} bytes, RLE would need about {} bytes",
+        stats.encode.saved(),
+        stats.encode.plain_bytes,
+        stats.encode.rle_bytes
+    );
     Ok(())
 }
+
+fn usb_selector(args: &SelectorArgs) -> anyhow::Result<backend::USBSelector> {
+    if let Some(sn) = &args.sn {
+        return Ok(backend::USBSelector::DeviceSerial(sn.clone()));
+    }
+    let (Some(vid), Some(pid)) = (&args.vid, &args.pid) else {
+        anyhow::bail!("please specify a printer with `--sn` or `--vid` and `--pid`");
+    };
+    let parse = |x: &str| u16::from_str_radix(x.trim_start_matches("0x"), 16);
+    Ok(backend::USBSelector::USBID(parse(vid)?, parse(pid)?))
+}
+
+async fn show_sensors(selector: &SelectorArgs, args: SensorsArgs) -> anyhow::Result<()> {
+    let b = backend::USBBackend::new(usb_selector(selector)?).await?;
+    let print = |r: &SensorReading| {
+        let battery = match r.battery_voltage {
+            Some(v) => format!("{v:.2} V"),
+            None => "none".to_string(),
+        };
+        println!(
+            "head {:.1} °C, battery {battery}, charge status {:?}, gap sensor {:?}",
+            r.head_temperature, r.charge_status, r.gap_sensor
+        );
+    };
+    match args.watch {
+        Some(interval) => {
+            poll_sensors(&b, interval, |r| {
+                print(r);
+                ControlFlow::<()>::Continue(())
+            })
+            .await?
+        }
+        None => print(&read_sensors(&b).await?),
+    }
+    Ok(())
+}
+
+async fn show_battery(selector: &SelectorArgs) -> anyhow::Result<()> {
+    let b = backend::USBBackend::new(usb_selector(selector)?).await?;
+    let curve = BatteryCurve::for_model(selector.sn.as_deref().unwrap_or_default());
+    let Some(s) = read_battery(&b, &curve).await? else {
+        println!("no battery");
+        return Ok(());
+    };
+    println!(
+        "battery {:.2} V, about {}%, {:?}, {} cell(s)",
+        s.voltage,
+        s.percent,
+        s.charge,
+        read_battery_count(&b).await?
+    );
+    Ok(())
+}
+
+async fn show_statistics(selector: &SelectorArgs, args: StatsArgs) -> anyhow::Result<()> {
+    let b = backend::USBBackend::new(usb_selector(selector)?).await?;
+    let s = statistics(&b).await?;
+    let dpi = match args.dpi {
+        Some(dpi) => dpi,
+        None => read_dpi(&b).await?,
+    };
+    for (name, lines) in [
+        ("fed", s.work_lines),
+        ("printed", s.print_lines),
+        ("blank", s.null_lines),
+    ] {
+        println!(
+            "{name:>8}: {lines} lines, {:.2} m",
+            lines_to_metres(lines, dpi)
+        );
+    }
+    println!("{:>8}: {}", "pages", s.print_pages);
+    Ok(())
+}
+
+async fn calibrate_labels(selector: &SelectorArgs, args: CalibrateArgs) -> anyhow::Result<()> {
+    let b = backend::USBBackend::new(usb_selector(selector)?).await?;
+    let area = PrintArea::new(read_dpi(&b).await?, 0);
+    let c = calibrate(&b, args.step, area.dots(args.length as f64)).await?;
+    let mm = |dots: u32| dots as f64 / area.dpi as f64 * 25.4;
+    println!(
+        "label length {:.1} mm, gap {:.1} mm, pitch {:.1} mm (sensor value #{})",
+        mm(c.label_length),
+        mm(c.gap),
+        mm(c.pitch()),
+        c.channel
+    );
+    if !args.no_save {
+        let gap = c.geometry(0).gap_setting(area.dpi);
+        let (cmd, chan) = backend::Command::without_response(
+            command::Command::new_host(HostCommand::GetSetPrintPaperGap)
+                .package(gap.to_be_bytes().to_vec(), false),
+        );
+        b.push(cmd).await?;
+        chan.await?;
+        println!("saved paper gap {gap} (0.01 mm)");
+    }
+    if let Some(path) = &args.profile {
+        tokio::fs::write(path, c.profile(area.dpi).to_json()).await?;
+        println!("wrote label profile to {}", path.display());
+    }
+    Ok(())
+}
+
+async fn probe_opcodes(selector: &SelectorArgs, args: ProbeArgs) -> anyhow::Result<()> {
+    let opcodes = if args.opcode.is_empty() {
+        ExperimentalOpcode::ALL.to_vec()
+    } else {
+        args.opcode
+    };
+    let timeout = args.timeout;
+    let b = backend::USBBackend::new(usb_selector(selector)?).await?;
+    let mut report = String::from("opcode\tsent\tstatus\telapsed_ms\n");
+    send_print_command(&b, &PrintCommand::ResetPrinter).await?;
+    for op in opcodes {
+        for c in op.samples() {
+            let r = probe(&b, &c, timeout).await?;
+            let sent: Vec<String> = r.sent.iter().map(|x| format!("{x:02x}")).collect();
+            let status = r
+                .status
+                .map(|x| format!("{x:#04x}"))
+                .unwrap_or_else(|| "timeout".to_string());
+            let line = format!(
+                "{:#04x}\t{}\t{}\t{}",
+                op as u8,
+                sent.join(" "),
+                status,
+                r.elapsed.as_millis()
+            );
+            println!("{line}");
+            report.push_str(&line);
+            report.push('\n');
+            send_print_command(&b, &PrintCommand::FeedLines(args.feed)).await?;
+        }
+    }
+    send_print_command(&b, &PrintCommand::NextPaper).await?;
+    if let Some(path) = &args.report {
+        tokio::fs::write(path, report).await?;
+        println!("wrote report to {}", path.display());
+    }
+    Ok(())
+}
+
+async fn preview(args: PreviewArgs) -> anyhow::Result<()> {
+    let data = tokio::fs::read(&args.file).await?;
+    let pages = render_commands(args.width, &data)?;
+    println!("{} page(s)", pages.len());
+    for (idx, page) in pages.iter().enumerate() {
+        let output = if idx == 0 {
+            args.output.clone()
+        } else {
+            let stem = args
+                .output
+                .file_stem()
+                .unwrap_or_default()
+                .to_string_lossy();
+            let name = match args.output.extension() {
+                Some(ext) => format!("{stem}-{}.{}", idx + 1, ext.to_string_lossy()),
+                None => format!("{stem}-{}", idx + 1),
+            };
+            args.output.with_file_name(name)
+        };
+        write_bitmap(&output, page).await?;
+    }
+    Ok(())
+}
+
+async fn write_bitmap(output: &Path, bitmap: &Bitmap) -> anyhow::Result<()> {
+    let data = if has_extension(output, "pbm") {
+        bitmap.to_pbm()
+    } else {
+        bitmap.to_png()?
+    };
+    tokio::fs::write(output, data).await?;
+    println!(
+        "wrote {}x{} preview to {}",
+        bitmap.width(),
+        bitmap.height(),
+        output.display()
+    );
+    Ok(())
+}
+
+async fn load_source(args: &PrintArgs) -> anyhow::Result<Box<dyn RowSource>> {
+    if args.text {
+        let font = tokio::fs::read(args.font.as_ref().unwrap()).await?;
+        let lines: Box<dyn Iterator<Item = String>> = if args.file == Path::new("-") {
+            Box::new(std::io::stdin().lines().map_while(Result::ok))
+        } else {
+            let f = std::fs::File::open(&args.file)?;
+            Box::new(std::io::BufReader::new(f).lines().map_while(Result::ok))
+        };
+        let source = TextSource::new(font, args.width, args.font_size, lines)?;
+        return Ok(Box::new(source));
+    }
+    let data = tokio::fs::read(&args.file).await?;
+    let im = if let Some(w) = args.raw_width {
+        Bitmap::from_packed(w, &data)?.to_gray_image()
+    } else if has_extension(&args.file, "pbm") {
+        Bitmap::from_pbm(&data)?.to_gray_image()
+    } else {
+        image::load_from_memory(&data)?.into_luma8()
+    };
+    Ok(Box::new(GrayImageSource::new(im)))
+}
+
+fn parse_opcode(s: &str) -> Result<ExperimentalOpcode, String> {
+    let x = u8::from_str_radix(s.trim_start_matches("0x"), 16).map_err(|e| e.to_string())?;
+    ExperimentalOpcode::from_u8(x).ok_or_else(|| format!("`{s}` is not an undocumented opcode"))
+}
+
+/// 大于 0 的秒数
+fn parse_seconds(s: &str) -> Result<Duration, String> {
+    s.trim()
+        .parse::<f32>()
+        .ok()
+        .filter(|x| *x > 0.0)
+        .and_then(|x| Duration::try_from_secs_f32(x).ok())
+        .ok_or_else(|| format!("expected a positive number of seconds, got `{s}`"))
+}
+
+/// 和打印纸间隔设置的范围一样: 0.5mm ~ 655.35mm
+fn parse_gap(s: &str) -> Result<f32, String> {
+    let gap: f32 = s.trim().parse().map_err(|_| format!("invalid gap `{s}`"))?;
+    let min = MIN_GAP as f32 / 100.0;
+    let max = u16::MAX as f32 / 100.0;
+    if !(min..=max).contains(&gap) {
+        return Err(format!(
+            "gap must be between {min}mm and {max}mm, got {gap}mm"
+        ));
+    }
+    Ok(gap)
+}
+
+fn parse_label_size(s: &str) -> Result<(f32, f32), String> {
+    let (w, h) = s.split_once(['x', 'X']).unwrap_or((s, "0"));
+    let parse = |x: &str| {
+        x.trim()
+            .parse::<f32>()
+            .ok()
+            .filter(|x| x.is_finite() && *x >= 0.0)
+            .ok_or_else(|| format!("invalid size `{x}`, expected WIDTHxHEIGHT"))
+    };
+    Ok((parse(w)?, parse(h)?))
+}
+
+fn has_extension(path: &Path, ext: &str) -> bool {
+    path.extension()
+        .is_some_and(|x| x.eq_ignore_ascii_case(ext))
+}
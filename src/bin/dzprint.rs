@@ -83,7 +83,7 @@ async fn main_fn() -> anyhow::Result<()> {
 
     let (cmd, _) = backend::Command::without_response(
         PrintCommand::ResetPrinter
-            .parse()
+            .parse()?
             .unwrap()
             .into_iter()
             .flatten()
@@ -94,7 +94,7 @@ async fn main_fn() -> anyhow::Result<()> {
     let mut errored = false;
 
     for c in parser {
-        if let Some(c) = c.parse() {
+        if let Some(c) = c.parse()? {
             for c in c {
                 let (cmd, _ch) = backend::Command::without_response(c);
                 b.push(cmd).await.ok();
@@ -180,7 +180,7 @@ async fn main_fn() -> anyhow::Result<()> {
 
     let (cmd, _) = backend::Command::without_response(
         PrintCommand::FeedLines(2)
-            .parse()
+            .parse()?
             .unwrap()
             .into_iter()
             .flatten()
@@ -190,7 +190,7 @@ async fn main_fn() -> anyhow::Result<()> {
 
     let (cmd, _) = backend::Command::without_response(
         PrintCommand::NextPaper
-            .parse()
+            .parse()?
             .unwrap()
             .into_iter()
             .flatten()
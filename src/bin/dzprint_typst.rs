@@ -3,672 +3,391 @@
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
 use std::{
-    collections::HashMap,
-    env,
     path::{Path, PathBuf},
+    time::Duration,
 };
 
-use chrono::Local;
+use chrono::{DateTime, FixedOffset};
+use clap::{Parser, ValueEnum};
 use dz_print::{
     backend,
-    command::{self, HostCommand},
-    image_proc::{
-        cmd_parser::{BitmapParser, PrintCommand},
-        Bitmap, DitherMode,
+    frontend::{
+        diagnostics::{compile, CompileError},
+        fonts::Fonts,
+        inputs::{load_records, merge, pairs_to_dict, parse_pair},
+        scale::PrintArea,
+        settings::{page_settings, preview, print_page, PageSettings},
+        watch::Watcher,
+        world::{parse_datetime, TypstWorld},
+    },
+    pipeline::{
+        statistics::{read_dpi, read_print_width},
+        PrintOptions,
     },
 };
 use tiny_skia::Pixmap;
-use typst::{
-    diag::{FileError, FileResult},
-    foundations::{Bytes, Datetime, NativeFunc, NativeFuncData},
-    layout::PagedDocument,
-    syntax::{FileId, Source, VirtualPath},
-    text::{Font, FontBook, FontInfo},
-    utils::{LazyHash, PicoStr},
-    Library, LibraryExt, World,
-};
-
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    tracing_subscriber::fmt::init();
-    main_fn().await
+use tokio::io::{AsyncBufReadExt, BufReader};
+use typst::{foundations::Dict, layout::Page};
+
+/// 监视模式检查文件修改的间隔
+const POLL_INTERVAL: Duration = Duration::from_millis(300);
+/// 半格字符预览时每个字符的宽度 (点), 576 点宽的页面是 144 列
+const BLOCK_SCALE: u32 = 4;
+
+#[derive(Parser, Debug)]
+#[command(version, about = "dz-print for typst")]
+struct Args {
+    /// Typst document to print
+    file: PathBuf,
+
+    /// Root directory for `#import`, `#include`, `image()`..., defaults to the directory of the document
+    #[arg(long)]
+    root: Option<PathBuf>,
+
+    /// Extra package directory (`{namespace}/{name}/{version}/`), searched before the typst defaults
+    #[arg(long)]
+    package_path: Vec<PathBuf>,
+
+    /// Extra directory to search for fonts
+    #[arg(long)]
+    font_path: Vec<PathBuf>,
+
+    /// Only use the embedded fonts and `--font-path`, do not search the system font directories
+    #[arg(long)]
+    no_system_fonts: bool,
+
+    /// Input visible to the document as `sys.inputs.KEY`
+    #[arg(long = "input", value_name = "KEY=VALUE", value_parser = parse_pair)]
+    inputs: Vec<(String, String)>,
+
+    /// Date and time seen by `datetime.today()`, e.g. `2024-05-01`, `2024-05-01 08:30` (local
+    /// time) or `2024-05-01T08:30:00+08:00`, for reproducible renders and reprints; defaults to now
+    #[arg(long, value_parser = parse_datetime)]
+    now: Option<DateTime<FixedOffset>>,
+
+    /// JSON (array of objects) or CSV (with a header row) file, one copy of the document is
+    /// printed for each record, with its fields merged into `sys.inputs`
+    #[arg(long)]
+    data: Option<PathBuf>,
+
+    /// Printer resolution in dots per inch, read from the printer by default
+    #[arg(long)]
+    dpi: Option<u16>,
+
+    /// Print head width in dots, read from the printer by default
+    #[arg(long)]
+    head_width: Option<u32>,
+
+    /// Scale every page to the print head width instead of printing it at its real size
+    #[arg(long)]
+    fit: bool,
+
+    /// Write the 1-bit preview, exactly as it would be printed, to this PNG or PBM file instead
+    /// of printing; later pages get `-2`, `-3`... suffixes
+    #[arg(long)]
+    preview: Option<PathBuf>,
+
+    /// Show the 1-bit preview in the terminal instead of printing
+    #[arg(long, value_enum)]
+    terminal: Option<TerminalPreview>,
+
+    /// Recompile and refresh the preview whenever the document or anything it reads changes,
+    /// press Enter to print the current version
+    #[arg(long)]
+    watch: bool,
 }
 
-async fn main_fn() -> anyhow::Result<()> {
-    println!("dz-print for typst");
-    let file_name = env::args()
-        .nth(1)
-        .ok_or(anyhow::anyhow!("please specify a filename"))?;
-    let file_path = std::path::PathBuf::from(&file_name);
-    println!("reading file");
-    let file_content = tokio::fs::read_to_string(file_name).await?;
-    println!("creating world");
-    let world = Minecraft::new(&file_path, file_content);
-    println!("compiling document");
-    let doc = typst::compile::<PagedDocument>(&world);
-    for w in doc.warnings {
-        println!("warning: {w:?}");
-    }
-    let doc = doc
-        .output
-        .map_err(|e| anyhow::anyhow!("compile error: {e:?}"))?;
-
-    let mut page_settings_map: HashMap<usize, PrintSettings> = HashMap::new();
-    let page_settings_selector = typst::foundations::Selector::Label(
-        typst::foundations::Label::new(PicoStr::intern("print-settings")).unwrap(),
-    );
-
-    println!("load page settings");
-    for content in doc.introspector.query(&page_settings_selector) {
-        if let Some(location) = content.location() {
-            let page_num = doc.introspector.page(location).get();
-            println!("parsing page setting {page_num}");
-            let values = content
-                .get_by_name("value")
-                .map_err(|e| anyhow::anyhow!("parse page setting: {e:?}"))?;
-            let typst::foundations::Value::Dict(v) = values else {
-                continue;
-            };
-            let paper = match v.get("paper").ok() {
-                Some(typst::foundations::Value::Str(x)) => PaperSetting::try_from(x.as_str())
-                    .map_err(|e| anyhow::anyhow!("parse paper setting: {e:?}"))?,
-                Some(e) => return Err(anyhow::anyhow!("parse paper setting: Invalid Type {e:?}")),
-                None => PaperSetting::default(),
-            };
-            macro_rules! parse_numeric_setting {
-                ($field_name:expr, $setting_type:ty, $cast_type:ty) => {
-                    match v.get($field_name).ok() {
-                        Some(typst::foundations::Value::Int(x)) => {
-                            <$setting_type>::try_from(*x as $cast_type).map_err(|e| {
-                                anyhow::anyhow!("parse {} setting: {e:?}", $field_name)
-                            })?
-                        }
-                        Some(typst::foundations::Value::Str(x)) => {
-                            <$setting_type>::try_from(x.as_str()).map_err(|e| {
-                                anyhow::anyhow!("parse {} setting: {e:?}", $field_name)
-                            })?
-                        }
-                        Some(_) => {
-                            return Err(anyhow::anyhow!(
-                                "parse {} setting: InvalidType",
-                                $field_name
-                            ))
-                        }
-                        None => <$setting_type>::default(),
-                    }
-                };
-            }
-            let darkness = parse_numeric_setting!("darkness", DarknessSetting, u8);
-            let speed = parse_numeric_setting!("speed", SpeedSetting, u8);
-            let gap = parse_numeric_setting!("gap", GapSetting, u16);
-            let ps = PrintSettings {
-                paper,
-                darkness,
-                speed,
-                gap,
-            };
-            page_settings_map.insert(page_num, ps);
-        }
-    }
-
-    println!("connecting to printer");
-    let b = backend::USBBackend::new(backend::USBSelector::DeviceSerial(
-        "DP27P-Y4094C023".to_string(),
-    ))
-    .await?;
-    for p in doc.pages {
-        println!("rendering page {}", p.number);
-        // 576px = 48mm
-        let r = typst_render::render(&p, 576.0 / (2.834_645_7 * 48.0));
-        let ps = page_settings_map
-            .get(&(p.number as usize))
-            .cloned()
-            .unwrap_or_default();
-        println!("printing page {}: {:?}, bp {}", p.number, ps, ps.bp());
-        print_page(&b, r, ps).await?;
-    }
-    Ok(())
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum TerminalPreview {
+    /// Full resolution, for terminals with sixel graphics
+    Sixel,
+    /// Unicode half blocks, 4 dots per column
+    Blocks,
 }
 
-async fn print_page(b: &backend::USBBackend, pm: Pixmap, ps: PrintSettings) -> anyhow::Result<()> {
-    assert_eq!(
-        pm.width(),
-        576,
-        "please ensure your page width is 576px or 48mm"
-    );
-    println!("converting to bitmap");
-    let bitmap = Bitmap::from_pixmap(&pm, DitherMode::FloydSteinberg);
-    // 这个 bp 参数其实是 magic number，以下是建议值
-    // 最慢 50 | 较慢 75 | 正常 100 | 较快 110 | 最快 120
-    // 可能受打印浓度影响
-    let parser = BitmapParser::new(bitmap, ps.bp());
-    println!("set paper type");
-    let (cmd, chan) = backend::Command::without_response(
-        command::Command::new_host(HostCommand::GetSetPrintPaperType)
-            .package(ps.paper_vec(), false),
-    );
-    b.push(cmd).await?;
-    chan.await?;
-    println!("set darkness");
-    let (cmd, chan) = backend::Command::without_response(
-        command::Command::new_host(HostCommand::GetSetPrintDarkness)
-            .package(ps.darkness_vec(), false),
-    );
-    b.push(cmd).await?;
-    chan.await?;
-    println!("set speed");
-    let (cmd, chan) = backend::Command::without_response(
-        command::Command::new_host(HostCommand::GetSetPrintSpeed).package(ps.speed_vec(), false),
-    );
-    b.push(cmd).await?;
-    chan.await?;
-    println!("set gap");
-    let (cmd, chan) = backend::Command::without_response(
-        command::Command::new_host(HostCommand::GetSetPrintPaperGap).package(ps.gap_vec(), false),
-    );
-    b.push(cmd).await?;
-    chan.await?;
-    println!("get status");
-    let (cmd, chan) = backend::Command::with_response(
-        command::Command::new_host(HostCommand::GetPrinterStatus).package(vec![], false),
-    );
-    b.push(cmd).await.ok();
-    let resp = chan.await?.ok_or(anyhow::anyhow!("get status error"))?;
-    println!("status: {:?}", resp.await?.get_command());
-    println!("enable high command");
-    let (cmd, chan) = backend::Command::with_response(
-        command::Command::new_host(HostCommand::EnableHighCommand).package(vec![0x7f], false),
-    );
-    b.push(cmd).await?;
-    let resp = chan
-        .await?
-        .ok_or(anyhow::anyhow!("enable high command error"))?;
-    println!("enable high command: {:?}", resp.await?.get_command());
-    println!("reset printer");
-    let (cmd, chan) = backend::Command::without_response(
-        PrintCommand::ResetPrinter
-            .parse()
-            .unwrap()
-            .into_iter()
-            .flatten()
-            .collect::<Vec<_>>(),
-    );
-    b.push(cmd).await?;
-    chan.await?;
-    println!("printing");
-    let mut errored = false;
-    for c in parser {
-        if let Some(c) = c.parse() {
-            for c in c {
-                let (cmd, _ch) = backend::Command::without_response(c);
-                b.push(cmd).await?;
-                // 这是相当保守且恐怖的，我下次应该等待整个buffer变空? 我不知道
-                // ch.await?;
-            }
-        } else {
-            let (cmd1, chan1) = backend::Command::with_response(
-                command::Command::new_host(HostCommand::GetPrinterStatus).package(vec![], false),
-            );
-            b.push(cmd1).await.ok();
-            let chan = chan1.await?;
-            if let Some(chan) = chan {
-                let resp = chan.await;
-                let resp = if let Ok(resp) = resp {
-                    resp
-                } else {
-                    // 如果收不到东西，那一定是打印机 buffer 炸了
-                    // 这是打印机固件写的烂，我没什么好办法
-                    println!("receive error");
-                    errored = true;
-                    break;
-                };
-                let stat = resp.get_payload()[0];
-                println!("status: {:?}", stat);
-                if stat == 35 {
-                    println!("no paper");
-                    errored = true;
-                    break;
-                }
-            } else {
-                println!("print failed");
-                errored = true;
-                break;
-            }
-        }
-    }
-    if errored {
-        println!("reset device...");
-        let (cmd, chan) = backend::Command::reset();
-        b.push(cmd).await?;
-        chan.await?;
-        let (cmd1, chan1) = backend::Command::with_response(
-            command::Command::new_host(HostCommand::GetPrinterStatus).package(vec![], false),
-        );
-        b.push(cmd1).await?;
-        let chan = chan1.await?;
-        if let Some(chan) = chan {
-            let resp = chan.await;
-            let resp = if let Ok(resp) = resp {
-                resp
-            } else {
-                unreachable!("炸了炸了，但我不知道怎么修")
-            };
-            let stat = resp.get_payload()[0];
-            println!("status: {:?}", stat);
-            if stat == 35 {
-                println!("no paper");
-            }
-        } else {
-            println!("print failed");
-        }
-        panic!("print errored");
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::init();
+    let result = main_fn(Args::parse()).await;
+    if let Some(e) = result
+        .as_ref()
+        .err()
+        .and_then(|e| e.downcast_ref::<CompileError>())
+    {
+        eprint!("{e}");
+        std::process::exit(1);
     }
-    // println!("feed 2 lines");
-    // let (cmd, _) = backend::Command::without_response(
-    //     PrintCommand::FeedLines(2)
-    //         .parse()
-    //         .unwrap()
-    //         .into_iter()
-    //         .flatten()
-    //         .collect::<Vec<_>>(),
-    // );
-    // b.push(cmd).await?;
-    println!("next paper");
-    let (cmd, _) = backend::Command::without_response(
-        PrintCommand::NextPaper
-            .parse()
-            .unwrap()
-            .into_iter()
-            .flatten()
-            .collect::<Vec<_>>(),
-    );
-    b.push(cmd).await?;
-    tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
-    Ok(())
-}
-
-/// 我的[世界](typst::World)
-struct Minecraft {
-    fontbook: LazyHash<FontBook>,
-    library: LazyHash<Library>,
-    main_fileid: FileId,
-    main_content: String,
-    root_path: PathBuf,
+    result
 }
 
-impl Minecraft {
-    fn new(main_file_path: &Path, main_content: String) -> Self {
-        let fontbook = LazyHash::new(make_fontbook());
-        let library = LazyHash::new(make_library());
-        let root_path = main_file_path
-            .parent()
-            .unwrap_or(Path::new(""))
-            .to_path_buf();
-        let root_path = root_path.canonicalize().unwrap_or(root_path);
-        let main_filename = main_file_path
-            .file_name()
-            .unwrap_or_default()
-            .to_string_lossy();
-        let vpath = VirtualPath::new(format!("/{}", main_filename));
-        let main_fileid = FileId::new_fake(vpath);
-        Self {
-            fontbook,
-            library,
-            main_fileid,
-            main_content,
-            root_path,
-        }
-    }
-
-    fn resolve_path(&self, vpath: &VirtualPath) -> FileResult<PathBuf> {
-        let path = vpath
-            .resolve(&self.root_path)
-            .ok_or(FileError::AccessDenied)?;
-        if !path.starts_with(&self.root_path) {
-            return Err(FileError::AccessDenied);
-        }
-        Ok(path)
+/// 编译错误自带 `error:` 前缀和源码片段, 原样输出
+fn report(e: &anyhow::Error) {
+    match e.downcast_ref::<CompileError>() {
+        Some(e) => print!("{e}"),
+        None => println!("error: {e}"),
     }
 }
 
-impl World for Minecraft {
-    fn library(&self) -> &LazyHash<Library> {
-        &self.library
-    }
-
-    fn book(&self) -> &LazyHash<FontBook> {
-        &self.fontbook
+async fn main_fn(args: Args) -> anyhow::Result<()> {
+    println!("dz-print for typst");
+    println!("searching fonts");
+    let mut fonts = Fonts::new();
+    for p in &args.font_path {
+        fonts.search_dir(p);
     }
-
-    fn main(&self) -> FileId {
-        self.main_fileid
+    if !args.no_system_fonts {
+        fonts.search_system();
     }
-
-    fn source(&self, id: FileId) -> FileResult<Source> {
-        if id == self.main_fileid {
-            return Ok(Source::new(id, self.main_content.clone()));
-        }
-        let path = self.resolve_path(id.vpath())?;
-        let content = std::fs::read_to_string(&path).map_err(|e| FileError::from_io(e, &path))?;
-        Ok(Source::new(id, content))
+    println!("found {} fonts", fonts.len());
+    println!("creating world");
+    let mut world = TypstWorld::new(&args.file, args.root.as_deref())?.with_fonts(fonts);
+    for p in args.package_path.iter().rev() {
+        world = world.with_package_path(p.clone());
     }
-
-    fn file(&self, id: FileId) -> FileResult<Bytes> {
-        let path = self.resolve_path(id.vpath())?;
-        let content = std::fs::read(&path).map_err(|e| FileError::from_io(e, &path))?;
-        Ok(Bytes::new(content))
+    if let Some(now) = args.now {
+        world = world.with_now(now);
     }
-
-    fn font(&self, index: usize) -> Option<Font> {
-        // 需要优化一下?
-        let font_unifont_bin = include_bytes!("../asset/unifont-16.0.04.ttf");
-        let font_unifont = Font::new(Bytes::new(font_unifont_bin), 0);
-        let font_unifontex_bin = include_bytes!("../asset/UnifontExMono.ttf");
-        let font_unifontex = Font::new(Bytes::new(font_unifontex_bin), 0);
-        match index {
-            0 => font_unifont,
-            1 => font_unifontex,
-            _ => None,
-        }
+    if args.watch {
+        return watch(&args, world).await;
     }
 
-    fn today(&self, _offset: Option<i64>) -> Option<Datetime> {
-        let _now = Local::now();
-        // todo
-        None
+    // 先编译全部记录, 出错时一张都不打印
+    let pages = compile_all(&args, &mut world)?;
+    if args.preview.is_some() || args.terminal.is_some() {
+        let area = offline_area(&args);
+        return show_preview(&args, render_pages(&pages, &area, args.fit)?, &area).await;
     }
+    let (b, area) = connect(&args).await?;
+    // 同样先检查全部页面的宽度
+    let rendered = render_pages(&pages, &area, args.fit)?;
+    print_all(&b, rendered, &area).await?;
+    Ok(())
 }
 
-#[derive(thiserror::Error, Debug)]
-enum PrintSettingError {
-    #[error("Invalid value `{0}`")]
-    InvalidU8(u8),
-    #[error("Invalid value `{0}`")]
-    InvalidU16(u16),
-    #[error("Invalid string `{0}`")]
-    InvalidString(String),
-}
-
-#[derive(Debug, Clone, Copy, Default)]
-enum SpeedSetting {
-    /// 最慢(1)
-    Min,
-    /// 稍慢(2)
-    Speed1,
-    /// 正常(3)
-    #[default]
-    Normal,
-    /// 稍快(4)
-    Speed3,
-    /// 最快(5)
-    Max,
-}
-
-impl TryFrom<u8> for SpeedSetting {
-    type Error = PrintSettingError;
-
-    fn try_from(value: u8) -> Result<Self, Self::Error> {
-        match value {
-            1 => Ok(Self::Min),
-            2 => Ok(Self::Speed1),
-            3 => Ok(Self::Normal),
-            4 => Ok(Self::Speed3),
-            5 => Ok(Self::Max),
-            _ => Err(Self::Error::InvalidU8(value)),
-        }
+/// 编译每条记录, 返回所有页面和它们的设置
+fn compile_all(args: &Args, world: &mut TypstWorld) -> anyhow::Result<Vec<(Page, PageSettings)>> {
+    let inputs = pairs_to_dict(&args.inputs);
+    let records = match &args.data {
+        Some(path) => load_records(path)?,
+        None => vec![Dict::new()],
+    };
+    if records.is_empty() {
+        anyhow::bail!("no records in the data file");
     }
-}
-
-impl TryFrom<&str> for SpeedSetting {
-    type Error = PrintSettingError;
-
-    fn try_from(value: &str) -> Result<Self, Self::Error> {
-        match value {
-            "min" | "1" => Ok(Self::Min),
-            "2" => Ok(Self::Speed1),
-            "normal" | "3" => Ok(Self::Normal),
-            "4" => Ok(Self::Speed3),
-            "max" | "5" => Ok(Self::Max),
-            _ => Err(Self::Error::InvalidString(value.to_string())),
+    let mut pages = vec![];
+    for (i, record) in records.iter().enumerate() {
+        println!("compiling document ({}/{})", i + 1, records.len());
+        world.set_inputs(merge(&inputs, record));
+        let (doc, warnings) = compile(world)?;
+        for w in warnings {
+            print!("{w}");
+        }
+        let mut settings = page_settings(&doc).map_err(|e| CompileError::new(world, &e))?;
+        // 设置按物理页码保存, 和 `#set page(numbering)` 无关
+        for (i, p) in doc.pages.into_iter().enumerate() {
+            let ps = settings.remove(&(i + 1)).unwrap_or_default();
+            pages.push((p, ps));
         }
     }
+    Ok(pages)
 }
 
-#[derive(Debug, Clone, Copy, Default)]
-enum DarknessSetting {
-    /// 最浅(1)
-    Min,
-    Darkness1,
-    Darkness2,
-    Darkness3,
-    Darkness4,
-    /// 正常(6)
-    #[default]
-    Normal,
-    Darkness6,
-    Darkness7,
-    Darkness8,
-    Darkness9,
-    Darkness10,
-    Darkness11,
-    Darkness12,
-    Darkness13,
-    /// 最深(15)
-    Max,
+/// 检查全部页面的宽度, 返回每页的渲染比例
+fn scales(pages: &[(Page, PageSettings)], area: &PrintArea, fit: bool) -> anyhow::Result<Vec<f32>> {
+    pages
+        .iter()
+        .enumerate()
+        .map(|(i, (p, ps))| {
+            let size = p.frame.size();
+            let width = ps.printed_width(size.x.to_pt(), size.y.to_pt());
+            Ok(area.scale(i + 1, width, fit)?)
+        })
+        .collect()
 }
 
-impl TryFrom<u8> for DarknessSetting {
-    type Error = PrintSettingError;
-
-    fn try_from(value: u8) -> Result<Self, Self::Error> {
-        match value {
-            1 => Ok(Self::Min),
-            2 => Ok(Self::Darkness1),
-            3 => Ok(Self::Darkness2),
-            4 => Ok(Self::Darkness3),
-            5 => Ok(Self::Darkness4),
-            6 => Ok(Self::Normal),
-            7 => Ok(Self::Darkness6),
-            8 => Ok(Self::Darkness7),
-            9 => Ok(Self::Darkness8),
-            10 => Ok(Self::Darkness9),
-            11 => Ok(Self::Darkness10),
-            12 => Ok(Self::Darkness11),
-            13 => Ok(Self::Darkness12),
-            14 => Ok(Self::Darkness13),
-            15 => Ok(Self::Max),
-            _ => Err(Self::Error::InvalidU8(value)),
-        }
-    }
+/// 先检查全部页面的宽度, 然后逐页渲染, 同时只有一页的位图在内存里
+fn render_pages<'a>(
+    pages: &'a [(Page, PageSettings)],
+    area: &PrintArea,
+    fit: bool,
+) -> anyhow::Result<impl ExactSizeIterator<Item = (Pixmap, &'a PageSettings)>> {
+    let scales = scales(pages, area, fit)?;
+    Ok(pages
+        .iter()
+        .zip(scales)
+        .enumerate()
+        .map(|(i, ((p, ps), scale))| {
+            println!("rendering page {} at {scale:.3} px/pt", i + 1);
+            (typst_render::render(p, scale), ps)
+        }))
 }
 
-impl TryFrom<&str> for DarknessSetting {
-    type Error = PrintSettingError;
-
-    fn try_from(value: &str) -> Result<Self, Self::Error> {
-        match value {
-            "min" | "1" => Ok(Self::Min),
-            "2" => Ok(Self::Darkness1),
-            "3" => Ok(Self::Darkness2),
-            "4" => Ok(Self::Darkness3),
-            "5" => Ok(Self::Darkness4),
-            "normal" | "6" => Ok(Self::Normal),
-            "7" => Ok(Self::Darkness6),
-            "8" => Ok(Self::Darkness7),
-            "9" => Ok(Self::Darkness8),
-            "10" => Ok(Self::Darkness9),
-            "11" => Ok(Self::Darkness10),
-            "12" => Ok(Self::Darkness11),
-            "13" => Ok(Self::Darkness12),
-            "14" => Ok(Self::Darkness13),
-            "max" | "15" => Ok(Self::Max),
-            _ => Err(Self::Error::InvalidString(value.to_string())),
-        }
-    }
+/// 不连接打印机时的打印区域
+fn offline_area(args: &Args) -> PrintArea {
+    let default = PrintArea::default();
+    PrintArea::new(
+        args.dpi.unwrap_or(default.dpi),
+        args.head_width.unwrap_or(default.head_dots),
+    )
 }
 
-#[derive(Debug, Clone, Copy, Default)]
-enum PaperSetting {
-    /// 小票纸
-    #[default]
-    Ticket = 0,
-    /// 不干胶
-    Adhesive = 2,
-    /// 卡纸
-    CardPaper = 3,
-    /// 透明贴
-    Transparent = 4,
+async fn connect(args: &Args) -> anyhow::Result<(backend::USBBackend, PrintArea)> {
+    println!("connecting to printer");
+    let b = backend::USBBackend::new(backend::USBSelector::DeviceSerial(
+        "DP27P-Y4094C023".to_string(),
+    ))
+    .await?;
+    let dpi = match args.dpi {
+        Some(x) => x,
+        None => read_dpi(&b).await?,
+    };
+    let head_width = match args.head_width {
+        Some(x) => x,
+        None => read_print_width(&b).await?.head_dots as u32,
+    };
+    let area = PrintArea::new(dpi, head_width);
+    println!(
+        "printer: {dpi} dpi, {head_width} dots ({:.1}mm)",
+        area.head_mm()
+    );
+    Ok((b, area))
 }
 
-impl TryFrom<&str> for PaperSetting {
-    type Error = PrintSettingError;
-
-    fn try_from(value: &str) -> Result<Self, Self::Error> {
-        match value {
-            "ticket" => Ok(Self::Ticket),
-            "adhesive" => Ok(Self::Adhesive),
-            "cardpaper" => Ok(Self::CardPaper),
-            "transparent" => Ok(Self::Transparent),
-            _ => Err(Self::Error::InvalidString(value.to_string())),
+async fn print_all(
+    b: &backend::USBBackend,
+    rendered: impl ExactSizeIterator<Item = (Pixmap, &PageSettings)>,
+    area: &PrintArea,
+) -> anyhow::Result<()> {
+    let total = rendered.len();
+    for (i, (r, ps)) in rendered.enumerate() {
+        println!("printing page {}/{total}: {:?}, bp {}", i + 1, ps, ps.bp());
+        for stats in print_page(b, &r, ps, area, &PrintOptions::default()).await? {
+            println!("printed {} lines, sent {} bytes", stats.lines, stats.bytes);
         }
     }
+    Ok(())
 }
 
-#[derive(Debug, Clone, Copy)]
-struct GapSetting(u16);
-
-impl Default for GapSetting {
-    fn default() -> Self {
-        Self(50)
+/// 第 `idx` 页 (从 0 开始) 的预览文件名
+fn page_path(path: &Path, idx: usize) -> PathBuf {
+    if idx == 0 {
+        return path.to_path_buf();
     }
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(ext) => format!("{stem}-{}.{}", idx + 1, ext.to_string_lossy()),
+        None => format!("{stem}-{}", idx + 1),
+    };
+    path.with_file_name(name)
 }
 
-impl TryFrom<u16> for GapSetting {
-    type Error = PrintSettingError;
-
-    fn try_from(value: u16) -> Result<Self, Self::Error> {
-        if value < 50 {
-            Err(Self::Error::InvalidU16(value))
-        } else {
-            Ok(Self(value))
+async fn show_preview(
+    args: &Args,
+    rendered: impl Iterator<Item = (Pixmap, &PageSettings)>,
+    area: &PrintArea,
+) -> anyhow::Result<()> {
+    // 监视模式下没有指定预览方式时在终端里显示
+    let terminal = match (args.terminal, &args.preview) {
+        (None, None) if args.watch => Some(TerminalPreview::Blocks),
+        (x, _) => x,
+    };
+    for (i, (r, ps)) in rendered.enumerate() {
+        let bitmap = preview(&r, ps, area);
+        if let Some(path) = &args.preview {
+            let path = page_path(path, i);
+            let is_pbm = path
+                .extension()
+                .is_some_and(|x| x.eq_ignore_ascii_case("pbm"));
+            let data = if is_pbm {
+                bitmap.to_pbm()
+            } else {
+                bitmap.to_png()?
+            };
+            tokio::fs::write(&path, data).await?;
+            println!(
+                "wrote {}x{} preview to {}",
+                bitmap.width(),
+                bitmap.height(),
+                path.display()
+            );
         }
-    }
-}
-
-impl TryFrom<&str> for GapSetting {
-    type Error = PrintSettingError;
-
-    fn try_from(value: &str) -> Result<Self, Self::Error> {
-        let lower = value.trim().to_lowercase();
-        let num_str = lower.strip_suffix("mm").unwrap_or(&lower).trim();
-        let val_f: f64 = num_str
-            .parse()
-            .map_err(|_| PrintSettingError::InvalidString(value.to_string()))?;
-        let val_rounded = (val_f * 100.0).round();
-        if val_rounded < 0.0 || val_rounded > u16::MAX as f64 {
-            return Err(PrintSettingError::InvalidU16(val_rounded as u16));
+        match terminal {
+            Some(TerminalPreview::Sixel) => println!("{}", bitmap.to_sixel()),
+            Some(TerminalPreview::Blocks) => print!("{}", bitmap.to_half_blocks(BLOCK_SCALE)),
+            None => {}
         }
-        let val_u16 = val_rounded as u16;
-        Self::try_from(val_u16)
-    }
-}
-
-#[derive(Debug, Clone, Copy, Default)]
-struct PrintSettings {
-    paper: PaperSetting,
-    darkness: DarknessSetting,
-    speed: SpeedSetting,
-    gap: GapSetting,
-}
-
-impl PrintSettings {
-    fn bp(&self) -> u32 {
-        let basic_bp = match self.speed {
-            SpeedSetting::Min => 50,
-            SpeedSetting::Speed1 => 75,
-            SpeedSetting::Normal => 100,
-            SpeedSetting::Speed3 => 110,
-            SpeedSetting::Max => 120,
-        };
-        let normal_darkness = 5;
-        let step = 3;
-        let adjustment = (normal_darkness - self.darkness as i32) * step;
-        let final_bp = basic_bp as i32 + adjustment;
-        final_bp.max(0) as u32
-    }
-
-    fn paper_vec(&self) -> Vec<u8> {
-        let v = self.paper as u8;
-        v.to_be_bytes().to_vec()
-    }
-
-    fn speed_vec(&self) -> Vec<u8> {
-        let v = self.speed as u8;
-        v.to_be_bytes().to_vec()
-    }
-
-    fn darkness_vec(&self) -> Vec<u8> {
-        let v = self.darkness as u8;
-        v.to_be_bytes().to_vec()
-    }
-
-    fn gap_vec(&self) -> Vec<u8> {
-        let v = self.gap.0;
-        v.to_be_bytes().to_vec()
-    }
-}
-
-fn make_library() -> Library {
-    let mut library = Library::builder().build();
-    #[allow(unused_variables)]
-    let scope = library.global.scope_mut();
-    // scope.define_func::<QrCodeFunc>();
-    library
-}
-
-pub struct QrCodeFunc {}
-
-impl NativeFunc for QrCodeFunc {
-    #[allow(unreachable_code, unused_variables)]
-    fn data() -> &'static NativeFuncData {
-        let data = NativeFuncData {
-            function: typst_library::foundations::NativeFuncPtr(&Self::f),
-            name: todo!(),
-            title: todo!(),
-            docs: todo!(),
-            keywords: todo!(),
-            contextual: todo!(),
-            scope: todo!(),
-            params: todo!(),
-            returns: todo!(),
-        };
-        todo!();
-        // &data
     }
+    Ok(())
 }
 
-// Fn(&mut Engine, Tracked<Context>, &mut Args) -> SourceResult<Value> + Send + Sync;
-
-impl QrCodeFunc {
-    #[allow(unreachable_code, unused_variables)]
-    pub fn f(
-        engine: &mut typst_library::engine::Engine,
-        context: typst::comemo::Tracked<typst_library::foundations::Context>,
-        args: &mut typst_library::foundations::Args,
-    ) -> typst_library::diag::SourceResult<typst_library::foundations::Value> {
-        todo!()
+/// 监视模式: 文件修改后重新编译并刷新预览, 按回车打印当前的版本
+///
+/// 编译是增量的, 没有修改的文件不会重新解析, comemo 会复用没有变化的部分
+async fn watch(args: &Args, mut world: TypstWorld) -> anyhow::Result<()> {
+    let area = offline_area(args);
+    let mut watcher = Watcher::new();
+    let mut stdin = BufReader::new(tokio::io::stdin()).lines();
+    let mut printer = None;
+    let mut pages = vec![];
+    loop {
+        world.reset();
+        let result = compile_all(args, &mut world).and_then(|p| {
+            scales(&p, &area, args.fit)?;
+            Ok(p)
+        });
+        match result {
+            Ok(p) => {
+                pages = p;
+                show_preview(args, render_pages(&pages, &area, args.fit)?, &area).await?;
+            }
+            Err(e) => {
+                // 不能打印上一次成功编译的旧版本
+                pages.clear();
+                report(&e);
+            }
+        }
+        typst::comemo::evict(10);
+        let mut files = world.dependencies();
+        files.extend(args.data.clone());
+        watcher.watch(files);
+        println!(
+            "watching {} files, press Enter to print, Ctrl-D to quit",
+            watcher.len()
+        );
+        loop {
+            tokio::select! {
+                line = stdin.next_line() => {
+                    if line?.is_none() {
+                        return Ok(());
+                    }
+                    if pages.is_empty() {
+                        println!("nothing to print, fix the errors above first");
+                        continue;
+                    }
+                    if printer.is_none() {
+                        match connect(args).await {
+                            Ok(x) => printer = Some(x),
+                            Err(e) => {
+                                report(&e);
+                                continue;
+                            }
+                        }
+                    }
+                    let (b, area) = printer.as_ref().unwrap();
+                    let result = match render_pages(&pages, area, args.fit) {
+                        Ok(rendered) => print_all(b, rendered, area).await,
+                        Err(e) => Err(e),
+                    };
+                    if let Err(e) = result {
+                        report(&e);
+                    }
+                }
+                _ = tokio::time::sleep(POLL_INTERVAL) => {
+                    if watcher.changed() {
+                        break;
+                    }
+                }
+            }
+        }
     }
 }
-
-fn make_fontbook() -> FontBook {
-    let mut fb = FontBook::new();
-    let font_unifont_bin = include_bytes!("../asset/unifont-16.0.04.ttf");
-    let mut font_unifont_info = FontInfo::new(font_unifont_bin, 0).unwrap();
-    font_unifont_info.family = "Unifont".to_string();
-    fb.push(font_unifont_info);
-    let font_unifontex_bin = include_bytes!("../asset/UnifontExMono.ttf");
-    let mut font_unifontex_info = FontInfo::new(font_unifontex_bin, 0).unwrap();
-    font_unifontex_info.family = "UnifontExMono".to_string();
-    fb.push(font_unifontex_info);
-    fb
-}
@@ -32,6 +32,11 @@ pub enum HostCommand {
     // Test = 0x1f70,
     EnableHighCommand = 0x1f80,
     GetSensorStatus = 0x1f88,
+    SetLabelWidth = 0x1f27,
+    ReadHardwareFlags = 0x1f84,
+    ReadDpi = 0x1f71,
+    ReadPrintWidth = 0x1f72,
+    ReadStatistics = 0x1f73,
 }
 
 #[derive(Debug, Clone, Copy, FromPrimitive, ToPrimitive, PartialEq)]
@@ -47,6 +52,10 @@ pub enum DeviceCommand {
     PrinterStatus = 0x1f70,
     HighCommand = 0x1f80,
     SensorStatus = 0x1f88,
+    HardwareFlags = 0x1f84,
+    Dpi = 0x1f71,
+    PrintWidth = 0x1f72,
+    Statistics = 0x1f73,
 }
 
 pub struct Command<Direction = DefaultState> {
@@ -57,24 +66,28 @@ pub struct Command<Direction = DefaultState> {
 
 impl Command<Host> {
     pub fn package(&self, p: Vec<u8>, fixed_checksum: bool) -> Vec<u8> {
-        let payload_len_buf = (p.len() as i32).to_variable_bytes();
-        // 命令组 + 命令类型 + 数据长度 + 数据... + 校验和
-        let packet_len = 2 + payload_len_buf.len() + p.len() + 1;
-        let mut buf = vec![0; packet_len];
-        (buf[0], buf[1]) = self.get_header();
-        buf[2..(payload_len_buf.len() + 2)].copy_from_slice(&payload_len_buf[..]);
-        for i in 0..p.len() {
-            buf[2 + payload_len_buf.len() + i] = p[i];
-        }
-        let checksum = if fixed_checksum {
-            0x88
-        } else {
-            checksum::calculate_checksum(&buf, 1, packet_len)
-        };
-        buf[packet_len - 1] = checksum;
-        buf
+        package_with_header(self.get_header(), &p, fixed_checksum)
     }
 }
+
+/// 按照 `命令组 + 命令类型 + 数据长度 + 数据... + 校验和` 打包任意命令, 用于还没有定义的命令
+pub fn package_with_header(header: (u8, u8), p: &[u8], fixed_checksum: bool) -> Vec<u8> {
+    let payload_len_buf = (p.len() as i32).to_variable_bytes();
+    // 命令组 + 命令类型 + 数据长度 + 数据... + 校验和
+    let packet_len = 2 + payload_len_buf.len() + p.len() + 1;
+    let mut buf = vec![0; packet_len];
+    (buf[0], buf[1]) = header;
+    buf[2..(payload_len_buf.len() + 2)].copy_from_slice(&payload_len_buf[..]);
+    buf[2 + payload_len_buf.len()..packet_len - 1].copy_from_slice(p);
+    let checksum = if fixed_checksum {
+        0x88
+    } else {
+        checksum::calculate_checksum(&buf, 1, packet_len)
+    };
+    buf[packet_len - 1] = checksum;
+    buf
+}
+
 impl Command<Device> {
     pub fn get_payload(&self) -> Vec<u8> {
         self.payload.clone()
@@ -2,6 +2,9 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
+use num_derive::{FromPrimitive, ToPrimitive};
+
+#[derive(Debug, Clone, Copy, FromPrimitive, ToPrimitive, PartialEq)]
 pub enum PrinterErrorCode {
     Cancelled = 12,
     VolTooLow = 30,
@@ -0,0 +1,269 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Typst 诊断信息: 文件, 行列, 源码片段和提示, 格式和 typst 命令行类似
+//!
+//! ```text
+//! error: unknown variable: nme
+//!   ┌─ label.typ:3:2
+//!   │
+//! 3 │ #nme
+//!   │  ^^^
+//!   = hint: ...
+//! ```
+
+use std::{fmt, ops::Range, path::PathBuf};
+
+use thiserror::Error;
+use typst::{
+    diag::{Severity, SourceDiagnostic},
+    layout::PagedDocument,
+    syntax::{FileId, Span},
+    World, WorldExt,
+};
+use unicode_width::UnicodeWidthStr;
+
+/// 源码里的位置, 行和列从 1 开始, 列按字符计算
+#[derive(Debug, Clone, PartialEq)]
+pub struct Location {
+    /// 相对于根目录的路径, 包里的文件前面加上 `@namespace/name:version`
+    pub path: PathBuf,
+    pub line: usize,
+    pub column: usize,
+    /// 所在的那一行
+    pub snippet: String,
+    /// 需要标出的范围, 按终端里的显示宽度计算 (中文占两列), 跨行时标到行尾
+    pub columns: Range<usize>,
+}
+
+impl Location {
+    /// `span` 没有对应的文件时返回 `None`
+    pub fn new(world: &dyn World, span: Span) -> Option<Self> {
+        let id = span.id()?;
+        let source = world.source(id).ok()?;
+        let range = world.range(span)?;
+        let lines = source.lines();
+        let (line, column) = lines.byte_to_line_column(range.start)?;
+        let line_range = lines.line_to_range(line)?;
+        let snippet = source.text()[line_range.clone()].trim_end_matches(['\n', '\r']);
+        let end = range
+            .end
+            .min(line_range.start + snippet.len())
+            .max(range.start);
+        let start = source.text()[line_range.start..range.start].width();
+        let width = source.text()[range.start..end].width().max(1);
+        Some(Location {
+            path: display_path(id),
+            line: line + 1,
+            column: column + 1,
+            snippet: snippet.to_string(),
+            columns: start..start + width,
+        })
+    }
+
+    fn write(&self, f: &mut fmt::Formatter, gutter: usize) -> fmt::Result {
+        let pad = " ".repeat(gutter);
+        let line = self.line;
+        writeln!(f, "{pad} ┌─ {}:{line}:{}", self.path.display(), self.column)?;
+        writeln!(f, "{pad} │")?;
+        writeln!(f, "{line:>gutter$} │ {}", self.snippet)?;
+        writeln!(
+            f,
+            "{pad} │ {}{}",
+            " ".repeat(self.columns.start),
+            "^".repeat(self.columns.len())
+        )
+    }
+}
+
+fn display_path(id: FileId) -> PathBuf {
+    let path = id.vpath().as_rootless_path().to_path_buf();
+    match id.package() {
+        Some(spec) => PathBuf::from(spec.to_string()).join(path),
+        None => path,
+    }
+}
+
+/// 一条诊断信息
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub location: Option<Location>,
+    pub hints: Vec<String>,
+    /// 调用链, 从内到外
+    pub trace: Vec<(String, Option<Location>)>,
+}
+
+impl Diagnostic {
+    pub fn new(world: &dyn World, diag: &SourceDiagnostic) -> Self {
+        Diagnostic {
+            severity: diag.severity,
+            message: diag.message.to_string(),
+            location: Location::new(world, diag.span),
+            hints: diag.hints.iter().map(|x| x.to_string()).collect(),
+            trace: diag
+                .trace
+                .iter()
+                .map(|x| (x.v.to_string(), Location::new(world, x.span)))
+                .collect(),
+        }
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // 行号的宽度
+        let gutter = std::iter::once(&self.location)
+            .chain(self.trace.iter().map(|(_, l)| l))
+            .flatten()
+            .map(|l| l.line.to_string().len())
+            .max()
+            .unwrap_or(1);
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        writeln!(f, "{severity}: {}", self.message)?;
+        if let Some(l) = &self.location {
+            l.write(f, gutter)?;
+        }
+        for hint in &self.hints {
+            writeln!(f, "{} = hint: {hint}", " ".repeat(gutter))?;
+        }
+        for (message, location) in &self.trace {
+            writeln!(f)?;
+            writeln!(f, "help: {message}")?;
+            if let Some(l) = location {
+                l.write(f, gutter)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 编译失败, 包括所有的错误和警告
+#[derive(Error, Debug, Clone, PartialEq)]
+pub struct CompileError(pub Vec<Diagnostic>);
+
+impl CompileError {
+    pub fn new(world: &dyn World, diags: &[SourceDiagnostic]) -> Self {
+        CompileError(diags.iter().map(|d| Diagnostic::new(world, d)).collect())
+    }
+
+    pub fn errors(&self) -> impl Iterator<Item = &Diagnostic> {
+        self.0.iter().filter(|d| d.is_error())
+    }
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, d) in self.0.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{d}")?;
+        }
+        Ok(())
+    }
+}
+
+/// 编译文档, 成功时返回文档和警告
+pub fn compile(world: &dyn World) -> Result<(PagedDocument, Vec<Diagnostic>), CompileError> {
+    let result = typst::compile::<PagedDocument>(world);
+    let warnings: Vec<Diagnostic> = result
+        .warnings
+        .iter()
+        .map(|d| Diagnostic::new(world, d))
+        .collect();
+    match result.output {
+        Ok(doc) => Ok((doc, warnings)),
+        Err(errors) => {
+            let mut diags = CompileError::new(world, &errors).0;
+            diags.extend(warnings);
+            Err(CompileError(diags))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use typst::diag::Severity;
+
+    use super::{compile, CompileError};
+    use crate::frontend::world::TypstWorld;
+
+    fn world(name: &str, files: &[(&str, &str)]) -> TypstWorld {
+        let dir = std::env::temp_dir().join(format!("dz-print-diagnostics-{name}"));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        for (path, text) in files {
+            std::fs::write(dir.join(path), text).unwrap();
+        }
+        TypstWorld::new(&dir.join("main.typ"), None).unwrap()
+    }
+
+    #[test]
+    fn test_error() {
+        let w = world("error", &[("main.typ", "= Title\n#let x = 1\n#nme")]);
+        let err = compile(&w).unwrap_err();
+        let d = err.errors().next().unwrap();
+        assert_eq!(d.message, "unknown variable: nme");
+        let l = d.location.as_ref().unwrap();
+        assert_eq!(
+            (l.path.to_str(), l.line, l.column),
+            (Some("main.typ"), 3, 2)
+        );
+        assert_eq!(
+            err.to_string(),
+            "error: unknown variable: nme\n  \
+             ┌─ main.typ:3:2\n  \
+             │\n\
+             3 │ #nme\n  \
+             │  ^^^\n"
+        );
+    }
+
+    #[test]
+    fn test_wide_characters() {
+        let w = world("wide", &[("main.typ", "= 标签\n标签 #nme")]);
+        let err = compile(&w).unwrap_err();
+        let l = err.errors().next().unwrap().location.clone().unwrap();
+        // 第 5 个字符, 但是前面的中文各占两列
+        assert_eq!((l.line, l.column, l.columns), (2, 5, 6..9));
+        assert!(
+            err.to_string().ends_with("2 │ 标签 #nme\n  │       ^^^\n"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn test_trace_and_warning() {
+        let w = world(
+            "trace",
+            &[
+                ("part.typ", "#let f(x) = x.len()\n"),
+                ("main.typ", "#import \"part.typ\": f\n#f(1)"),
+            ],
+        );
+        let CompileError(diags) = compile(&w).unwrap_err();
+        let d = &diags[0];
+        assert_eq!(d.location.as_ref().unwrap().path.to_str(), Some("part.typ"));
+        let (message, location) = &d.trace[0];
+        assert!(message.contains("`f`"), "{message}");
+        assert_eq!(location.as_ref().unwrap().line, 2);
+        assert!(diags[0].to_string().contains("help: "));
+
+        let w = world("warning", &[("main.typ", "#box[]#text(font: \"Nope\")[x]")]);
+        let (_, warnings) = compile(&w).unwrap();
+        assert_eq!(warnings[0].severity, Severity::Warning);
+        assert!(warnings[0]
+            .to_string()
+            .starts_with("warning: unknown font family: nope"));
+    }
+}
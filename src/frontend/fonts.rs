@@ -0,0 +1,229 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! 字体: 内置的 Unifont, 系统字体目录和用户指定的目录
+//!
+//! 搜索时只读取字体信息, 字体第一次被使用时才加载, 加载后缓存
+
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use tracing::debug;
+use typst::{
+    foundations::Bytes,
+    text::{Font, FontBook, FontInfo},
+};
+
+/// 字体文件的扩展名
+const FONT_EXTENSIONS: &[&str] = &["ttf", "otf", "ttc", "otc"];
+
+/// 一个字体, `index` 是字体集合里的序号
+struct FontSlot {
+    path: Option<PathBuf>,
+    index: u32,
+    font: OnceLock<Option<Font>>,
+}
+
+impl FontSlot {
+    fn get(&self) -> Option<Font> {
+        self.font
+            .get_or_init(|| {
+                let path = self.path.as_ref()?;
+                debug!("loading font {} #{}", path.display(), self.index);
+                let data = std::fs::read(path).ok()?;
+                Font::new(Bytes::new(data), self.index)
+            })
+            .clone()
+    }
+}
+
+/// 字体列表, 顺序和 [FontBook] 一致
+pub struct Fonts {
+    book: FontBook,
+    slots: Vec<FontSlot>,
+    /// 搜索过的目录 (规范化之后的路径), 避免符号链接造成的循环和重复的字体
+    searched: HashSet<PathBuf>,
+}
+
+impl Default for Fonts {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Fonts {
+    /// 只有内置字体
+    pub fn new() -> Self {
+        let mut fonts = Fonts {
+            book: FontBook::new(),
+            slots: vec![],
+            searched: HashSet::new(),
+        };
+        fonts.add_embedded(include_bytes!("../asset/unifont-16.0.04.ttf"), "Unifont");
+        fonts.add_embedded(
+            include_bytes!("../asset/UnifontExMono.ttf"),
+            "UnifontExMono",
+        );
+        fonts
+    }
+
+    fn add_embedded(&mut self, data: &'static [u8], family: &str) {
+        let mut info = FontInfo::new(data, 0).unwrap();
+        info.family = family.to_string();
+        self.book.push(info);
+        self.slots.push(FontSlot {
+            path: None,
+            index: 0,
+            font: OnceLock::from(Font::new(Bytes::new(data), 0)),
+        });
+    }
+
+    pub fn book(&self) -> &FontBook {
+        &self.book
+    }
+
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    /// 第 `index` 个字体, 第一次调用时从文件加载
+    pub fn font(&self, index: usize) -> Option<Font> {
+        self.slots.get(index)?.get()
+    }
+
+    /// 搜索系统字体目录
+    pub fn search_system(&mut self) {
+        for dir in system_font_dirs() {
+            self.search_dir(&dir);
+        }
+    }
+
+    /// 递归搜索目录里的字体文件, 每个目录只搜索一次
+    pub fn search_dir(&mut self, dir: &Path) {
+        let Ok(canonical) = dir.canonicalize() else {
+            return;
+        };
+        if !self.searched.insert(canonical) {
+            return;
+        }
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            return;
+        };
+        let mut paths: Vec<PathBuf> = entries.flatten().map(|e| e.path()).collect();
+        paths.sort();
+        for path in paths {
+            if path.is_dir() {
+                self.search_dir(&path);
+            } else {
+                self.search_file(&path);
+            }
+        }
+    }
+
+    /// 读取一个字体文件里的全部字体, 返回找到的数量
+    pub fn search_file(&mut self, path: &Path) -> usize {
+        let is_font = path
+            .extension()
+            .and_then(|x| x.to_str())
+            .is_some_and(|x| FONT_EXTENSIONS.contains(&x.to_lowercase().as_str()));
+        if !is_font {
+            return 0;
+        }
+        let Ok(data) = std::fs::read(path) else {
+            return 0;
+        };
+        let count = ttf_parser::fonts_in_collection(&data).unwrap_or(1);
+        let mut found = 0;
+        for index in 0..count {
+            let Some(info) = FontInfo::new(&data, index) else {
+                continue;
+            };
+            self.book.push(info);
+            self.slots.push(FontSlot {
+                path: Some(path.to_path_buf()),
+                index,
+                font: OnceLock::new(),
+            });
+            found += 1;
+        }
+        found
+    }
+}
+
+/// 各平台的系统字体目录
+pub fn system_font_dirs() -> Vec<PathBuf> {
+    let home = std::env::var_os("HOME").map(PathBuf::from);
+    let mut dirs = vec![];
+    if cfg!(target_os = "windows") {
+        dirs.push(PathBuf::from(r"C:\Windows\Fonts"));
+        if let Some(local) = std::env::var_os("LOCALAPPDATA") {
+            dirs.push(PathBuf::from(local).join(r"Microsoft\Windows\Fonts"));
+        }
+    } else if cfg!(target_os = "macos") {
+        dirs.push("/Library/Fonts".into());
+        dirs.push("/System/Library/Fonts".into());
+        dirs.extend(home.map(|h| h.join("Library/Fonts")));
+    } else {
+        dirs.push("/usr/share/fonts".into());
+        dirs.push("/usr/local/share/fonts".into());
+        if let Some(home) = home {
+            dirs.push(home.join(".local/share/fonts"));
+            dirs.push(home.join(".fonts"));
+        }
+    }
+    dirs
+}
+
+#[cfg(test)]
+mod test {
+    use super::Fonts;
+
+    #[test]
+    fn test_search() {
+        let dir = std::env::temp_dir().join("dz-print-fonts");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        let data = include_bytes!("../asset/UnifontExMono.ttf");
+        std::fs::write(dir.join("sub/mono.TTF"), data).unwrap();
+        std::fs::write(dir.join("broken.ttf"), b"not a font").unwrap();
+        std::fs::write(dir.join("readme.txt"), b"hello").unwrap();
+
+        let mut fonts = Fonts::new();
+        assert_eq!(fonts.len(), 2);
+        assert!(fonts.book().contains_family("unifont"));
+        fonts.search_dir(&dir);
+        assert_eq!(fonts.len(), 3);
+        // 加载一次之后使用缓存
+        let font = fonts.font(2).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(fonts.font(2), Some(font));
+        assert!(fonts.font(3).is_none());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlink_loop() {
+        let dir = std::env::temp_dir().join("dz-print-fonts-loop");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("a")).unwrap();
+        let data = include_bytes!("../asset/UnifontExMono.ttf");
+        std::fs::write(dir.join("a/mono.ttf"), data).unwrap();
+        // a/loop -> .. -> a/loop -> ...
+        std::os::unix::fs::symlink(&dir, dir.join("a/loop")).unwrap();
+
+        let mut fonts = Fonts::new();
+        fonts.search_dir(&dir);
+        assert_eq!(fonts.len(), 3);
+        // 再搜索一次也不会重复添加
+        fonts.search_dir(&dir.join("a/loop/a"));
+        assert_eq!(fonts.len(), 3);
+    }
+}
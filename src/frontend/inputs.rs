@@ -0,0 +1,136 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Typst 输入 (`sys.inputs`): 命令行的键值对, JSON 或者 CSV 数据
+//!
+//! 数据文件的每条记录打印一份 (邮件合并), 记录的字段合并到 `sys.inputs` 里,
+//! JSON 保留原来的类型, CSV 的字段都是字符串
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use thiserror::Error;
+use typst::foundations::{Dict, Str, Value};
+
+#[derive(Error, Debug)]
+pub enum InputError {
+    #[error("invalid input `{0}`, expected `key=value`")]
+    InvalidPair(String),
+    #[error("cannot read `{0}`: {1}")]
+    Io(PathBuf, io::Error),
+    #[error("unsupported data file `{0}`, expected .json or .csv")]
+    UnsupportedFormat(PathBuf),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    Csv(#[from] csv::Error),
+    #[error("record {0} is not a JSON object")]
+    NotAnObject(usize),
+}
+
+/// 解析命令行的 `key=value`
+pub fn parse_pair(s: &str) -> Result<(String, String), InputError> {
+    match s.split_once('=') {
+        Some((k, v)) if !k.trim().is_empty() => Ok((k.trim().to_string(), v.to_string())),
+        _ => Err(InputError::InvalidPair(s.to_string())),
+    }
+}
+
+/// 键值对转换成 `sys.inputs`
+pub fn pairs_to_dict(pairs: &[(String, String)]) -> Dict {
+    pairs
+        .iter()
+        .map(|(k, v)| (Str::from(k.as_str()), Value::Str(v.as_str().into())))
+        .collect()
+}
+
+/// JSON 数据: 对象的数组, 或者单个对象
+pub fn parse_json(text: &str) -> Result<Vec<Dict>, InputError> {
+    let records = match serde_json::from_str::<Value>(text)? {
+        Value::Array(a) => a.into_iter().collect(),
+        x => vec![x],
+    };
+    records
+        .into_iter()
+        .enumerate()
+        .map(|(i, x)| match x {
+            Value::Dict(d) => Ok(d),
+            _ => Err(InputError::NotAnObject(i)),
+        })
+        .collect()
+}
+
+/// CSV 数据: 第一行是字段名, 后面每行一条记录
+pub fn parse_csv(text: &str) -> Result<Vec<Dict>, InputError> {
+    let mut reader = csv::Reader::from_reader(text.as_bytes());
+    let headers = reader.headers()?.clone();
+    reader
+        .records()
+        .map(|row| {
+            Ok(headers
+                .iter()
+                .zip(row?.iter())
+                .map(|(k, v)| (Str::from(k), Value::Str(v.into())))
+                .collect())
+        })
+        .collect()
+}
+
+/// 按扩展名读取数据文件
+pub fn load_records(path: &Path) -> Result<Vec<Dict>, InputError> {
+    let text = std::fs::read_to_string(path).map_err(|e| InputError::Io(path.into(), e))?;
+    match path.extension().and_then(|x| x.to_str()) {
+        Some(x) if x.eq_ignore_ascii_case("json") => parse_json(&text),
+        Some(x) if x.eq_ignore_ascii_case("csv") => parse_csv(&text),
+        _ => Err(InputError::UnsupportedFormat(path.into())),
+    }
+}
+
+/// 把一条记录合并到 `base` 里, 记录的字段优先
+pub fn merge(base: &Dict, record: &Dict) -> Dict {
+    let mut out = base.clone();
+    for (k, v) in record.iter() {
+        out.insert(k.clone(), v.clone());
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use typst::foundations::{IntoValue, Value};
+
+    use super::{merge, pairs_to_dict, parse_csv, parse_json, parse_pair, InputError};
+
+    #[test]
+    fn test_pairs() {
+        assert_eq!(
+            parse_pair("name=a=b").unwrap(),
+            ("name".to_string(), "a=b".to_string())
+        );
+        assert!(matches!(parse_pair("=x"), Err(InputError::InvalidPair(_))));
+        assert!(parse_pair("name").is_err());
+    }
+
+    #[test]
+    fn test_records() {
+        let rows = parse_csv("name,zip\nAlice,100000\n\"Bob, Jr.\",200000\n").unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[1].get("name").unwrap(), &"Bob, Jr.".into_value());
+
+        let rows = parse_json(r#"[{"name": "Alice", "count": 3}, {"name": "Bob"}]"#).unwrap();
+        assert_eq!(rows[0].get("count").unwrap(), &Value::Int(3));
+        assert_eq!(parse_json(r#"{"name": "Alice"}"#).unwrap().len(), 1);
+        assert!(matches!(parse_json("[1]"), Err(InputError::NotAnObject(0))));
+
+        let base = pairs_to_dict(&[
+            ("name".to_string(), "?".to_string()),
+            ("shop".to_string(), "A".to_string()),
+        ]);
+        let merged = merge(&base, &rows[1]);
+        assert_eq!(merged.get("name").unwrap(), &"Bob".into_value());
+        assert_eq!(merged.get("shop").unwrap(), &"A".into_value());
+    }
+}
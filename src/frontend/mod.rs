@@ -0,0 +1,12 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+pub mod barcode;
+pub mod diagnostics;
+pub mod fonts;
+pub mod inputs;
+pub mod scale;
+pub mod settings;
+pub mod watch;
+pub mod world;
@@ -0,0 +1,127 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! 渲染比例: 由打印机的分辨率和打印头宽度算出每 pt 多少像素
+//!
+//! 页面比打印头窄时右边补白, 比打印头宽时报错, 或者整体缩放到打印头宽度
+
+use thiserror::Error;
+
+const PT_PER_INCH: f64 = 72.0;
+const MM_PER_INCH: f64 = 25.4;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum ScaleError {
+    #[error(
+        "page {page} is {width:.1}mm wide ({dots} dots at {dpi} dpi), but the printer only prints \
+         {head:.1}mm ({head_dots} dots); use `#set page(width: {head:.1}mm)` or scale the page to fit"
+    )]
+    TooWide {
+        page: usize,
+        width: f64,
+        dots: u32,
+        dpi: u16,
+        head: f64,
+        head_dots: u32,
+    },
+    #[error("page {0} has no width")]
+    EmptyPage(usize),
+}
+
+/// 打印区域
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PrintArea {
+    /// 每英寸的点数
+    pub dpi: u16,
+    /// 打印头的点数
+    pub head_dots: u32,
+}
+
+impl Default for PrintArea {
+    /// DP27P: 300 dpi, 576 点
+    fn default() -> Self {
+        PrintArea::new(300, 576)
+    }
+}
+
+impl PrintArea {
+    pub fn new(dpi: u16, head_dots: u32) -> Self {
+        PrintArea {
+            dpi: dpi.max(1),
+            head_dots,
+        }
+    }
+
+    /// 不缩放时每 pt 的像素数
+    pub fn pixel_per_pt(&self) -> f64 {
+        self.dpi as f64 / PT_PER_INCH
+    }
+
+    /// 打印头宽度 (mm)
+    pub fn head_mm(&self) -> f64 {
+        self.head_dots as f64 / self.dpi as f64 * MM_PER_INCH
+    }
+
+    /// 长度 (mm) 对应的点数
+    pub fn dots(&self, mm: f64) -> u32 {
+        (mm / MM_PER_INCH * self.dpi as f64).round().max(0.0) as u32
+    }
+
+    /// 第 `page` 页 (从 1 开始) 的渲染比例, 页面宽度单位是 pt
+    ///
+    /// `fit` 时缩放到正好是打印头宽度, 否则按真实尺寸渲染并检查宽度
+    pub fn scale(&self, page: usize, width: f64, fit: bool) -> Result<f32, ScaleError> {
+        if width <= 0.0 {
+            return Err(ScaleError::EmptyPage(page));
+        }
+        if fit {
+            return Ok((self.head_dots as f64 / width) as f32);
+        }
+        let dots = (width * self.pixel_per_pt()).round() as u32;
+        if dots > self.head_dots {
+            return Err(ScaleError::TooWide {
+                page,
+                width: width / PT_PER_INCH * MM_PER_INCH,
+                dots,
+                dpi: self.dpi,
+                head: self.head_mm(),
+                head_dots: self.head_dots,
+            });
+        }
+        Ok(self.pixel_per_pt() as f32)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{PrintArea, ScaleError};
+
+    const MM: f64 = 72.0 / 25.4;
+
+    #[test]
+    fn test_scale() {
+        // DP27P: 300 dpi, 576 点
+        let area = PrintArea::new(300, 576);
+        assert!((area.head_mm() - 48.77).abs() < 0.01);
+        assert_eq!(area.dots(40.0), 472);
+        assert_eq!(area.scale(1, 48.0 * MM, false), Ok(300.0 / 72.0));
+        assert_eq!(
+            area.scale(1, 48.0 * MM, true),
+            Ok((576.0 / (48.0 * MM)) as f32)
+        );
+
+        let err = area.scale(2, 57.0 * MM, false).unwrap_err();
+        assert!(matches!(
+            err,
+            ScaleError::TooWide {
+                page: 2,
+                dots: 673,
+                ..
+            }
+        ));
+        assert!(err.to_string().contains("page 2 is 57.0mm wide"), "{err}");
+        assert!(err.to_string().contains("width: 48.8mm"), "{err}");
+        assert_eq!(area.scale(3, 0.0, true), Err(ScaleError::EmptyPage(3)));
+    }
+}
@@ -0,0 +1,694 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! 每页的打印设置, 在文档里用 `<print-settings>` 标记的 metadata 声明
+//!
+//! ```typst
+//! #metadata((paper: "adhesive", darkness: 8, copies: 2, label-width: 40mm)) <print-settings>
+//! ```
+//!
+//! | 键 | 类型 | 默认值 | 说明 |
+//! |----|------|--------|------|
+//! | `paper` | `"ticket"`, `"adhesive"`, `"cardpaper"`, `"transparent"` | `"ticket"` | 纸张类型 |
+//! | `darkness` | 1 到 15, 或者 `"min"`, `"normal"`, `"max"` | 6 | 打印浓度 |
+//! | `speed` | 1 到 5, 或者 `"min"`, `"normal"`, `"max"` | 3 | 打印速度 |
+//! | `gap` | 长度, 或者单位 0.01mm 的整数, 至少 0.5mm | `0.5mm` | 标签间隙 |
+//! | `copies` | 正整数 | 1 | 份数 |
+//! | `dither` | `"floyd-steinberg"`, `"threshold"` | `"floyd-steinberg"` | 抖动方式 |
+//! | `threshold` | 0 到 255 | 128 | `dither: "threshold"` 时亮度低于这个值的像素打印成黑色 |
+//! | `rotate` | 0, 90, 180, 270 (整数或者角度) | 0 | 顺时针旋转 |
+//! | `feed` | 长度 | `0mm` | 内容之后额外走纸 |
+//! | `finish` | `"next-paper"`, `"stop"` | `"next-paper"` | 打印完定位到下一张纸, 或者停在内容后面 |
+//! | `label-width` | 长度 | 打印头宽度 | 标签宽度, 内容在打印头中间 |
+//!
+//! 同一页有多个设置时后面的生效, 没有设置的页面使用默认值
+
+use std::collections::HashMap;
+
+use thiserror::Error;
+use tiny_skia::Pixmap;
+use typst::{
+    diag::{SourceDiagnostic, SourceResult},
+    ecow::EcoVec,
+    foundations::{Dict, Label, Selector, Value},
+    layout::PagedDocument,
+    utils::PicoStr,
+};
+
+use super::scale::PrintArea;
+use crate::{
+    backend,
+    command::{self, HostCommand},
+    image_proc::{
+        label::{LabelGeometry, MIN_GAP},
+        pixel_luma, Bitmap, DitherMode,
+    },
+    pipeline::{
+        begin_job, collect_bitmap, print_stream_with, source::PixmapSource, Finish, PipelineError,
+        PrintOptions, StreamEncoder, StreamStats,
+    },
+};
+
+/// 文档里标记打印设置的标签
+pub const LABEL: &str = "print-settings";
+
+const KEYS: [&str; 11] = [
+    "paper",
+    "darkness",
+    "speed",
+    "gap",
+    "copies",
+    "dither",
+    "threshold",
+    "rotate",
+    "feed",
+    "finish",
+    "label-width",
+];
+
+#[derive(Error, Debug, PartialEq)]
+pub enum SettingsError {
+    #[error("print settings must be a dictionary, found {0}")]
+    NotADict(String),
+    #[error("unknown print setting `{0}`")]
+    UnknownKey(String),
+    #[error("`{0}` expects {1}, found {2}")]
+    InvalidType(&'static str, &'static str, String),
+    #[error("`{0}` must be {1}, got {2}")]
+    InvalidValue(&'static str, &'static str, String),
+}
+
+impl SettingsError {
+    fn hint(&self) -> Option<String> {
+        match self {
+            SettingsError::UnknownKey(_) => Some(format!("known settings are {}", KEYS.join(", "))),
+            _ => None,
+        }
+    }
+}
+
+/// 纸张类型, 值是发送给打印机的参数
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PaperType {
+    /// 小票纸
+    #[default]
+    Ticket = 0,
+    /// 不干胶
+    Adhesive = 2,
+    /// 卡纸
+    CardPaper = 3,
+    /// 透明贴
+    Transparent = 4,
+}
+
+/// 一页的打印设置
+#[derive(Debug, Clone, PartialEq)]
+pub struct PageSettings {
+    pub paper: PaperType,
+    /// 1 到 15
+    pub darkness: u8,
+    /// 1 到 5
+    pub speed: u8,
+    /// 标签间隙, 单位 0.01mm
+    pub gap: u16,
+    pub copies: u32,
+    pub dither: DitherMode,
+    pub threshold: u8,
+    /// 顺时针旋转的角度
+    pub rotate: u16,
+    /// 内容之后额外走纸 (mm)
+    pub feed: f64,
+    pub finish: Finish,
+    /// 标签宽度 (mm), `None` 表示和打印头一样宽
+    pub label_width: Option<f64>,
+}
+
+impl Default for PageSettings {
+    fn default() -> Self {
+        PageSettings {
+            paper: PaperType::default(),
+            darkness: 6,
+            speed: 3,
+            gap: MIN_GAP,
+            copies: 1,
+            dither: DitherMode::FloydSteinberg,
+            threshold: 128,
+            rotate: 0,
+            feed: 0.0,
+            finish: Finish::default(),
+            label_width: None,
+        }
+    }
+}
+
+fn type_error(key: &'static str, expected: &'static str, v: &Value) -> SettingsError {
+    SettingsError::InvalidType(key, expected, v.ty().to_string())
+}
+
+/// 整数, 或者 `"min"`/`"normal"`/`"max"` 和数字字符串
+fn level(key: &'static str, v: &Value, max: u8, normal: u8) -> Result<u8, SettingsError> {
+    let expected = match max {
+        5 => "an integer from 1 to 5",
+        _ => "an integer from 1 to 15",
+    };
+    let n = match v {
+        Value::Int(x) => *x,
+        Value::Str(s) => match s.as_str() {
+            "min" => 1,
+            "normal" => normal as i64,
+            "max" => max as i64,
+            x => x
+                .parse()
+                .map_err(|_| SettingsError::InvalidValue(key, expected, format!("{x:?}")))?,
+        },
+        _ => return Err(type_error(key, "an integer or a string", v)),
+    };
+    match u8::try_from(n) {
+        Ok(x) if (1..=max).contains(&x) => Ok(x),
+        _ => Err(SettingsError::InvalidValue(key, expected, n.to_string())),
+    }
+}
+
+/// 长度 (mm), 不能用 `em`
+fn length(key: &'static str, v: &Value) -> Result<f64, SettingsError> {
+    match v {
+        Value::Length(x) if x.em.get() == 0.0 => Ok(x.abs.to_mm()),
+        Value::Length(x) => Err(SettingsError::InvalidValue(
+            key,
+            "an absolute length",
+            format!("{x:?}"),
+        )),
+        _ => Err(type_error(key, "a length", v)),
+    }
+}
+
+fn string<'a>(key: &'static str, v: &'a Value) -> Result<&'a str, SettingsError> {
+    match v {
+        Value::Str(x) => Ok(x.as_str()),
+        _ => Err(type_error(key, "a string", v)),
+    }
+}
+
+impl PageSettings {
+    /// 从 metadata 的值解析, 检查所有的键和值
+    pub fn from_value(value: &Value) -> Result<Self, SettingsError> {
+        match value {
+            Value::Dict(d) => Self::from_dict(d),
+            x => Err(SettingsError::NotADict(x.ty().to_string())),
+        }
+    }
+
+    pub fn from_dict(dict: &Dict) -> Result<Self, SettingsError> {
+        let mut s = PageSettings::default();
+        for (k, v) in dict.iter() {
+            match k.as_str() {
+                "paper" => {
+                    s.paper = match string("paper", v)? {
+                        "ticket" => PaperType::Ticket,
+                        "adhesive" => PaperType::Adhesive,
+                        "cardpaper" => PaperType::CardPaper,
+                        "transparent" => PaperType::Transparent,
+                        x => {
+                            return Err(SettingsError::InvalidValue(
+                                "paper",
+                                "\"ticket\", \"adhesive\", \"cardpaper\" or \"transparent\"",
+                                format!("{x:?}"),
+                            ))
+                        }
+                    }
+                }
+                "darkness" => s.darkness = level("darkness", v, 15, 6)?,
+                "speed" => s.speed = level("speed", v, 5, 3)?,
+                "gap" => {
+                    let gap = match v {
+                        Value::Int(x) => *x as f64,
+                        Value::Str(x) => {
+                            let x = x.as_str().trim().to_lowercase();
+                            let mm = x.strip_suffix("mm").unwrap_or(&x).trim().parse::<f64>();
+                            mm.map_err(|_| type_error("gap", "a length", v))? * 100.0
+                        }
+                        _ => length("gap", v)? * 100.0,
+                    }
+                    .round();
+                    if !(MIN_GAP as f64..=u16::MAX as f64).contains(&gap) {
+                        return Err(SettingsError::InvalidValue(
+                            "gap",
+                            "at least 0.5mm",
+                            format!("{}mm", gap / 100.0),
+                        ));
+                    }
+                    s.gap = gap as u16;
+                }
+                "copies" => {
+                    s.copies = match v {
+                        Value::Int(x) if *x >= 1 && *x <= u32::MAX as i64 => *x as u32,
+                        Value::Int(x) => {
+                            return Err(SettingsError::InvalidValue(
+                                "copies",
+                                "a positive integer",
+                                x.to_string(),
+                            ))
+                        }
+                        _ => return Err(type_error("copies", "an integer", v)),
+                    }
+                }
+                "dither" => {
+                    s.dither = match string("dither", v)? {
+                        "floyd-steinberg" => DitherMode::FloydSteinberg,
+                        "threshold" => DitherMode::Threshold,
+                        x => {
+                            return Err(SettingsError::InvalidValue(
+                                "dither",
+                                "\"floyd-steinberg\" or \"threshold\"",
+                                format!("{x:?}"),
+                            ))
+                        }
+                    }
+                }
+                "threshold" => {
+                    s.threshold = match v {
+                        Value::Int(x) => u8::try_from(*x).map_err(|_| {
+                            SettingsError::InvalidValue(
+                                "threshold",
+                                "an integer from 0 to 255",
+                                x.to_string(),
+                            )
+                        })?,
+                        _ => return Err(type_error("threshold", "an integer", v)),
+                    }
+                }
+                "rotate" => {
+                    let deg = match v {
+                        Value::Int(x) => *x as f64,
+                        Value::Angle(x) => x.to_deg(),
+                        _ => return Err(type_error("rotate", "an integer or an angle", v)),
+                    };
+                    // 角度是用弧度存的, 不一定正好是 90 的倍数
+                    let quarters = deg.rem_euclid(360.0) / 90.0;
+                    s.rotate = match quarters.round() {
+                        x if (quarters - x).abs() < 1e-6 => (x as u16 % 4) * 90,
+                        _ => {
+                            return Err(SettingsError::InvalidValue(
+                                "rotate",
+                                "a multiple of 90 degrees",
+                                format!("{deg}deg"),
+                            ))
+                        }
+                    }
+                }
+                "feed" => s.feed = length("feed", v)?.max(0.0),
+                "finish" => {
+                    s.finish = match string("finish", v)? {
+                        "next-paper" => Finish::NextPaper,
+                        "stop" => Finish::Stop,
+                        x => {
+                            return Err(SettingsError::InvalidValue(
+                                "finish",
+                                "\"next-paper\" or \"stop\"",
+                                format!("{x:?}"),
+                            ))
+                        }
+                    }
+                }
+                "label-width" => {
+                    let w = length("label-width", v)?;
+                    if w <= 0.0 {
+                        return Err(SettingsError::InvalidValue(
+                            "label-width",
+                            "a positive length",
+                            format!("{w}mm"),
+                        ));
+                    }
+                    s.label_width = Some(w);
+                }
+                x => return Err(SettingsError::UnknownKey(x.to_string())),
+            }
+        }
+        Ok(s)
+    }
+
+    /// 每隔多少行插入一个断点, 这个参数其实是 magic number, 可能受打印浓度影响
+    pub fn bp(&self) -> u32 {
+        // 最慢 50 | 较慢 75 | 正常 100 | 较快 110 | 最快 120
+        let basic_bp: i32 = match self.speed {
+            1 => 50,
+            2 => 75,
+            4 => 110,
+            5 => 120,
+            _ => 100,
+        };
+        let adjustment = (6 - self.darkness as i32) * 3;
+        (basic_bp + adjustment).max(0) as u32
+    }
+
+    /// 旋转之后页面的宽度 (pt)
+    pub fn printed_width(&self, width: f64, height: f64) -> f64 {
+        if self.rotate % 180 == 90 {
+            height
+        } else {
+            width
+        }
+    }
+
+    /// 旋转, 阈值模式下把每个像素变成纯黑或者纯白
+    pub fn prepare(&self, pm: &Pixmap) -> Pixmap {
+        let (w, h) = (pm.width(), pm.height());
+        let (ow, oh) = if self.rotate % 180 == 90 {
+            (h, w)
+        } else {
+            (w, h)
+        };
+        let mut out = Pixmap::new(ow.max(1), oh.max(1)).unwrap();
+        let src = pm.pixels();
+        let dst = out.pixels_mut();
+        for y in 0..h {
+            for x in 0..w {
+                let (dx, dy) = match self.rotate {
+                    90 => (h - 1 - y, x),
+                    180 => (w - 1 - x, h - 1 - y),
+                    270 => (y, w - 1 - x),
+                    _ => (x, y),
+                };
+                dst[(dy * ow + dx) as usize] = src[(y * w + x) as usize];
+            }
+        }
+        if self.dither == DitherMode::Threshold {
+            let black = tiny_skia::ColorU8::from_rgba(0, 0, 0, 255).premultiply();
+            let white = tiny_skia::ColorU8::from_rgba(255, 255, 255, 255).premultiply();
+            for px in out.pixels_mut() {
+                *px = if pixel_luma(*px) < self.threshold {
+                    black
+                } else {
+                    white
+                };
+            }
+        }
+        out
+    }
+
+    /// 发送纸张类型, 浓度, 速度和间隙
+    pub async fn send(&self, b: &backend::USBBackend) -> Result<(), PipelineError> {
+        set(b, HostCommand::GetSetPrintPaperType, vec![self.paper as u8]).await?;
+        set(b, HostCommand::GetSetPrintDarkness, vec![self.darkness - 1]).await?;
+        set(b, HostCommand::GetSetPrintSpeed, vec![self.speed - 1]).await?;
+        set(
+            b,
+            HostCommand::GetSetPrintPaperGap,
+            self.gap.to_be_bytes().to_vec(),
+        )
+        .await
+    }
+}
+
+async fn set(
+    b: &backend::USBBackend,
+    c: HostCommand,
+    payload: Vec<u8>,
+) -> Result<(), PipelineError> {
+    let (cmd, chan) =
+        backend::Command::without_response(command::Command::new_host(c).package(payload, false));
+    b.push(cmd)
+        .await
+        .map_err(|_| PipelineError::BackendClosed)?;
+    chan.await.map_err(|_| PipelineError::BackendClosed)?;
+    Ok(())
+}
+
+/// 读取文档里每页的设置, 键是页码 (从 1 开始); 所有错误都带着 metadata 的位置
+pub fn page_settings(doc: &PagedDocument) -> SourceResult<HashMap<usize, PageSettings>> {
+    let selector = Selector::Label(Label::new(PicoStr::intern(LABEL)).unwrap());
+    let mut out = HashMap::new();
+    let mut errors = EcoVec::new();
+    for content in doc.introspector.query(&selector) {
+        let Some(location) = content.location() else {
+            continue;
+        };
+        let page = doc.introspector.page(location).get();
+        let settings = match content.get_by_name("value") {
+            Ok(v) => PageSettings::from_value(&v),
+            Err(_) => Err(SettingsError::NotADict(content.elem().name().into())),
+        };
+        match settings {
+            Ok(s) => {
+                out.insert(page, s);
+            }
+            Err(e) => {
+                let mut diag = SourceDiagnostic::error(content.span(), e.to_string());
+                if let Some(hint) = e.hint() {
+                    diag.hint(hint);
+                }
+                errors.push(diag);
+            }
+        }
+    }
+    if errors.is_empty() {
+        Ok(out)
+    } else {
+        Err(errors)
+    }
+}
+
+/// 标签宽度对应的尺寸, 没有设置时是 `None`
+fn geometry(settings: &PageSettings, area: &PrintArea) -> Option<LabelGeometry> {
+    settings
+        .label_width
+        .map(|w| LabelGeometry::new(area.dots(w), 0, 0).centred(area.head_dots))
+}
+
+/// 打印预览, 和 [print_page] 打印出来的一份完全一样
+pub fn preview(pm: &Pixmap, settings: &PageSettings, area: &PrintArea) -> Bitmap {
+    let pm = settings.prepare(pm);
+    match geometry(settings, area) {
+        Some(g) => {
+            let source = PixmapSource::new(pm.width(), std::iter::once(pm));
+            g.fit(&collect_bitmap(source, settings.dither))
+        }
+        None => {
+            let source = PixmapSource::new(area.head_dots, std::iter::once(pm));
+            collect_bitmap(source, settings.dither)
+        }
+    }
+}
+
+/// 按设置打印一页: 发送设置, 然后打印 `copies` 份, 返回每份的统计
+pub async fn print_page(
+    b: &backend::USBBackend,
+    pm: &Pixmap,
+    settings: &PageSettings,
+    area: &PrintArea,
+    options: &PrintOptions,
+) -> Result<Vec<StreamStats>, PipelineError> {
+    begin_job(b).await?;
+    settings.send(b).await?;
+    let pm = settings.prepare(pm);
+    let options = PrintOptions {
+        feed: area.dots(settings.feed),
+        finish: settings.finish,
+        ..options.clone()
+    };
+    let geometry = geometry(settings, area);
+    let mut stats = vec![];
+    for _ in 0..settings.copies {
+        // 有标签宽度时由标签居中, 否则靠左并补白到打印头宽度
+        let width = geometry.map_or(area.head_dots, |_| pm.width());
+        let source = PixmapSource::new(width, std::iter::once(pm.clone()));
+        let mut encoder = StreamEncoder::new(source, settings.dither, settings.bp());
+        if let Some(g) = geometry {
+            encoder = encoder.with_geometry(g);
+        }
+        stats.push(print_stream_with(b, encoder, &options).await?);
+    }
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod test {
+    use tiny_skia::Pixmap;
+    use typst::{
+        foundations::{Dict, IntoValue, Str, Value},
+        layout::{Abs, Angle, Length, PagedDocument},
+    };
+
+    use super::{page_settings, preview, print_page, PageSettings, PaperType, SettingsError};
+    use crate::{
+        backend::USBBackend,
+        frontend::{scale::PrintArea, world::TypstWorld},
+        image_proc::DitherMode,
+        pipeline::{fake_printer, Finish, PrintOptions},
+    };
+
+    fn compile(name: &str, source: &str) -> PagedDocument {
+        let dir = std::env::temp_dir().join(format!("dz-print-settings-{name}"));
+        std::fs::create_dir_all(&dir).unwrap();
+        let main = dir.join("main.typ");
+        std::fs::write(&main, source).unwrap();
+        let world = TypstWorld::new(&main, None).unwrap();
+        typst::compile::<PagedDocument>(&world).output.unwrap()
+    }
+
+    fn dict(pairs: &[(&str, Value)]) -> Dict {
+        pairs
+            .iter()
+            .map(|(k, v)| (Str::from(*k), v.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        let mm = |x: f64| Value::Length(Length::from(Abs::mm(x)));
+        let s = PageSettings::from_dict(&dict(&[
+            ("paper", "adhesive".into_value()),
+            ("darkness", "max".into_value()),
+            ("speed", 2.into_value()),
+            ("gap", mm(2.0)),
+            ("copies", 3.into_value()),
+            ("dither", "threshold".into_value()),
+            ("threshold", 100.into_value()),
+            ("rotate", Value::Angle(Angle::deg(-90.0))),
+            ("feed", mm(5.0)),
+            ("finish", "stop".into_value()),
+            ("label-width", mm(40.0)),
+        ]))
+        .unwrap();
+        assert_eq!(
+            s,
+            PageSettings {
+                paper: PaperType::Adhesive,
+                darkness: 15,
+                speed: 2,
+                gap: 200,
+                copies: 3,
+                dither: DitherMode::Threshold,
+                threshold: 100,
+                rotate: 270,
+                feed: 5.0,
+                finish: Finish::Stop,
+                label_width: Some(40.0),
+            }
+        );
+        // 旧的写法
+        let s = PageSettings::from_dict(&dict(&[
+            ("darkness", "6".into_value()),
+            ("gap", "3mm".into_value()),
+        ]))
+        .unwrap();
+        assert_eq!((s.darkness, s.gap, s.bp()), (6, 300, 100));
+        assert_eq!(PageSettings::default().bp(), 100);
+    }
+
+    #[test]
+    fn test_errors() {
+        let parse = |k: &str, v: Value| PageSettings::from_dict(&dict(&[(k, v)])).unwrap_err();
+        assert_eq!(
+            parse("colour", 1.into_value()),
+            SettingsError::UnknownKey("colour".into())
+        );
+        assert_eq!(
+            parse("darkness", 16.into_value()).to_string(),
+            "`darkness` must be an integer from 1 to 15, got 16"
+        );
+        assert!(matches!(
+            parse("copies", "2".into_value()),
+            SettingsError::InvalidType("copies", _, _)
+        ));
+        assert!(matches!(
+            parse("rotate", 45.into_value()),
+            SettingsError::InvalidValue("rotate", _, _)
+        ));
+        assert!(matches!(
+            parse("gap", 10.into_value()),
+            SettingsError::InvalidValue("gap", _, _)
+        ));
+        assert!(matches!(
+            PageSettings::from_value(&1.into_value()),
+            Err(SettingsError::NotADict(_))
+        ));
+    }
+
+    #[test]
+    fn test_prepare() {
+        let mut pm = Pixmap::new(3, 2).unwrap();
+        pm.fill(tiny_skia::Color::WHITE);
+        // 左上角是灰色
+        pm.pixels_mut()[0] = tiny_skia::ColorU8::from_rgba(100, 100, 100, 255).premultiply();
+        let s = PageSettings {
+            rotate: 90,
+            dither: DitherMode::Threshold,
+            threshold: 101,
+            ..Default::default()
+        };
+        let out = s.prepare(&pm);
+        assert_eq!((out.width(), out.height()), (2, 3));
+        // 顺时针旋转后在右上角, 并且变成纯黑
+        assert_eq!(out.pixel(1, 0).unwrap().red(), 0);
+        assert_eq!(out.pixel(0, 0).unwrap().red(), 255);
+        assert_eq!(s.printed_width(3.0, 2.0), 2.0);
+        let s = PageSettings {
+            threshold: 100,
+            ..s
+        };
+        assert_eq!(s.prepare(&pm).pixel(1, 0).unwrap().red(), 255);
+    }
+
+    #[test]
+    fn test_preview() {
+        let mut pm = Pixmap::new(10, 4).unwrap();
+        pm.fill(tiny_skia::Color::BLACK);
+        let area = PrintArea::new(254, 20);
+        let bm = preview(&pm, &PageSettings::default(), &area);
+        // 靠左, 补白到打印头宽度
+        assert_eq!((bm.width(), bm.height()), (20, 4));
+        assert!(bm.get_pixel(9, 0) && !bm.get_pixel(10, 0));
+
+        // 标签 1.2mm = 12 点, 在打印头中间
+        let s = PageSettings {
+            label_width: Some(1.2),
+            rotate: 90,
+            ..Default::default()
+        };
+        let bm = preview(&pm, &s, &area);
+        assert_eq!((bm.width(), bm.height()), (16, 10));
+        assert!(!bm.get_pixel(7, 0) && bm.get_pixel(8, 0) && bm.get_pixel(11, 9));
+        assert!(!bm.get_pixel(12, 0));
+    }
+
+    #[test]
+    fn test_page_settings() {
+        let doc = compile(
+            "ok",
+            "#set page(numbering: \"i\")\n\
+             #metadata((copies: 2)) <print-settings>\n\
+             #pagebreak()\n\
+             #metadata((copies: 3, rotate: 90deg)) <print-settings>",
+        );
+        let settings = page_settings(&doc).unwrap();
+        assert_eq!(settings[&1].copies, 2);
+        assert_eq!((settings[&2].copies, settings[&2].rotate), (3, 90));
+
+        let doc = compile("err", "#metadata((colour: 1)) <print-settings>");
+        let errors = page_settings(&doc).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message, "unknown print setting `colour`");
+        assert!(errors[0].hints[0].contains("label-width"));
+        assert!(!errors[0].span.is_detached());
+    }
+
+    #[tokio::test]
+    async fn test_print_page() {
+        let (b, rx) = USBBackend::mock();
+        let printer = fake_printer(rx, |op, _| if op == 0x80 { vec![0x7f] } else { vec![0] });
+        let pm = Pixmap::new(8, 4).unwrap();
+        let area = PrintArea::new(254, 16);
+        let options = PrintOptions::default();
+        print_page(&b, &pm, &PageSettings::default(), &area, &options)
+            .await
+            .unwrap();
+        drop(b);
+        // 发送设置之前先检查状态并激活高位命令
+        let log = printer.await.unwrap();
+        let ops: Vec<u8> = log.iter().filter_map(|x| x.get(1).copied()).collect();
+        assert_eq!(ops[..3], [0x70, 0x80, 0x42]);
+        assert_eq!(ops.iter().filter(|x| **x == 0x80).count(), 1);
+    }
+}
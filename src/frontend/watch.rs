@@ -0,0 +1,86 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! 监视文件修改, 轮询修改时间和大小, 不依赖系统的文件通知
+
+use std::{collections::HashMap, path::PathBuf, time::SystemTime};
+
+/// 文件的修改时间和大小, 文件不存在时是 `None`
+type Stamp = Option<(SystemTime, u64)>;
+
+fn stamp(path: &PathBuf) -> Stamp {
+    let meta = std::fs::metadata(path).ok()?;
+    Some((meta.modified().ok()?, meta.len()))
+}
+
+#[derive(Debug, Default)]
+pub struct Watcher {
+    files: HashMap<PathBuf, Stamp>,
+}
+
+impl Watcher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 替换监视的文件列表, 记录它们当前的状态
+    pub fn watch(&mut self, paths: impl IntoIterator<Item = PathBuf>) {
+        self.files = paths
+            .into_iter()
+            .map(|p| {
+                let s = stamp(&p);
+                (p, s)
+            })
+            .collect();
+    }
+
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    /// 有文件被修改, 创建或者删除时返回 `true`, 并记录新的状态
+    pub fn changed(&mut self) -> bool {
+        let mut changed = false;
+        for (path, old) in self.files.iter_mut() {
+            let new = stamp(path);
+            if new != *old {
+                *old = new;
+                changed = true;
+            }
+        }
+        changed
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Watcher;
+
+    #[test]
+    fn test_watcher() {
+        let dir = std::env::temp_dir().join("dz-print-watch");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let (a, b) = (dir.join("a.typ"), dir.join("b.typ"));
+        std::fs::write(&a, "a").unwrap();
+
+        let mut w = Watcher::new();
+        w.watch([a.clone(), b.clone()]);
+        assert_eq!(w.len(), 2);
+        assert!(!w.changed());
+        // 大小变化, 即使修改时间的精度不够也能发现
+        std::fs::write(&a, "aa").unwrap();
+        assert!(w.changed());
+        assert!(!w.changed());
+        // 之前不存在的文件
+        std::fs::write(&b, "b").unwrap();
+        assert!(w.changed());
+        std::fs::remove_file(&a).unwrap();
+        assert!(w.changed());
+    }
+}
@@ -0,0 +1,417 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Typst [World]: 根目录里的文件, 本地的包, 字体
+//!
+//! 包只从磁盘读取, 目录结构和 typst 一样: `{包目录}/{namespace}/{name}/{version}/`,
+//! `@preview` 的包需要先用 typst 下载到缓存目录
+//!
+//! 源文件会被缓存, 重新编译时只增量解析修改过的部分, 配合 comemo 的缓存用于监视模式
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use chrono::{DateTime, Datelike, FixedOffset, Local, NaiveDate, NaiveDateTime, TimeZone};
+use thiserror::Error;
+use typst::{
+    diag::{FileError, FileResult, PackageError},
+    foundations::{Bytes, Datetime, Dict},
+    syntax::{package::PackageSpec, FileId, Source, VirtualPath},
+    text::{Font, FontBook},
+    utils::LazyHash,
+    Library, LibraryExt, World,
+};
+
+use super::{barcode, fonts::Fonts};
+
+#[derive(Error, Debug)]
+pub enum WorldError {
+    #[error("cannot read `{0}`: {1}")]
+    Io(PathBuf, io::Error),
+    #[error("`{0}` is outside of the root directory `{1}`")]
+    OutsideRoot(PathBuf, PathBuf),
+    #[error(
+        "invalid date/time `{0}`, expected `2024-05-01`, `2024-05-01 08:30` or `2024-05-01T08:30:00+08:00`"
+    )]
+    InvalidDatetime(String),
+}
+
+/// 我的[世界](typst::World)
+pub struct TypstWorld {
+    library: LazyHash<Library>,
+    book: LazyHash<FontBook>,
+    fonts: Fonts,
+    main: FileId,
+    root: PathBuf,
+    package_paths: Vec<PathBuf>,
+    /// 解析过的源文件
+    sources: Mutex<HashMap<FileId, Source>>,
+    /// 上次 [TypstWorld::reset] 之后读取过的文件
+    dependencies: Mutex<BTreeSet<PathBuf>>,
+    /// 固定的当前时间, 没有设置时用系统时间
+    now: Option<DateTime<FixedOffset>>,
+}
+
+impl TypstWorld {
+    /// `main` 是主文件, 只能访问 `root` 里的文件, `root` 默认是主文件所在的目录
+    pub fn new(main: &Path, root: Option<&Path>) -> Result<Self, WorldError> {
+        let canonicalize = |p: &Path| p.canonicalize().map_err(|e| WorldError::Io(p.into(), e));
+        let main = canonicalize(main)?;
+        let root = match root {
+            Some(root) => canonicalize(root)?,
+            None => main.parent().unwrap_or(Path::new("/")).to_path_buf(),
+        };
+        let vpath = VirtualPath::within_root(&main, &root)
+            .ok_or_else(|| WorldError::OutsideRoot(main.clone(), root.clone()))?;
+        let fonts = Fonts::new();
+        Ok(TypstWorld {
+            library: LazyHash::new(make_library(Dict::new())),
+            book: LazyHash::new(fonts.book().clone()),
+            fonts,
+            main: FileId::new(None, vpath),
+            root,
+            package_paths: default_package_paths(),
+            sources: Mutex::default(),
+            dependencies: Mutex::default(),
+            now: None,
+        })
+    }
+
+    pub fn with_inputs(mut self, inputs: Dict) -> Self {
+        self.set_inputs(inputs);
+        self
+    }
+
+    /// 设置 `sys.inputs`, 批量打印时每条记录调用一次
+    pub fn set_inputs(&mut self, inputs: Dict) {
+        self.library = LazyHash::new(make_library(inputs));
+    }
+
+    pub fn with_fonts(mut self, fonts: Fonts) -> Self {
+        self.book = LazyHash::new(fonts.book().clone());
+        self.fonts = fonts;
+        self
+    }
+
+    /// 添加包目录, 比默认的目录优先
+    pub fn with_package_path(mut self, path: PathBuf) -> Self {
+        self.package_paths.insert(0, path);
+        self
+    }
+
+    /// 固定 `datetime.today()` 用的当前时间, 用于可重复的渲染和补打旧的标签
+    pub fn with_now(mut self, now: DateTime<FixedOffset>) -> Self {
+        self.now = Some(now);
+        self
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// 开始新的一次编译, 清空读取过的文件列表; 源文件的缓存会保留
+    pub fn reset(&mut self) {
+        self.dependencies.get_mut().unwrap().clear();
+    }
+
+    /// 上次 [TypstWorld::reset] 之后编译读取过的文件, 包括读取失败的
+    pub fn dependencies(&self) -> Vec<PathBuf> {
+        self.dependencies.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// 包的根目录
+    fn package_root(&self, spec: &PackageSpec) -> FileResult<PathBuf> {
+        let sub = format!("{}/{}/{}", spec.namespace, spec.name, spec.version);
+        self.package_paths
+            .iter()
+            .map(|p| p.join(&sub))
+            .find(|p| p.is_dir())
+            .ok_or_else(|| FileError::Package(PackageError::NotFound(spec.clone())))
+    }
+
+    /// 把文件 ID 转换成磁盘上的路径, 不允许离开根目录或者包目录
+    fn resolve(&self, id: FileId) -> FileResult<PathBuf> {
+        let root = match id.package() {
+            Some(spec) => self.package_root(spec)?,
+            None => self.root.clone(),
+        };
+        let path = id.vpath().resolve(&root).ok_or(FileError::AccessDenied)?;
+        if !path.starts_with(&root) {
+            return Err(FileError::AccessDenied);
+        }
+        Ok(path)
+    }
+
+    fn read(&self, id: FileId) -> FileResult<Vec<u8>> {
+        let path = self.resolve(id)?;
+        self.dependencies.lock().unwrap().insert(path.clone());
+        if path.is_dir() {
+            return Err(FileError::IsDirectory);
+        }
+        std::fs::read(&path).map_err(|e| FileError::from_io(e, &path))
+    }
+}
+
+impl World for TypstWorld {
+    fn library(&self) -> &LazyHash<Library> {
+        &self.library
+    }
+
+    fn book(&self) -> &LazyHash<FontBook> {
+        &self.book
+    }
+
+    fn main(&self) -> FileId {
+        self.main
+    }
+
+    fn source(&self, id: FileId) -> FileResult<Source> {
+        let text = String::from_utf8(self.read(id)?).map_err(|_| FileError::InvalidUtf8)?;
+        let mut sources = self.sources.lock().unwrap();
+        match sources.get_mut(&id) {
+            Some(source) => {
+                // 内容没变时不会重新解析
+                source.replace(&text);
+                Ok(source.clone())
+            }
+            None => Ok(sources.entry(id).or_insert(Source::new(id, text)).clone()),
+        }
+    }
+
+    fn file(&self, id: FileId) -> FileResult<Bytes> {
+        Ok(Bytes::new(self.read(id)?))
+    }
+
+    fn font(&self, index: usize) -> Option<Font> {
+        self.fonts.font(index)
+    }
+
+    /// `offset` 是相对 UTC 的小时数, 没有时用本地时区 (或者固定时间自己的时区)
+    fn today(&self, offset: Option<i64>) -> Option<Datetime> {
+        let now = self.now.unwrap_or_else(|| Local::now().fixed_offset());
+        let date = match offset {
+            Some(hours) => {
+                let tz = FixedOffset::east_opt(i32::try_from(hours.checked_mul(3600)?).ok()?)?;
+                now.with_timezone(&tz).date_naive()
+            }
+            None => now.date_naive(),
+        };
+        Datetime::from_ymd(date.year(), date.month() as u8, date.day() as u8)
+    }
+}
+
+fn make_library(inputs: Dict) -> Library {
+    let mut library = Library::builder().with_inputs(inputs).build();
+    barcode::define(library.global.scope_mut());
+    library
+}
+
+/// 解析命令行里的时间: RFC 3339, 或者本地时区的 `YYYY-MM-DD[ HH:MM[:SS]]`
+pub fn parse_datetime(s: &str) -> Result<DateTime<FixedOffset>, WorldError> {
+    let s = s.trim();
+    if let Ok(t) = DateTime::parse_from_rfc3339(s) {
+        return Ok(t);
+    }
+    let naive = [
+        "%Y-%m-%d %H:%M:%S",
+        "%Y-%m-%dT%H:%M:%S",
+        "%Y-%m-%d %H:%M",
+        "%Y-%m-%dT%H:%M",
+    ]
+    .iter()
+    .find_map(|f| NaiveDateTime::parse_from_str(s, f).ok())
+    .or_else(|| {
+        NaiveDate::parse_from_str(s, "%Y-%m-%d")
+            .ok()
+            .and_then(|d| d.and_hms_opt(0, 0, 0))
+    })
+    .ok_or_else(|| WorldError::InvalidDatetime(s.to_string()))?;
+    Local
+        .from_local_datetime(&naive)
+        .earliest()
+        .map(|t| t.fixed_offset())
+        .ok_or_else(|| WorldError::InvalidDatetime(s.to_string()))
+}
+
+/// typst 默认的包目录: 本地的包 (`@local`) 和下载的包 (`@preview`)
+pub fn default_package_paths() -> Vec<PathBuf> {
+    let home = std::env::var_os("HOME").map(PathBuf::from);
+    let xdg = |var: &str, fallback: &str| {
+        std::env::var_os(var)
+            .map(PathBuf::from)
+            .or_else(|| home.as_ref().map(|h| h.join(fallback)))
+    };
+    [
+        xdg("XDG_DATA_HOME", ".local/share"),
+        xdg("XDG_CACHE_HOME", ".cache"),
+    ]
+    .into_iter()
+    .flatten()
+    .map(|p| p.join("typst/packages"))
+    .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::{Path, PathBuf};
+
+    use typst::{
+        diag::FileError, foundations::IntoValue, layout::PagedDocument, syntax::FileId, World,
+    };
+
+    use super::{parse_datetime, TypstWorld, WorldError};
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("dz-print-world-{name}"));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write(path: &Path, content: &str) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, content).unwrap();
+    }
+
+    #[test]
+    fn test_imports_and_packages() {
+        let dir = temp_dir("imports");
+        let packages = dir.join("packages");
+        write(
+            &packages.join("local/greet/0.1.0/typst.toml"),
+            "[package]\nname = \"greet\"\nversion = \"0.1.0\"\nentrypoint = \"lib.typ\"\n",
+        );
+        write(
+            &packages.join("local/greet/0.1.0/lib.typ"),
+            "#let hello(x) = [Hello #x]",
+        );
+        write(&dir.join("doc/parts/name.typ"), "#let name = \"label\"");
+        write(&dir.join("doc/data.txt"), "A-1234");
+        write(
+            &dir.join("doc/main.typ"),
+            "#import \"@local/greet:0.1.0\": hello\n\
+             #import \"parts/name.typ\": name\n\
+             #hello(name) #read(\"data.txt\")",
+        );
+        let world = TypstWorld::new(&dir.join("doc/main.typ"), None)
+            .unwrap()
+            .with_package_path(packages);
+        let doc = typst::compile::<PagedDocument>(&world);
+        assert!(doc.output.is_ok(), "{:?}", doc.output.err());
+
+        // 不能访问根目录以外的文件
+        let outside = world.main().join("../packages/local/greet/0.1.0/lib.typ");
+        assert_eq!(world.source(outside).err(), Some(FileError::AccessDenied));
+        // 没有安装的包
+        let doc = typst::compile::<PagedDocument>(
+            &TypstWorld::new(&dir.join("doc/main.typ"), None).unwrap(),
+        );
+        assert!(doc.output.is_err());
+        assert!(world
+            .file(FileId::new(None, typst::syntax::VirtualPath::new("parts")))
+            .is_err());
+    }
+
+    #[test]
+    fn test_inputs() {
+        let dir = temp_dir("inputs");
+        write(
+            &dir.join("main.typ"),
+            "#assert.eq(sys.inputs.name, \"Alice\")",
+        );
+        let mut world = TypstWorld::new(&dir.join("main.typ"), None).unwrap();
+        assert!(typst::compile::<PagedDocument>(&world).output.is_err());
+        let inputs = [("name".into(), "Alice".into_value())]
+            .into_iter()
+            .collect();
+        world.set_inputs(inputs);
+        let doc = typst::compile::<PagedDocument>(&world);
+        assert!(doc.output.is_ok(), "{:?}", doc.output.err());
+    }
+
+    #[test]
+    fn test_dependencies() {
+        let dir = temp_dir("dependencies");
+        write(&dir.join("part.typ"), "#let x = 1");
+        write(&dir.join("main.typ"), "#import \"part.typ\": x\n#x");
+        let mut world = TypstWorld::new(&dir.join("main.typ"), None).unwrap();
+        assert!(typst::compile::<PagedDocument>(&world).output.is_ok());
+        let dir = dir.canonicalize().unwrap();
+        assert_eq!(
+            world.dependencies(),
+            vec![dir.join("main.typ"), dir.join("part.typ")]
+        );
+
+        // 修改之后重新编译能看到新的内容
+        write(&dir.join("part.typ"), "#let x = 2\n#assert.eq(x, 1)");
+        world.reset();
+        assert!(world.dependencies().is_empty());
+        assert!(typst::compile::<PagedDocument>(&world).output.is_err());
+        assert_eq!(world.dependencies().len(), 2);
+    }
+
+    #[test]
+    fn test_root() {
+        let dir = temp_dir("root");
+        write(&dir.join("shared/logo.typ"), "#let logo = [LOGO]");
+        write(
+            &dir.join("labels/main.typ"),
+            "#import \"/shared/logo.typ\": logo\n#logo",
+        );
+        let world = TypstWorld::new(&dir.join("labels/main.typ"), Some(&dir)).unwrap();
+        assert_eq!(world.root(), dir.canonicalize().unwrap());
+        let doc = typst::compile::<PagedDocument>(&world);
+        assert!(doc.output.is_ok(), "{:?}", doc.output.err());
+
+        assert!(matches!(
+            TypstWorld::new(&dir.join("labels/main.typ"), Some(&dir.join("shared"))),
+            Err(WorldError::OutsideRoot(..))
+        ));
+        assert!(matches!(
+            TypstWorld::new(&dir.join("missing.typ"), None),
+            Err(WorldError::Io(..))
+        ));
+    }
+
+    #[test]
+    fn test_today() {
+        let dir = temp_dir("today");
+        write(
+            &dir.join("main.typ"),
+            "#assert.eq(datetime.today().display(), \"2024-05-01\")\n\
+             #assert.eq(datetime.today(offset: 0).display(), \"2024-04-30\")\n\
+             #assert.eq(datetime.today(offset: 9).display(), \"2024-05-01\")",
+        );
+        let now = parse_datetime("2024-05-01T06:30:00+08:00").unwrap();
+        let world = TypstWorld::new(&dir.join("main.typ"), None)
+            .unwrap()
+            .with_now(now);
+        let doc = typst::compile::<PagedDocument>(&world);
+        assert!(doc.output.is_ok(), "{:?}", doc.output.err());
+
+        // 系统时间
+        let world = TypstWorld::new(&dir.join("main.typ"), None).unwrap();
+        assert!(world.today(None).is_some());
+        assert!(world.today(Some(-12)).is_some());
+        assert!(world.today(Some(i64::MAX)).is_none());
+
+        let local = parse_datetime("2024-05-01 08:30").unwrap();
+        assert_eq!(local.naive_local().to_string(), "2024-05-01 08:30:00");
+        assert_eq!(
+            parse_datetime("2024-05-01")
+                .unwrap()
+                .naive_local()
+                .to_string(),
+            "2024-05-01 00:00:00"
+        );
+        assert!(matches!(
+            parse_datetime("May 1st"),
+            Err(WorldError::InvalidDatetime(..))
+        ));
+    }
+}
@@ -2,8 +2,27 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
+use num_derive::{FromPrimitive, ToPrimitive};
+use thiserror::Error;
+
 use super::Bitmap;
+use crate::{
+    command::{package_with_header, Command, HostCommand},
+    rle,
+};
+
+/// `0x1f 0x2b m n` 中 m 和 n 的最大值
+const SKIP_LINE_MAX_BYTES: u32 = 191;
 
+#[derive(Error, Debug, PartialEq)]
+pub enum PrintCommandError {
+    #[error("line of {0} dots is too wide for a single command")]
+    LineTooWide(u32),
+    #[error("skip of {0} dots is beyond the line width {1}")]
+    SkipBeyondWidth(u32, u32),
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum PrintCommand {
     /// 初始化
     ResetPrinter,
@@ -19,12 +38,83 @@ pub enum PrintCommand {
     NextPaper,
     /// 断点
     Breakpoint,
+    /// 设置标签宽度为 `.0` 个点, 超出的点会被打印机忽略
+    LabelWidth(u32),
+    /// (实验性) 用途还没有确认的命令, `0x1f .0` 后面原样发送 `.1`
+    Experimental(ExperimentalOpcode, Vec<u8>),
+}
+
+/// 用途还没有确认的打印命令, 用于逆向
+#[derive(Debug, Clone, Copy, FromPrimitive, ToPrimitive, PartialEq)]
+pub enum ExperimentalOpcode {
+    Unknown20 = 0x20,
+    Unknown21 = 0x21,
+    Unknown22 = 0x22,
+    /// 进纸后打印一行空白内容, 然后出纸
+    FeedEject24 = 0x24,
+    /// 进纸后打印一行空白内容, 然后出纸
+    FeedEject28 = 0x28,
+    Unknown29 = 0x29,
+    Unknown2c = 0x2c,
+    Unknown2d = 0x2d,
+    Unknown3d = 0x3d,
+}
+
+impl ExperimentalOpcode {
+    pub const ALL: [ExperimentalOpcode; 9] = [
+        Self::Unknown20,
+        Self::Unknown21,
+        Self::Unknown22,
+        Self::FeedEject24,
+        Self::FeedEject28,
+        Self::Unknown29,
+        Self::Unknown2c,
+        Self::Unknown2d,
+        Self::Unknown3d,
+    ];
+
+    /// 命令后面直接跟着数据, 和 `0x1f 0x2a` 一样
+    pub fn raw(self, data: Vec<u8>) -> PrintCommand {
+        PrintCommand::Experimental(self, data)
+    }
+
+    /// 带长度和校验和的数据, 和设置命令一样
+    pub fn framed(self, payload: &[u8]) -> PrintCommand {
+        let buf = package_with_header((0x1f, self as u8), payload, false);
+        PrintCommand::Experimental(self, buf[2..].to_vec())
+    }
+
+    /// 探测用的样例数据
+    ///
+    /// 包括空数据, 带长度和校验和的数据, 类似 `0x1f 0x2a` 的一行, 以及几种可能的 RLE 压缩行
+    pub fn samples(self) -> Vec<PrintCommand> {
+        // 一行 48 字节 (384 点) 的条纹
+        let line: Vec<u8> = (0..48)
+            .map(|i| if i % 8 < 4 { 0xff } else { 0x00 })
+            .collect();
+        let packed = rle::encode(&line);
+        let mut with_len = vec![packed.len() as u8];
+        with_len.extend(&packed);
+        let mut with_dots = (line.len() as u16 * 8).to_le_bytes().to_vec();
+        with_dots.extend(&packed);
+        vec![
+            self.raw(vec![]),
+            self.framed(&[]),
+            self.framed(&[0x01]),
+            self.raw(vec![0x08, 0x00, 0xaa]),
+            self.raw(with_len),
+            self.raw(with_dots),
+        ]
+    }
 }
 
 impl PrintCommand {
-    pub fn parse(&self) -> Option<Vec<Vec<u8>>> {
+    /// 编码为要发送的数据, 断点命令返回 `None`
+    ///
+    /// [PrintCommand::SkipPrintLine] 超出 `0x1f 0x2b` 的范围时会改用 `0x1f 0x2a` 发送
+    pub fn parse(&self) -> Result<Option<Vec<Vec<u8>>>, PrintCommandError> {
         match self {
-            PrintCommand::ResetPrinter => Some(vec![vec![0x1b, 0x40]]),
+            PrintCommand::ResetPrinter => Ok(Some(vec![vec![0x1b, 0x40]])),
             PrintCommand::FeedLines(ln) => {
                 let mut buf = vec![];
                 let max_feed = 255;
@@ -40,59 +130,43 @@ impl PrintCommand {
                     };
                     buf.push(vec![0x1b, 0x4a, x as u8]);
                 }
-                Some(buf)
+                Ok(Some(buf))
             }
             PrintCommand::PrintLine(mw, dots) => {
-                if *mw > 65535 {
-                    return Self::FeedLines(1).parse();
-                }
                 let w = (*mw).min(dots.len() as u32);
-                let bytes_to_print = w.div_ceil(8);
-                let mut b: Vec<u8> = vec![0; bytes_to_print as usize];
-                for (idx, bit) in dots.iter().take(w as usize).enumerate() {
-                    let byte = idx / 8;
-                    let shift = 7 - idx % 8;
-                    b[byte] |= (*bit as u8) << shift;
-                }
+                let w16 = u16::try_from(w).map_err(|_| PrintCommandError::LineTooWide(w))?;
+                let b = pack_dots(&dots[..w as usize]);
                 let mut c = Vec::with_capacity(b.len() + 4);
-                let w16 = w as u16;
                 c.extend(&[0x1f, 0x2a]);
                 c.extend(w16.to_le_bytes());
                 c.extend(b);
-                Some(vec![c])
+                Ok(Some(vec![c]))
             }
             PrintCommand::SkipPrintLine(mw, skip, dots) => {
-                if *mw > 1528 || skip > mw {
-                    return Self::FeedLines(1).parse();
+                if skip > mw {
+                    return Err(PrintCommandError::SkipBeyondWidth(*skip, *mw));
                 }
                 let w = (mw - skip).min(dots.len() as u32);
-                let snaped_skip_bytes = skip / 8;
-                let snaped_skip_offset = skip % 8;
-                let bytes_to_print = 1 + w.div_ceil(8);
-                let mut offset_dots = Vec::with_capacity(8 * bytes_to_print as usize);
-                offset_dots.extend(std::iter::repeat_n(false, snaped_skip_offset as usize));
-                offset_dots.extend(dots);
-
-                // println!("{:?}", dots);
-                // println!("{:?}", offset_dots);
-                // println!("w={}", w);
-
-                let mut b: Vec<u8> = vec![0; bytes_to_print as usize];
-                for (idx, bit) in offset_dots
-                    .into_iter()
-                    .take((snaped_skip_offset + w) as usize)
-                    .enumerate()
-                {
-                    let byte = idx / 8;
-                    let shift = 7 - idx % 8;
-                    b[byte] |= (bit as u8) << shift;
+                // m 放不下的前导空白当作数据发送
+                let skip_bytes = (skip / 8).min(SKIP_LINE_MAX_BYTES);
+                let lead = skip - skip_bytes * 8;
+                let bytes_to_print = lead / 8 + 1 + w.div_ceil(8);
+                if bytes_to_print > SKIP_LINE_MAX_BYTES {
+                    let mut line = vec![false; *skip as usize];
+                    line.extend(&dots[..w as usize]);
+                    return Self::PrintLine(*mw, line).parse();
                 }
 
+                let mut offset_dots = vec![false; lead as usize];
+                offset_dots.extend(&dots[..w as usize]);
+                let mut b = pack_dots(&offset_dots);
+                b.resize(bytes_to_print as usize, 0);
+
                 let mut c = Vec::with_capacity(b.len() + 4);
                 c.extend(&[0x1f, 0x2b]);
-                c.extend([snaped_skip_bytes as u8, bytes_to_print as u8]);
+                c.extend([skip_bytes as u8, bytes_to_print as u8]);
                 c.extend(b);
-                Some(vec![c])
+                Ok(Some(vec![c]))
             }
             PrintCommand::RepeatLine(ln) => {
                 let mut buf = vec![];
@@ -109,12 +183,41 @@ impl PrintCommand {
                     };
                     buf.push(vec![0x1f, 0x2e, x as u8 - 1]);
                 }
-                Some(buf)
+                Ok(Some(buf))
+            }
+            PrintCommand::NextPaper => Ok(Some(vec![vec![0x0c]])),
+            PrintCommand::Breakpoint => Ok(None),
+            PrintCommand::Experimental(op, data) => {
+                let mut c = vec![0x1f, *op as u8];
+                c.extend(data);
+                Ok(Some(vec![c]))
+            }
+            PrintCommand::LabelWidth(w) => {
+                let bytes =
+                    u8::try_from(w.div_ceil(8)).map_err(|_| PrintCommandError::LineTooWide(*w))?;
+                Ok(Some(vec![
+                    Command::new_host(HostCommand::SetLabelWidth).package(vec![bytes], false)
+                ]))
             }
-            PrintCommand::NextPaper => Some(vec![vec![0x0c]]),
-            PrintCommand::Breakpoint => None,
         }
     }
+
+    /// 编码后的字节数, 断点命令不发送任何数据
+    pub fn byte_len(&self) -> Result<usize, PrintCommandError> {
+        Ok(self
+            .parse()?
+            .map(|bufs| bufs.iter().map(Vec::len).sum())
+            .unwrap_or(0))
+    }
+}
+
+/// 把点阵按高位在前打包成字节, 不足一个字节的部分补白
+pub(crate) fn pack_dots(dots: &[bool]) -> Vec<u8> {
+    let mut b = vec![0; dots.len().div_ceil(8)];
+    for (idx, bit) in dots.iter().enumerate() {
+        b[idx / 8] |= (*bit as u8) << (7 - idx % 8);
+    }
+    b
 }
 
 pub struct BitmapParser {
@@ -211,63 +314,384 @@ impl Iterator for BitmapParser {
             return Some(PrintCommand::RepeatLine(repeat_line_counter));
         }
         // 否则这一行和上一行不一样
-        // 看看前缀多少空白
-        // 这一行肯定有黑色的像素
-        let first_black = self
-            .im
-            .first_black_pixel_in_line(self.next_line_cursor)
-            .unwrap()
-            - self.im.line_loc_unchecked(self.next_line_cursor).0;
-        let last_black = self
-            .im
-            .last_black_pixel_in_line(self.next_line_cursor)
-            .unwrap()
-            - self.im.line_loc_unchecked(self.next_line_cursor).0;
-        if first_black > 0 {
-            let skipped: Vec<bool> = self
-                .im
-                .get_line(self.next_line_cursor)
-                .into_iter()
-                .take(last_black + 1)
-                .skip(first_black)
-                .collect();
-            // println!(
-            //     "next_line_cursor={} SkipPrintLine({}, {})",
-            //     self.next_line_cursor,
-            //     first_black,
-            //     skipped.len()
-            // );
-            self.next_line_cursor += 1;
-            return Some(PrintCommand::SkipPrintLine(
-                self.im.width(),
-                first_black as u32,
-                skipped,
-            ));
-        }
-        // 否则第 0 个像素就是黑色的
-        let line: Vec<bool> = self
-            .im
-            .get_line(self.next_line_cursor)
-            .into_iter()
-            .take(last_black + 1)
-            .collect();
-        // println!(
-        //     "next_line_cursor={} PrintLine({})",
-        //     self.next_line_cursor,
-        //     line.len()
-        // );
+        let line = self.im.get_line(self.next_line_cursor);
         self.next_line_cursor += 1;
-        Some(PrintCommand::PrintLine(self.im.width(), line))
+        Some(line_command(self.im.width(), &line))
+    }
+}
+
+/// 为一行有内容的点阵选择打印命令
+///
+/// 前缀有空白时使用 [PrintCommand::SkipPrintLine], 否则使用 [PrintCommand::PrintLine], 末尾的空白都会被去掉
+pub fn line_command(width: u32, line: &[bool]) -> PrintCommand {
+    // 这一行肯定有黑色的像素
+    let first_black = line.iter().position(|x| *x).unwrap();
+    let last_black = line.iter().rposition(|x| *x).unwrap();
+    if first_black > 0 {
+        let skipped = line[first_black..=last_black].to_vec();
+        return PrintCommand::SkipPrintLine(width, first_black as u32, skipped);
+    }
+    // 否则第 0 个像素就是黑色的
+    PrintCommand::PrintLine(width, line[..=last_black].to_vec())
+}
+
+/// 在 `0x1f 0x2a` 和 `0x1f 0x2b` 里为一行有内容的点阵选择编码后字节数较少的一个
+///
+/// 比较去掉末尾空白的 [PrintCommand::PrintLine] 和跳过前导空白的 [PrintCommand::SkipPrintLine],
+/// 字节数相同时使用 [PrintCommand::PrintLine]
+///
+/// 只有这两种候选: 重复行由 [LineEncoder] 处理; RLE 压缩的打印命令还没有确认, 只在
+/// [EncodeStats::rle_bytes] 里估算; 每条打印命令都会走纸一行, 所以一行不能拆成多条命令
+pub fn shortest_line_command(width: u32, line: &[bool]) -> PrintCommand {
+    let first_black = line.iter().position(|x| *x).unwrap();
+    let last_black = line.iter().rposition(|x| *x).unwrap();
+    let plain = PrintCommand::PrintLine(width, line[..=last_black].to_vec());
+    // 前导空白不到一个字节时跳不过任何数据, 肯定更贵
+    if first_black < 8 {
+        return plain;
+    }
+    let skip = PrintCommand::SkipPrintLine(
+        width,
+        first_black as u32,
+        line[first_black..=last_black].to_vec(),
+    );
+    let cost = |c: &PrintCommand| c.byte_len().unwrap_or(usize::MAX);
+    if cost(&skip) < cost(&plain) {
+        skip
+    } else {
+        plain
+    }
+}
+
+/// 选择打印命令的方式
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum LineStrategy {
+    /// 和 [BitmapParser] 相同, 见 [line_command]
+    Simple,
+    /// `0x1f 0x2a` 和 `0x1f 0x2b` 里字节数较少的一个, 见 [shortest_line_command]
+    #[default]
+    Shortest,
+}
+
+/// 编码统计
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct EncodeStats {
+    /// 输入的行数
+    pub lines: u32,
+    /// 生成的命令字节数
+    pub bytes: usize,
+    /// 每行都用完整宽度的 `0x1f 0x2a` 发送时的字节数
+    pub plain_bytes: usize,
+    /// 有内容的行改用 RLE 压缩数据时的估算字节数, 假设命令头和 `0x1f 0x2a` 一样是 4 字节
+    ///
+    /// 打印机上的压缩命令还没有确认 (见 [rle](crate::rle)), 所以只统计不生成
+    pub rle_bytes: usize,
+}
+
+impl EncodeStats {
+    /// 比每行都用完整宽度发送节省的字节数
+    pub fn saved(&self) -> usize {
+        self.plain_bytes.saturating_sub(self.bytes)
+    }
+}
+
+/// 逐行输入的打印命令转换器, 只需要保存上一行
+///
+/// 使用 [LineStrategy::Simple] 时生成的命令和 [BitmapParser] 相同
+pub struct LineEncoder {
+    width: u32,
+    breakpoint: u32,
+    strategy: LineStrategy,
+    line_cursor: u32,
+    prev_line: Option<Vec<bool>>,
+    empty_lines: u32,
+    repeat_lines: u32,
+    stats: EncodeStats,
+}
+
+impl LineEncoder {
+    /// - width: 每行的点数
+    /// - bp: 每隔多少行插入一个断点命令
+    pub fn new(width: u32, bp: u32) -> Self {
+        LineEncoder {
+            width,
+            breakpoint: bp,
+            strategy: LineStrategy::default(),
+            line_cursor: 0,
+            prev_line: None,
+            empty_lines: 0,
+            repeat_lines: 0,
+            stats: EncodeStats::default(),
+        }
+    }
+
+    /// 设置选择打印命令的方式
+    pub fn with_strategy(mut self, strategy: LineStrategy) -> Self {
+        self.strategy = strategy;
+        self
+    }
+
+    /// 到目前为止的统计, 攒起来还没有发送的空行和重复行不计入字节数
+    pub fn stats(&self) -> EncodeStats {
+        self.stats
+    }
+
+    /// 输入一行, 返回可以发送的命令, 空行和重复行会攒起来一起发送
+    pub fn push_line(&mut self, line: Vec<bool>) -> Vec<PrintCommand> {
+        let mut out = vec![];
+        if self.breakpoint > 0 && self.line_cursor.is_multiple_of(self.breakpoint) {
+            self.flush_into(&mut out);
+            out.push(PrintCommand::Breakpoint);
+        }
+        self.line_cursor += 1;
+        self.stats.lines += 1;
+        self.stats.plain_bytes += 4 + (self.width as usize).div_ceil(8);
+        if !line.iter().any(|x| *x) {
+            if self.repeat_lines > 0 {
+                self.flush_into(&mut out);
+            }
+            self.empty_lines += 1;
+        } else if self.prev_line.as_ref() == Some(&line) {
+            if self.empty_lines > 0 {
+                self.flush_into(&mut out);
+            }
+            self.repeat_lines += 1;
+        } else {
+            self.flush_into(&mut out);
+            let c = match self.strategy {
+                LineStrategy::Simple => line_command(self.width, &line),
+                LineStrategy::Shortest => shortest_line_command(self.width, &line),
+            };
+            // 无法编码的命令在发送时才会报错, 这里不计入
+            let len = c.byte_len().unwrap_or(0);
+            let last_black = line.iter().rposition(|x| *x).unwrap();
+            let rle_len = 4 + rle::encoded_len(&pack_dots(&line[..=last_black]));
+            self.stats.bytes += len;
+            self.stats.rle_bytes += len.min(rle_len);
+            out.push(c);
+        }
+        self.prev_line = Some(line);
+        out
+    }
+
+    /// 发送攒起来的空行和重复行
+    pub fn finish(&mut self) -> Vec<PrintCommand> {
+        let mut out = vec![];
+        self.flush_into(&mut out);
+        out
+    }
+
+    fn flush_into(&mut self, out: &mut Vec<PrintCommand>) {
+        let start = out.len();
+        if self.empty_lines > 0 {
+            out.push(PrintCommand::FeedLines(self.empty_lines));
+            self.empty_lines = 0;
+        }
+        if self.repeat_lines > 0 {
+            out.push(PrintCommand::RepeatLine(self.repeat_lines));
+            self.repeat_lines = 0;
+        }
+        let len: usize = out[start..].iter().map(|c| c.byte_len().unwrap_or(0)).sum();
+        self.stats.bytes += len;
+        self.stats.rle_bytes += len;
     }
 }
 
 #[cfg(test)]
 mod test {
-    use super::PrintCommand;
+    use num_traits::FromPrimitive;
+
+    use super::{
+        line_command, shortest_line_command, BitmapParser, EncodeStats, ExperimentalOpcode,
+        LineEncoder, LineStrategy, PrintCommand, PrintCommandError,
+    };
+    use crate::image_proc::{cmd_render::render_commands, Bitmap};
+
+    fn sample(w: u32, h: u32) -> Bitmap {
+        let mut b = Bitmap::new(w, h);
+        for y in 0..h {
+            for x in 0..w {
+                let black = match (y / 5) % 6 {
+                    0 => false,
+                    1 => x % 4 == 0,
+                    2 => x > 20 && (x + y) % 3 == 0,
+                    3 => x == 0 || (x * y) % 7 == 0,
+                    4 => x == w - 1,
+                    _ => y % 2 == 0 && x < 9,
+                };
+                b.set_pixel(x, y, black);
+            }
+        }
+        b
+    }
+
+    fn encode_all(b: &Bitmap, e: &mut LineEncoder) -> Vec<PrintCommand> {
+        let mut out = vec![];
+        for y in 0..b.height() {
+            out.extend(e.push_line(b.get_line(y)));
+        }
+        out.extend(e.finish());
+        out
+    }
+
+    fn to_bytes(cmds: &[PrintCommand]) -> Vec<u8> {
+        cmds.iter()
+            .filter_map(|c| c.parse().unwrap())
+            .flatten()
+            .flatten()
+            .collect()
+    }
+
+    #[test]
+    fn test_line_encoder_matches_parser() {
+        let (w, h) = (67, 300);
+        let b = sample(w, h);
+        for bp in [0, 1, 3, 5, 120] {
+            let expected: Vec<PrintCommand> = BitmapParser::new(b.clone(), bp).collect();
+            let mut e = LineEncoder::new(w, bp).with_strategy(LineStrategy::Simple);
+            let got = encode_all(&b, &mut e);
+            assert_eq!(got, expected, "unexcepted result: bp={bp}");
+        }
+    }
+
+    #[test]
+    fn test_shortest_encoder() {
+        for w in [1, 8, 67, 384, 576] {
+            let b = sample(w, 300);
+            for bp in [0, 5] {
+                let simple = encode_all(
+                    &b,
+                    &mut LineEncoder::new(w, bp).with_strategy(LineStrategy::Simple),
+                );
+                let mut e = LineEncoder::new(w, bp);
+                let shortest = encode_all(&b, &mut e);
+                let data = to_bytes(&shortest);
+                assert!(data.len() <= to_bytes(&simple).len(), "not cheaper: w={w}");
+
+                let pages = render_commands(w, &data).unwrap();
+                assert!(pages[0] == b, "round trip failed: w={w} bp={bp}");
+
+                let stats = e.stats();
+                assert_eq!(stats.lines, 300);
+                assert_eq!(stats.bytes, data.len());
+                assert_eq!(stats.plain_bytes, 300 * (4 + (w as usize).div_ceil(8)));
+                assert_eq!(stats.saved(), stats.plain_bytes - data.len());
+                assert!(stats.rle_bytes <= stats.bytes);
+            }
+        }
+    }
+
+    #[test]
+    fn test_shortest_line_command() {
+        // 前导空白不到一个字节, 用 0x2a
+        let mut line = vec![false; 64];
+        line[3] = true;
+        assert_eq!(
+            shortest_line_command(64, &line),
+            PrintCommand::PrintLine(64, vec![false, false, false, true])
+        );
+        // 前导空白很长, 用 0x2b
+        let mut line = vec![false; 64];
+        line[40] = true;
+        assert_eq!(
+            shortest_line_command(64, &line),
+            PrintCommand::SkipPrintLine(64, 40, vec![true])
+        );
+        // 0x2b 固定多一个字节, 只跳过一个字节时打平, 用 0x2a
+        let mut line = vec![false; 64];
+        line[8] = true;
+        line[63] = true;
+        assert!(matches!(
+            shortest_line_command(64, &line),
+            PrintCommand::PrintLine(..)
+        ));
+        assert_eq!(EncodeStats::default().saved(), 0);
+    }
+
+    /// 测试用的最宽打印头, 超过了 `0x1f 0x2b` 能表示的 191 字节
+    const MAX_HEAD_WIDTH: u32 = 1728;
+
+    #[test]
+    fn test_skip_line_limits() {
+        for w in 1..=MAX_HEAD_WIDTH {
+            let mut skips: Vec<u32> = (0..w).step_by(97).collect();
+            skips.extend([1, 7, 8, 9, 1527, 1528, 1529, 1535, 1536, 1537, w - 1]);
+            for skip in skips.into_iter().filter(|x| *x < w) {
+                let line: Vec<bool> = (0..w)
+                    .map(|x| x == skip || x == w - 1 || (x > skip && x % 5 == 0))
+                    .collect();
+                for c in [line_command(w, &line), shortest_line_command(w, &line)] {
+                    let data = c.parse().unwrap().unwrap().concat();
+                    if data[1] == 0x2b {
+                        assert!(data[2] <= 191 && data[3] <= 191, "out of range: w={w}");
+                    }
+                    let pages = render_commands(w, &data).unwrap();
+                    assert!(
+                        pages[0].get_line(0) == line,
+                        "round trip failed: w={w} skip={skip}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_skip_line_fallback() {
+        // m 放不下的前导空白放到数据里
+        let x = PrintCommand::SkipPrintLine(2000, 1600, vec![true])
+            .parse()
+            .unwrap()
+            .unwrap();
+        assert_eq!(x[0][..4], [0x1f, 0x2b, 191, 11]);
+        assert_eq!(x[0][4..13], [0; 9]);
+        assert_eq!(x[0][13], 0x80);
+        // 数据超过 191 字节, 改用 0x1f 0x2a
+        let x = PrintCommand::SkipPrintLine(1600, 8, vec![true; 1592])
+            .parse()
+            .unwrap()
+            .unwrap();
+        assert_eq!(x[0][..4], [0x1f, 0x2a, 0x40, 0x06]);
+        assert_eq!(x[0][4], 0x00);
+        assert_eq!(x[0].len(), 4 + 200);
+
+        assert_eq!(
+            PrintCommand::SkipPrintLine(10, 11, vec![true]).parse(),
+            Err(PrintCommandError::SkipBeyondWidth(11, 10))
+        );
+        assert_eq!(
+            PrintCommand::PrintLine(70000, vec![true; 70000]).parse(),
+            Err(PrintCommandError::LineTooWide(70000))
+        );
+        // 宽度很大但数据不多时没有问题
+        assert!(PrintCommand::PrintLine(70000, vec![true; 8])
+            .parse()
+            .is_ok());
+    }
+
+    #[test]
+    fn test_experimental() {
+        let x = ExperimentalOpcode::FeedEject24.framed(&[]);
+        assert_eq!(
+            x.parse().unwrap().unwrap(),
+            vec![vec![0x1f, 0x24, 0x00, 0xdb]]
+        );
+        let x = ExperimentalOpcode::Unknown2c.raw(vec![0x01, 0x02]);
+        assert_eq!(
+            x.parse().unwrap().unwrap(),
+            vec![vec![0x1f, 0x2c, 0x01, 0x02]]
+        );
+        for op in ExperimentalOpcode::ALL {
+            assert_eq!(ExperimentalOpcode::from_u8(op as u8), Some(op));
+            for c in op.samples() {
+                let data = c.parse().unwrap().unwrap().concat();
+                assert_eq!(data[..2], [0x1f, op as u8]);
+            }
+        }
+    }
 
     #[test]
     fn test_cmd_parse() {
-        let x = PrintCommand::FeedLines(2233).parse().unwrap();
+        let x = PrintCommand::FeedLines(2233).parse().unwrap().unwrap();
         assert_eq!(
             x,
             vec![
@@ -285,11 +709,12 @@ mod test {
             x
         );
 
-        let x = PrintCommand::NextPaper.parse().unwrap();
+        let x = PrintCommand::NextPaper.parse().unwrap().unwrap();
         assert_eq!(x, vec![vec![0x0c]], "unexcepted result: {:02x?}", x);
 
         let x = PrintCommand::PrintLine(10, vec![false, false, true, true, true])
             .parse()
+            .unwrap()
             .unwrap();
         assert_eq!(
             x,
@@ -298,7 +723,7 @@ mod test {
             x
         );
 
-        let x = PrintCommand::RepeatLine(8964).parse().unwrap();
+        let x = PrintCommand::RepeatLine(8964).parse().unwrap().unwrap();
         assert_eq!(
             x,
             vec![
@@ -354,11 +779,12 @@ mod test {
             x
         );
 
-        let x = PrintCommand::ResetPrinter.parse().unwrap();
+        let x = PrintCommand::ResetPrinter.parse().unwrap().unwrap();
         assert_eq!(x, vec![vec![0x1b, 0x40]], "unexcepted result: {:02x?}", x);
 
         let x = PrintCommand::SkipPrintLine(10, 5, vec![false, true])
             .parse()
+            .unwrap()
             .unwrap();
         assert_eq!(
             x,
@@ -367,7 +793,7 @@ mod test {
             x
         );
 
-        let x = PrintCommand::RepeatLine(0).parse().unwrap();
+        let x = PrintCommand::RepeatLine(0).parse().unwrap().unwrap();
         assert_eq!(x, Vec::<Vec<u8>>::new(), "unexcepted result: {:02x?}", x);
 
         // println!("{:02x?}", x);
@@ -0,0 +1,297 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use thiserror::Error;
+
+use super::Bitmap;
+use crate::command::variable_bytes::ToVariableBytes;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum RenderError {
+    #[error("unknown command `{1:#04x}` at offset {0}")]
+    UnknownCommand(usize, u8),
+    #[error("truncated command at offset {0}")]
+    Truncated(usize),
+}
+
+/// 打印命令渲染器, 把打印命令还原成每一页的位图, 相当于 [BitmapParser](super::cmd_parser::BitmapParser) 的逆过程
+///
+/// 支持的命令:
+/// - `0x1b 0x40` 初始化
+/// - `0x1b 0x4a n` 走空行
+/// - `0x1f 0x2a` 打印一行
+/// - `0x1f 0x2b` 打印有前导空白的一行
+/// - `0x1f 0x2e` 重复行
+/// - `0x0c` 定位到下一张纸
+///
+/// 其他 `0x1f` 开头的命令按照带长度和校验和的格式跳过
+pub struct CommandRenderer {
+    width: u32,
+    buf: Vec<u8>,
+    offset: usize,
+    rows: Vec<bool>,
+    last_line: Vec<bool>,
+    pages: Vec<Bitmap>,
+}
+
+impl CommandRenderer {
+    /// - width: 打印头宽度 (点数), 超出的内容会被丢弃
+    pub fn new(width: u32) -> Self {
+        CommandRenderer {
+            width,
+            buf: Vec::new(),
+            offset: 0,
+            rows: Vec::new(),
+            last_line: vec![false; width as usize],
+            pages: Vec::new(),
+        }
+    }
+
+    /// 输入一段命令流, 不完整的命令会留到下次继续解析
+    pub fn feed(&mut self, data: &[u8]) -> Result<(), RenderError> {
+        self.buf.extend_from_slice(data);
+        let mut cursor = 0;
+        while cursor < self.buf.len() {
+            match self.step(cursor)? {
+                Some(len) => cursor += len,
+                None => break,
+            }
+        }
+        self.buf.drain(..cursor);
+        self.offset += cursor;
+        Ok(())
+    }
+
+    /// 结束渲染, 返回所有页面, 最后一页没有 `0x0c` 也会被返回
+    pub fn finish(mut self) -> Result<Vec<Bitmap>, RenderError> {
+        if !self.buf.is_empty() {
+            return Err(RenderError::Truncated(self.offset));
+        }
+        if !self.rows.is_empty() {
+            self.next_page();
+        }
+        Ok(self.pages)
+    }
+
+    /// 当前页已经渲染的行数
+    pub fn current_height(&self) -> u32 {
+        (self.rows.len() / self.width.max(1) as usize) as u32
+    }
+
+    /// 解析一条命令, 返回消耗的字节数, 数据不足时返回 `None`
+    fn step(&mut self, cursor: usize) -> Result<Option<usize>, RenderError> {
+        let offset = self.offset + cursor;
+        let b = &self.buf[cursor..];
+        match b[0] {
+            0x0c => {
+                self.next_page();
+                Ok(Some(1))
+            }
+            0x1b => {
+                let Some(op) = b.get(1) else {
+                    return Ok(None);
+                };
+                match op {
+                    0x40 => {
+                        self.last_line.fill(false);
+                        Ok(Some(2))
+                    }
+                    0x4a => {
+                        let Some(n) = b.get(2) else {
+                            return Ok(None);
+                        };
+                        self.last_line.fill(false);
+                        for _ in 0..*n {
+                            self.rows
+                                .extend(std::iter::repeat_n(false, self.width as usize));
+                        }
+                        Ok(Some(3))
+                    }
+                    x => Err(RenderError::UnknownCommand(offset + 1, *x)),
+                }
+            }
+            0x1f => {
+                let Some(op) = b.get(1) else {
+                    return Ok(None);
+                };
+                match op {
+                    0x2a => {
+                        if b.len() < 4 {
+                            return Ok(None);
+                        }
+                        let dots = u16::from_le_bytes([b[2], b[3]]) as usize;
+                        let len = 4 + dots.div_ceil(8);
+                        if b.len() < len {
+                            return Ok(None);
+                        }
+                        let line = unpack_line(&b[4..len], 0, dots);
+                        self.push_line(line);
+                        Ok(Some(len))
+                    }
+                    0x2b => {
+                        if b.len() < 4 {
+                            return Ok(None);
+                        }
+                        let skip = b[2] as usize * 8;
+                        let len = 4 + b[3] as usize;
+                        if b.len() < len {
+                            return Ok(None);
+                        }
+                        let line = unpack_line(&b[4..len], skip, (len - 4) * 8);
+                        self.push_line(line);
+                        Ok(Some(len))
+                    }
+                    0x2e => {
+                        let Some(n) = b.get(2) else {
+                            return Ok(None);
+                        };
+                        for _ in 0..=*n {
+                            self.rows.extend_from_slice(&self.last_line);
+                        }
+                        Ok(Some(3))
+                    }
+                    _ => {
+                        // 其他命令: 命令组 + 命令类型 + 数据长度 + 数据... + 校验和
+                        if b.len() < 3 {
+                            return Ok(None);
+                        }
+                        let len_bytes = b[2..b.len().min(4)].to_vec();
+                        let Some((payload_len, len_offset)) = len_bytes.to_variable_bytes() else {
+                            return Ok(None);
+                        };
+                        let len = 2 + len_offset + payload_len as usize + 1;
+                        if b.len() < len {
+                            return Ok(None);
+                        }
+                        Ok(Some(len))
+                    }
+                }
+            }
+            x => Err(RenderError::UnknownCommand(offset, x)),
+        }
+    }
+
+    fn push_line(&mut self, line: Vec<bool>) {
+        let w = self.width as usize;
+        self.last_line.fill(false);
+        let n = line.len().min(w);
+        self.last_line[..n].copy_from_slice(&line[..n]);
+        self.rows.extend_from_slice(&self.last_line);
+    }
+
+    fn next_page(&mut self) {
+        let h = self.current_height();
+        let rows = std::mem::take(&mut self.rows);
+        self.pages
+            .push(Bitmap::from_raw(self.width, h, rows).unwrap());
+        self.last_line.fill(false);
+    }
+}
+
+/// 解开一行点阵数据, 前面补 `skip` 个空白点
+fn unpack_line(data: &[u8], skip: usize, dots: usize) -> Vec<bool> {
+    let mut line = vec![false; skip + dots];
+    for idx in 0..dots {
+        line[skip + idx] = data[idx / 8] & (1 << (7 - idx % 8)) != 0;
+    }
+    line
+}
+
+/// 把一段完整的命令流渲染成位图
+pub fn render_commands(width: u32, data: &[u8]) -> Result<Vec<Bitmap>, RenderError> {
+    let mut r = CommandRenderer::new(width);
+    r.feed(data)?;
+    r.finish()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{render_commands, CommandRenderer, RenderError};
+    use crate::image_proc::{
+        cmd_parser::{BitmapParser, PrintCommand},
+        Bitmap,
+    };
+
+    fn encode(b: &Bitmap, bp: u32) -> Vec<u8> {
+        let mut buf = PrintCommand::ResetPrinter
+            .parse()
+            .unwrap()
+            .unwrap()
+            .concat();
+        for c in BitmapParser::new(b.clone(), bp) {
+            if let Some(c) = c.parse().unwrap() {
+                buf.extend(c.concat());
+            }
+        }
+        buf.extend(PrintCommand::NextPaper.parse().unwrap().unwrap().concat());
+        buf
+    }
+
+    fn pattern(w: u32, h: u32) -> Bitmap {
+        let mut b = Bitmap::new(w, h);
+        for y in 0..h {
+            for x in 0..w {
+                let black = match (y / 7) % 5 {
+                    // 空行
+                    0 => false,
+                    // 重复行
+                    1 => x % 3 == 0,
+                    // 前导空白
+                    2 => x > w / 3 && (x * 7 + y) % 5 == 0,
+                    // 第 0 个点是黑色
+                    3 => x == 0 || (x + y) % 4 == 0,
+                    // 只有最后一个点是黑色
+                    _ => x == w - 1,
+                };
+                b.set_pixel(x, y, black);
+            }
+        }
+        b
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for w in [1, 7, 8, 9, 64, 100, 383, 384, 576] {
+            for bp in [0, 10, 120] {
+                let b = pattern(w, 200);
+                let pages = render_commands(w, &encode(&b, bp)).unwrap();
+                assert_eq!(pages.len(), 1);
+                assert!(pages[0] == b, "round trip failed: w={w} bp={bp}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_pages_and_streaming() {
+        let a = pattern(48, 30);
+        let b = pattern(48, 50);
+        let mut data = encode(&a, 0);
+        // 中间夹一个设置命令
+        data.extend([0x1f, 0x43, 0x01, 0x05, 0xb6]);
+        data.extend(encode(&b, 0));
+        let mut r = CommandRenderer::new(48);
+        for chunk in data.chunks(3) {
+            r.feed(chunk).unwrap();
+        }
+        let pages = r.finish().unwrap();
+        assert_eq!(pages.len(), 2);
+        assert!(pages[0] == a);
+        assert!(pages[1] == b);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(
+            render_commands(8, &[0x1b, 0x40, 0x55]),
+            Err(RenderError::UnknownCommand(2, 0x55))
+        );
+        assert_eq!(
+            render_commands(8, &[0x1b, 0x4a, 0x01, 0x1f, 0x2a, 0x08]),
+            Err(RenderError::Truncated(3))
+        );
+        // 超出宽度的点被丢弃
+        let pages = render_commands(4, &[0x1f, 0x2a, 0x08, 0x00, 0xff, 0x1f, 0x2e, 0x01]).unwrap();
+        assert_eq!(pages[0].pixels(), &[true; 12]);
+    }
+}
@@ -0,0 +1,268 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{
+    sync::atomic::{AtomicU8, AtomicUsize, Ordering},
+    thread,
+};
+
+use rayon::iter::{IntoParallelRefMutIterator, ParallelIterator};
+
+use super::DitherMode;
+
+/// 并行误差扩散每次同步的列数
+const BLOCK_WIDTH: usize = 32;
+
+/// 亮度截断
+pub fn threshold(gray: &mut [u8]) {
+    gray.par_iter_mut().for_each(|px| {
+        *px = if *px > 127 { 255 } else { 0 };
+    });
+}
+
+/// 单线程 Floyd-Steinberg 误差扩散
+pub fn floyd_steinberg(gray: &mut [u8], w: usize) {
+    if w == 0 {
+        return;
+    }
+    let h = gray.len() / w;
+    for y in 0..h {
+        let (cur, rest) = gray[y * w..].split_at_mut(w);
+        let below = if y + 1 < h {
+            Some(&mut rest[..w])
+        } else {
+            None
+        };
+        diffuse_row(cur, below);
+    }
+}
+
+/// 多线程 Floyd-Steinberg 误差扩散, 结果和 [floyd_steinberg] 完全相同
+///
+/// 按行分配给各个线程, 第 `y` 行处理到第 `x` 个点之前, 等待第 `y - 1` 行处理完第 `x + 2` 个点,
+/// 所以每个点收到误差的顺序和单线程一致
+pub fn floyd_steinberg_parallel(gray: &mut [u8], w: usize, threads: usize) {
+    if w == 0 {
+        return;
+    }
+    let h = gray.len() / w;
+    let threads = threads.min(h);
+    if threads <= 1 {
+        floyd_steinberg(gray, w);
+        return;
+    }
+    let cells: Vec<AtomicU8> = gray.iter().map(|x| AtomicU8::new(*x)).collect();
+    let done: Vec<AtomicUsize> = (0..h).map(|_| AtomicUsize::new(0)).collect();
+    thread::scope(|s| {
+        for t in 0..threads {
+            let cells = &cells;
+            let done = &done;
+            s.spawn(move || {
+                for y in (t..h).step_by(threads) {
+                    let mut avail = if y == 0 { w } else { 0 };
+                    let mut x = 0;
+                    while x < w {
+                        let end = (x + BLOCK_WIDTH).min(w);
+                        let need = (end + 2).min(w);
+                        let mut spins = 0;
+                        while avail < need {
+                            avail = done[y - 1].load(Ordering::Acquire);
+                            spins += 1;
+                            if spins > 64 {
+                                thread::yield_now();
+                            } else {
+                                std::hint::spin_loop();
+                            }
+                        }
+                        for x in x..end {
+                            diffuse_pixel(cells, w, h, x, y);
+                        }
+                        done[y].store(end, Ordering::Release);
+                        x = end;
+                    }
+                }
+            });
+        }
+    });
+    for (px, cell) in gray.iter_mut().zip(cells) {
+        *px = cell.into_inner();
+    }
+}
+
+fn diffuse_pixel(cells: &[AtomicU8], w: usize, h: usize, x: usize, y: usize) {
+    let add = |i: usize, v: i16| {
+        let old = cells[i].load(Ordering::Relaxed);
+        cells[i].store((old as i16 + v).clamp(0, 255) as u8, Ordering::Relaxed);
+    };
+    let idx = y * w + x;
+    let old_pixel = cells[idx].load(Ordering::Relaxed);
+    let new_pixel = if old_pixel > 127 { 255 } else { 0 };
+    cells[idx].store(new_pixel, Ordering::Relaxed);
+
+    let err = old_pixel as i16 - new_pixel as i16;
+    if err == 0 {
+        return;
+    }
+    if x + 1 < w {
+        add(idx + 1, err * 7 / 16);
+    }
+    if y + 1 < h {
+        if x > 0 {
+            add(idx + w - 1, err * 3 / 16);
+        }
+        add(idx + w, err * 5 / 16);
+        if x + 1 < w {
+            add(idx + w + 1, err / 16);
+        }
+    }
+}
+
+/// 处理一行, 并把误差扩散到下一行
+fn diffuse_row(cur: &mut [u8], mut below: Option<&mut [u8]>) {
+    let w = cur.len();
+    let add = |px: &mut u8, v: i16| *px = (*px as i16 + v).clamp(0, 255) as u8;
+    for x in 0..w {
+        let old_pixel = cur[x];
+        let new_pixel = if old_pixel > 127 { 255 } else { 0 };
+        cur[x] = new_pixel;
+
+        let err = old_pixel as i16 - new_pixel as i16;
+        if err == 0 {
+            continue;
+        }
+        if x + 1 < w {
+            add(&mut cur[x + 1], err * 7 / 16);
+        }
+        if let Some(below) = below.as_deref_mut() {
+            if x > 0 {
+                add(&mut below[x - 1], err * 3 / 16);
+            }
+            add(&mut below[x], err * 5 / 16);
+            if x + 1 < w {
+                add(&mut below[x + 1], err / 16);
+            }
+        }
+    }
+}
+
+/// 逐行处理的抖动器, 不需要把整张图片读进内存
+///
+/// Floyd-Steinberg 模式下会缓存一行, 所以输出比输入晚一行, 结束时调用 [RowDitherer::finish] 取出最后一行
+pub struct RowDitherer {
+    w: usize,
+    mode: DitherMode,
+    pending: Option<Vec<u8>>,
+}
+
+impl RowDitherer {
+    pub fn new(w: u32, mode: DitherMode) -> Self {
+        RowDitherer {
+            w: w as usize,
+            mode,
+            pending: None,
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.w as u32
+    }
+
+    /// 输入一行灰度值, 返回已经处理完的一行, 黑色为 `true`
+    ///
+    /// 长度不足宽度的部分视为白色, 超出的部分会被丢弃
+    pub fn push_row(&mut self, row: &[u8]) -> Option<Vec<bool>> {
+        let mut row = row.to_vec();
+        row.resize(self.w, 255);
+        match self.mode {
+            DitherMode::Threshold => {
+                threshold(&mut row);
+                Some(to_bits(&row))
+            }
+            DitherMode::FloydSteinberg => {
+                let out = self.pending.take().map(|mut cur| {
+                    diffuse_row(&mut cur, Some(&mut row));
+                    to_bits(&cur)
+                });
+                self.pending = Some(row);
+                out
+            }
+        }
+    }
+
+    /// 取出缓存的最后一行
+    pub fn finish(&mut self) -> Option<Vec<bool>> {
+        self.pending.take().map(|mut cur| {
+            diffuse_row(&mut cur, None);
+            to_bits(&cur)
+        })
+    }
+}
+
+fn to_bits(row: &[u8]) -> Vec<bool> {
+    row.iter().map(|px| *px < 128).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{floyd_steinberg, floyd_steinberg_parallel, RowDitherer};
+    use crate::image_proc::DitherMode;
+
+    fn gradient(w: usize, h: usize) -> Vec<u8> {
+        let mut x: u32 = 0x1989_0604;
+        (0..w * h)
+            .map(|i| {
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                let base = (i % w * 255 / w.max(1)) as u32;
+                (base + x % 64).min(255) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_parallel_matches_serial() {
+        for (w, h) in [(1, 50), (2, 9), (33, 17), (100, 64), (576, 300)] {
+            let gray = gradient(w, h);
+            let mut serial = gray.clone();
+            floyd_steinberg(&mut serial, w);
+            for threads in [1, 2, 3, 8] {
+                let mut parallel = gray.clone();
+                floyd_steinberg_parallel(&mut parallel, w, threads);
+                assert!(
+                    serial == parallel,
+                    "parallel result differs: w={w} h={h} threads={threads}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_streaming_matches_serial() {
+        let (w, h) = (77, 40);
+        let gray = gradient(w, h);
+        let mut serial = gray.clone();
+        floyd_steinberg(&mut serial, w);
+        let expected: Vec<bool> = serial.iter().map(|x| *x < 128).collect();
+
+        let mut d = RowDitherer::new(w as u32, DitherMode::FloydSteinberg);
+        let mut out = Vec::new();
+        for row in gray.chunks(w) {
+            if let Some(x) = d.push_row(row) {
+                out.extend(x);
+            }
+        }
+        assert_eq!(out.len(), w * (h - 1));
+        out.extend(d.finish().unwrap());
+        assert!(out == expected);
+        assert_eq!(d.finish(), None);
+
+        let mut d = RowDitherer::new(4, DitherMode::Threshold);
+        assert_eq!(
+            d.push_row(&[0, 200, 100]),
+            Some(vec![true, false, true, false])
+        );
+        assert_eq!(d.finish(), None);
+    }
+}
@@ -0,0 +1,317 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::io::Cursor;
+
+use image::{GrayImage, ImageFormat, Luma};
+
+use super::{Bitmap, BitmapError};
+
+impl Bitmap {
+    /// 每行按字节对齐后的字节数
+    pub fn packed_line_bytes(&self) -> usize {
+        (self.w as usize).div_ceil(8)
+    }
+
+    /// 导出为每像素 1 bit 的数据, 每行按字节对齐, 靠左的点在较高位, 1 为黑色
+    ///
+    /// 和打印命令里的点阵数据排列方式相同
+    pub fn to_packed(&self) -> Vec<u8> {
+        let line_bytes = self.packed_line_bytes();
+        let mut buf = vec![0u8; line_bytes * self.h as usize];
+        for y in 0..self.h {
+            let (start, end) = self.line_loc_unchecked(y);
+            let line = &mut buf[y as usize * line_bytes..(y as usize + 1) * line_bytes];
+            for (idx, bit) in self.pix[start..end].iter().enumerate() {
+                line[idx / 8] |= (*bit as u8) << (7 - idx % 8);
+            }
+        }
+        buf
+    }
+
+    /// 从每像素 1 bit 的数据导入, 高度由数据长度决定
+    ///
+    /// - w: 宽度, 每行占用 `(w + 7) / 8` 个字节
+    /// - data: 见 [Bitmap::to_packed]
+    pub fn from_packed(w: u32, data: &[u8]) -> Result<Bitmap, BitmapError> {
+        let line_bytes = (w as usize).div_ceil(8);
+        if line_bytes == 0 || !data.len().is_multiple_of(line_bytes) {
+            return Err(BitmapError::PackedLength(data.len(), w));
+        }
+        let h = data.len() / line_bytes;
+        let mut pix = Vec::with_capacity(w as usize * h);
+        for line in data.chunks_exact(line_bytes) {
+            pix.extend((0..w as usize).map(|idx| line[idx / 8] & (1 << (7 - idx % 8)) != 0));
+        }
+        Bitmap::from_raw(w, h as u32, pix)
+    }
+
+    /// 导出为二进制 PBM (`P4`)
+    pub fn to_pbm(&self) -> Vec<u8> {
+        let mut buf = format!("P4\n{} {}\n", self.w, self.h).into_bytes();
+        buf.extend(self.to_packed());
+        buf
+    }
+
+    /// 导入 PBM, 支持 `P1` (文本) 和 `P4` (二进制)
+    pub fn from_pbm(data: &[u8]) -> Result<Bitmap, BitmapError> {
+        let mut cursor = 0;
+        let magic = next_pbm_token(data, &mut cursor)?;
+        let w = parse_pbm_number(next_pbm_token(data, &mut cursor)?)?;
+        let h = parse_pbm_number(next_pbm_token(data, &mut cursor)?)?;
+        match magic {
+            b"P4" => {
+                // 头部和数据之间只有一个空白字符
+                let body = data.get(cursor + 1..).unwrap_or_default();
+                let len = (w as usize).div_ceil(8) * h as usize;
+                if body.len() < len {
+                    return Err(BitmapError::InvalidPbm("truncated data".to_string()));
+                }
+                if w == 0 || h == 0 {
+                    return Ok(Bitmap::new(w, h));
+                }
+                Bitmap::from_packed(w, &body[..len])
+            }
+            b"P1" => {
+                // 每个像素至少一个字节, 先检查长度再分配
+                let len = (w as usize)
+                    .checked_mul(h as usize)
+                    .filter(|&len| len <= data.len() - cursor)
+                    .ok_or_else(|| BitmapError::InvalidPbm("truncated data".to_string()))?;
+                let mut pix = Vec::with_capacity(len);
+                while pix.len() < len {
+                    cursor = skip_pbm_space(data, cursor);
+                    match data.get(cursor) {
+                        Some(b'0') => pix.push(false),
+                        Some(b'1') => pix.push(true),
+                        Some(x) => {
+                            return Err(BitmapError::InvalidPbm(format!(
+                                "unexpected byte `{x:#04x}`"
+                            )))
+                        }
+                        None => return Err(BitmapError::InvalidPbm("truncated data".to_string())),
+                    }
+                    cursor += 1;
+                }
+                Bitmap::from_raw(w, h, pix)
+            }
+            _ => Err(BitmapError::InvalidPbm(
+                "unsupported magic number".to_string(),
+            )),
+        }
+    }
+
+    /// 黑色为 0, 白色为 255
+    pub fn to_gray_image(&self) -> GrayImage {
+        GrayImage::from_fn(self.w, self.h, |x, y| {
+            Luma([if self.get_pixel(x, y) { 0 } else { 255 }])
+        })
+    }
+
+    /// 导出为 PNG
+    pub fn to_png(&self) -> Result<Vec<u8>, BitmapError> {
+        let mut buf = Cursor::new(Vec::new());
+        self.to_gray_image().write_to(&mut buf, ImageFormat::Png)?;
+        Ok(buf.into_inner())
+    }
+
+    /// 导出为 sixel, 支持的终端里可以按原始分辨率预览
+    ///
+    /// 每 6 行一组, 先用白色画空白的点, 回到行首再用黑色画黑色的点
+    pub fn to_sixel(&self) -> String {
+        let mut out = format!(
+            "\x1bP0;1;0q\"1;1;{};{}#0;2;100;100;100#1;2;0;0;0",
+            self.w, self.h
+        );
+        for band in (0..self.h).step_by(6) {
+            for (color, black) in [(0, false), (1, true)] {
+                let sixels = (0..self.w).map(|x| {
+                    let bits = (0..6)
+                        .filter(|dy| band + dy < self.h && self.get_pixel(x, band + dy) == black)
+                        .fold(0u8, |acc, dy| acc | 1 << dy);
+                    (63 + bits) as char
+                });
+                out.push_str(&format!("#{color}"));
+                push_sixel_runs(&mut out, sixels);
+                out.push('$');
+            }
+            out.push('-');
+        }
+        out.push_str("\x1b\\");
+        out
+    }
+
+    /// 用半格字符 (`▀`, `▄`, `█`) 预览, 每个字符是 `scale` 个点宽, `2 * scale` 个点高
+    ///
+    /// `scale` 大于 1 时, 只要对应区域里有黑色的点就显示为黑色
+    pub fn to_half_blocks(&self, scale: u32) -> String {
+        let scale = scale.max(1);
+        let black = |cx: u32, cy: u32| {
+            (cy * scale..((cy + 1) * scale).min(self.h))
+                .any(|y| (cx * scale..((cx + 1) * scale).min(self.w)).any(|x| self.get_pixel(x, y)))
+        };
+        let cols = self.w.div_ceil(scale);
+        let rows = self.h.div_ceil(scale);
+        let mut out = String::new();
+        for cy in (0..rows).step_by(2) {
+            for cx in 0..cols {
+                out.push(match (black(cx, cy), cy + 1 < rows && black(cx, cy + 1)) {
+                    (true, true) => '█',
+                    (true, false) => '▀',
+                    (false, true) => '▄',
+                    (false, false) => ' ',
+                });
+            }
+            out.push('\n');
+        }
+        out
+    }
+}
+
+/// 连续相同的 sixel 字符用 `!n` 压缩
+fn push_sixel_runs(out: &mut String, sixels: impl Iterator<Item = char>) {
+    let mut run: Option<(char, usize)> = None;
+    let flush = |out: &mut String, (c, n): (char, usize)| {
+        if n > 3 {
+            out.push_str(&format!("!{n}{c}"));
+        } else {
+            out.extend(std::iter::repeat_n(c, n));
+        }
+    };
+    for c in sixels {
+        run = match run {
+            Some((x, n)) if x == c => Some((x, n + 1)),
+            Some(r) => {
+                flush(out, r);
+                Some((c, 1))
+            }
+            None => Some((c, 1)),
+        };
+    }
+    if let Some(r) = run {
+        flush(out, r);
+    }
+}
+
+/// 跳过空白和 `#` 注释
+fn skip_pbm_space(data: &[u8], mut cursor: usize) -> usize {
+    while let Some(x) = data.get(cursor) {
+        if *x == b'#' {
+            while data.get(cursor).is_some_and(|x| *x != b'\n') {
+                cursor += 1;
+            }
+        } else if x.is_ascii_whitespace() {
+            cursor += 1;
+        } else {
+            break;
+        }
+    }
+    cursor
+}
+
+fn next_pbm_token<'a>(data: &'a [u8], cursor: &mut usize) -> Result<&'a [u8], BitmapError> {
+    let start = skip_pbm_space(data, *cursor);
+    let mut end = start;
+    while data.get(end).is_some_and(|x| !x.is_ascii_whitespace()) {
+        end += 1;
+    }
+    if start == end {
+        return Err(BitmapError::InvalidPbm("truncated header".to_string()));
+    }
+    *cursor = end;
+    Ok(&data[start..end])
+}
+
+fn parse_pbm_number(token: &[u8]) -> Result<u32, BitmapError> {
+    std::str::from_utf8(token)
+        .ok()
+        .and_then(|x| x.parse().ok())
+        .ok_or_else(|| BitmapError::InvalidPbm("invalid size".to_string()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Bitmap, BitmapError};
+
+    fn sample() -> Bitmap {
+        let pix = (0..13 * 3).map(|i| i % 3 == 0 || i % 7 == 0).collect();
+        Bitmap::from_raw(13, 3, pix).unwrap()
+    }
+
+    #[test]
+    fn test_packed() {
+        let b = Bitmap::from_raw(
+            10,
+            1,
+            vec![
+                true, false, true, true, false, false, false, false, false, true,
+            ],
+        )
+        .unwrap();
+        let x = b.to_packed();
+        assert_eq!(x, vec![0xb0, 0x40], "unexcepted result: {:02x?}", x);
+        assert_eq!(Bitmap::from_packed(10, &x).unwrap(), b);
+
+        let b = sample();
+        assert_eq!(Bitmap::from_packed(13, &b.to_packed()).unwrap(), b);
+        assert!(matches!(
+            Bitmap::from_packed(13, &[0; 5]),
+            Err(BitmapError::PackedLength(5, 13))
+        ));
+    }
+
+    #[test]
+    fn test_pbm() {
+        let b = sample();
+        let x = b.to_pbm();
+        assert!(x.starts_with(b"P4\n13 3\n"));
+        assert_eq!(Bitmap::from_pbm(&x).unwrap(), b);
+
+        let x = b"P1\n# comment\n3 2\n1 0 1\n010\n";
+        let b = Bitmap::from_pbm(x).unwrap();
+        assert_eq!(b.pixels(), &[true, false, true, false, true, false]);
+
+        assert!(matches!(
+            Bitmap::from_pbm(b"P4 8 2\n\x00"),
+            Err(BitmapError::InvalidPbm(_))
+        ));
+        // 头部的尺寸很大但数据很短, 不能先按尺寸分配
+        for x in [&b"P1 4294967295 4294967295 "[..], b"P1 100000 100000\n0 1"] {
+            assert!(matches!(
+                Bitmap::from_pbm(x),
+                Err(BitmapError::InvalidPbm(_))
+            ));
+        }
+        assert!(matches!(
+            Bitmap::from_pbm(b"P2 1 1 1"),
+            Err(BitmapError::InvalidPbm(_))
+        ));
+    }
+
+    #[test]
+    fn test_terminal() {
+        // 2x3, 第一列全黑
+        let b = Bitmap::from_raw(2, 3, vec![true, false, true, false, true, false]).unwrap();
+        assert_eq!(b.to_half_blocks(1), "█ \n▀ \n");
+        assert_eq!(b.to_half_blocks(2), "█\n");
+        // 白色: 第二列 0b111 -> 'F'; 黑色: 第一列 0b111 -> 'F'
+        assert_eq!(
+            b.to_sixel(),
+            "\x1bP0;1;0q\"1;1;2;3#0;2;100;100;100#1;2;0;0;0#0?F$#1F?$-\x1b\\"
+        );
+        let wide = Bitmap::new(10, 1);
+        assert!(wide.to_sixel().contains("#0!10@$#1!10?$"));
+    }
+
+    #[test]
+    fn test_png() {
+        let b = sample();
+        let png = b.to_png().unwrap();
+        let im = image::load_from_memory(&png).unwrap().into_luma8();
+        assert_eq!(im, b.to_gray_image());
+        for (x, y, p) in im.enumerate_pixels() {
+            assert_eq!(p[0] == 0, b.get_pixel(x, y));
+        }
+    }
+}
@@ -0,0 +1,159 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use super::{cmd_parser::PrintCommand, Bitmap};
+
+const MM_PER_INCH: f32 = 25.4;
+
+/// 打印纸间隔的最小值, 单位 0.01 mm
+pub const MIN_GAP: u16 = 50;
+
+/// 标签尺寸, 单位都是点
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LabelGeometry {
+    /// 标签宽度
+    pub width: u32,
+    /// 标签高度, 0 表示连续纸
+    pub height: u32,
+    /// 标签之间的间隙
+    pub gap: u32,
+    /// 标签左边缘到打印头左边缘的距离
+    pub offset: u32,
+}
+
+impl LabelGeometry {
+    pub fn new(width: u32, height: u32, gap: u32) -> Self {
+        LabelGeometry {
+            width,
+            height,
+            gap,
+            offset: 0,
+        }
+    }
+
+    /// 按毫米和打印机的分辨率 (dpi) 创建
+    pub fn from_mm(width: f32, height: f32, gap: f32, dpi: u16) -> Self {
+        let dots = |mm: f32| (mm / MM_PER_INCH * dpi as f32).round().max(0.0) as u32;
+        Self::new(dots(width), dots(height), dots(gap))
+    }
+
+    pub fn with_offset(mut self, offset: u32) -> Self {
+        self.offset = offset;
+        self
+    }
+
+    /// 把标签放在宽度为 `head_width` 的打印头中间, 比打印头宽的标签按 [Self::within] 裁掉
+    pub fn centred(self, head_width: u32) -> Self {
+        self.with_offset(head_width.saturating_sub(self.width) / 2)
+            .within(head_width)
+    }
+
+    /// 限制在宽度为 `head_width` 的打印头里, 超出打印头右边缘的部分不打印
+    pub fn within(mut self, head_width: u32) -> Self {
+        self.offset = self.offset.min(head_width);
+        self.width = self.width.min(head_width - self.offset);
+        self
+    }
+
+    /// 每行要发送的点数, 包括左边的偏移
+    pub fn line_width(&self) -> u32 {
+        self.offset + self.width
+    }
+
+    /// 间隙, 单位 0.01 mm, 用于设置打印纸间隔
+    pub fn gap_setting(&self, dpi: u16) -> u16 {
+        let gap = (self.gap as f32 / dpi.max(1) as f32 * MM_PER_INCH * 100.0).round();
+        gap.min(u16::MAX as f32) as u16
+    }
+
+    /// 开始打印前要发送的命令
+    ///
+    /// 打印机会忽略超出标签宽度的点, 所以设置的宽度包括左边的偏移
+    pub fn commands(&self) -> Vec<PrintCommand> {
+        vec![PrintCommand::LabelWidth(self.line_width())]
+    }
+
+    /// 把一行内容水平居中放到标签里, 超出标签宽度的部分被裁掉
+    pub fn place_line(&self, line: &[bool]) -> Vec<bool> {
+        let w = self.width as usize;
+        let mut out = vec![false; self.line_width() as usize];
+        let label = &mut out[self.offset as usize..];
+        if line.len() > w {
+            let start = (line.len() - w) / 2;
+            label.copy_from_slice(&line[start..start + w]);
+        } else {
+            let start = (w - line.len()) / 2;
+            label[start..start + line.len()].copy_from_slice(line);
+        }
+        out
+    }
+
+    /// 是否已经超出标签高度
+    pub fn is_full(&self, lines: u32) -> bool {
+        self.height > 0 && lines >= self.height
+    }
+
+    /// 把位图放到标签里, 水平居中, 超出标签高度的行被裁掉
+    pub fn fit(&self, bm: &Bitmap) -> Bitmap {
+        let mut pix = Vec::new();
+        let mut h = 0;
+        for y in 0..bm.height() {
+            if self.is_full(h) {
+                break;
+            }
+            pix.extend(self.place_line(&bm.get_line(y)));
+            h += 1;
+        }
+        Bitmap::from_raw(self.line_width(), h, pix).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::LabelGeometry;
+    use crate::image_proc::{cmd_parser::PrintCommand, Bitmap};
+
+    #[test]
+    fn test_geometry() {
+        // DP27P: 300 dpi, 576 点
+        let g = LabelGeometry::from_mm(40.0, 30.0, 2.0, 300).centred(576);
+        assert_eq!(g, LabelGeometry::new(472, 354, 24).with_offset(52));
+        assert_eq!(g.line_width(), 524);
+        assert_eq!(g.gap_setting(300), 203);
+        assert_eq!(g.commands(), vec![PrintCommand::LabelWidth(524)]);
+        // 203 dpi
+        let g = LabelGeometry::from_mm(40.0, 30.0, 2.0, 203);
+        assert_eq!(g, LabelGeometry::new(320, 240, 16));
+        assert_eq!(g.gap_setting(203), 200);
+        assert_eq!(
+            PrintCommand::LabelWidth(448).parse().unwrap().unwrap(),
+            vec![vec![0x1f, 0x27, 0x01, 0x38, 0x9f]]
+        );
+        // 标签比打印头宽
+        let g = LabelGeometry::new(600, 0, 0).centred(576);
+        assert_eq!((g.width, g.offset, g.line_width()), (576, 0, 576));
+        let g = LabelGeometry::new(500, 0, 0).with_offset(100).within(576);
+        assert_eq!((g.width, g.offset, g.line_width()), (476, 100, 576));
+        let g = LabelGeometry::new(500, 0, 0).with_offset(600).within(576);
+        assert_eq!((g.width, g.offset), (0, 576));
+    }
+
+    #[test]
+    fn test_place() {
+        let g = LabelGeometry::new(6, 2, 0).with_offset(1);
+        let line = g.place_line(&[true, true]);
+        assert_eq!(line, vec![false, false, false, true, true, false, false]);
+        // 比标签宽的内容裁掉两边
+        let line = g.place_line(&[true, false, true, true, true, true, false, true]);
+        assert_eq!(line, vec![false, false, true, true, true, true, false]);
+
+        let mut bm = Bitmap::new(2, 3);
+        bm.set_pixel(0, 0, true);
+        bm.set_pixel(1, 2, true);
+        let fitted = g.fit(&bm);
+        assert_eq!((fitted.width(), fitted.height()), (7, 2));
+        assert!(fitted.get_pixel(3, 0));
+        assert!(!fitted.get_pixel(4, 1));
+    }
+}
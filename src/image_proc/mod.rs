@@ -3,11 +3,48 @@
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
 pub mod cmd_parser;
+pub mod cmd_render;
+pub mod dither;
+pub mod format;
+pub mod label;
 use image::GrayImage;
 use rayon::iter::{IntoParallelRefMutIterator, ParallelIterator};
-use tiny_skia::Pixmap;
+use thiserror::Error;
+use tiny_skia::{Pixmap, PremultipliedColorU8};
 
-#[derive(Clone, Copy)]
+#[derive(Error, Debug)]
+pub enum BitmapError {
+    #[error("pixel count `{0}` does not match size {1}x{2}")]
+    SizeMismatch(usize, u32, u32),
+    #[error("region {0}x{1}+{2}+{3} is out of bounds")]
+    OutOfBounds(u32, u32, u32, u32),
+    #[error("packed data length `{0}` does not match width `{1}`")]
+    PackedLength(usize, u32),
+    #[error("size {0}x{1} is too large")]
+    TooLarge(u64, u64),
+    #[error("invalid pbm: {0}")]
+    InvalidPbm(String),
+    #[error("image error: `{0:?}`")]
+    ImageError(#[from] image::ImageError),
+}
+
+/// 位图叠加方式
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BlitOp {
+    /// 直接覆盖
+    Replace,
+    /// 黑色叠加
+    Or,
+    /// 仅保留两者都是黑色的像素
+    And,
+    /// 反色叠加
+    Xor,
+}
+
+/// 超过这个高度的图片使用多线程误差扩散
+const PARALLEL_DITHER_MIN_ROWS: usize = 256;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum DitherMode {
     /// 亮度截断
     Threshold,
@@ -15,7 +52,13 @@ pub enum DitherMode {
     FloydSteinberg,
 }
 
-#[derive(Clone)]
+/// 常规的灰度化公式
+pub(crate) fn pixel_luma(px: PremultipliedColorU8) -> u8 {
+    let px = px.demultiply();
+    (0.299 * px.red() as f32 + 0.587 * px.green() as f32 + 0.114 * px.blue() as f32) as u8
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub struct Bitmap {
     w: u32,
     h: u32,
@@ -23,6 +66,23 @@ pub struct Bitmap {
 }
 
 impl Bitmap {
+    /// 全白的位图
+    pub fn new(w: u32, h: u32) -> Bitmap {
+        Bitmap {
+            w,
+            h,
+            pix: vec![false; w as usize * h as usize],
+        }
+    }
+
+    /// 从逐行排列的像素创建位图, `true` 为黑色
+    pub fn from_raw(w: u32, h: u32, pix: Vec<bool>) -> Result<Bitmap, BitmapError> {
+        if pix.len() != w as usize * h as usize {
+            return Err(BitmapError::SizeMismatch(pix.len(), w, h));
+        }
+        Ok(Bitmap { w, h, pix })
+    }
+
     /// black (0) pixel will convert to `true`, otherwise to `false`
     pub fn from_gray_image(im: &GrayImage, mode: DitherMode) -> Bitmap {
         let w = im.width();
@@ -41,16 +101,7 @@ impl Bitmap {
         let h = im.height();
 
         // 转换为灰度缓冲 (Luma)
-        let mut gray: Vec<u8> = im
-            .pixels()
-            .iter()
-            .map(|px| {
-                let px = px.demultiply();
-                // 常规的灰度化公式
-                (0.299 * px.red() as f32 + 0.587 * px.green() as f32 + 0.114 * px.blue() as f32)
-                    as u8
-            })
-            .collect();
+        let mut gray: Vec<u8> = im.pixels().iter().map(|px| pixel_luma(*px)).collect();
 
         Self::process_dither(&mut gray, w as usize, h as usize, mode);
 
@@ -59,42 +110,15 @@ impl Bitmap {
         Bitmap { w, h, pix }
     }
 
-    fn process_dither(gray: &mut Vec<u8>, w: usize, h: usize, mode: DitherMode) {
+    fn process_dither(gray: &mut [u8], w: usize, h: usize, mode: DitherMode) {
         match mode {
-            DitherMode::Threshold => {
-                gray.par_iter_mut().for_each(|px| {
-                    *px = if *px > 127 { 255 } else { 0 };
-                });
-            }
+            DitherMode::Threshold => dither::threshold(gray),
             DitherMode::FloydSteinberg => {
-                for y in 0..h {
-                    for x in 0..w {
-                        let idx = y * w + x;
-                        let old_pixel = gray[idx];
-                        let new_pixel = if old_pixel > 127 { 255 } else { 0 };
-                        gray[idx] = new_pixel;
-
-                        let err = old_pixel as i16 - new_pixel as i16;
-                        if err == 0 {
-                            continue;
-                        }
-                        if x + 1 < w {
-                            let i = idx + 1;
-                            gray[i] = (gray[i] as i16 + err * 7 / 16).clamp(0, 255) as u8;
-                        }
-                        if y + 1 < h {
-                            if x > 0 {
-                                let i = (y + 1) * w + x - 1;
-                                gray[i] = (gray[i] as i16 + err * 3 / 16).clamp(0, 255) as u8;
-                            }
-                            let i = (y + 1) * w + x;
-                            gray[i] = (gray[i] as i16 + err * 5 / 16).clamp(0, 255) as u8;
-                            if x + 1 < w {
-                                let i = (y + 1) * w + x + 1;
-                                gray[i] = (gray[i] as i16 + err * 1 / 16).clamp(0, 255) as u8;
-                            }
-                        }
-                    }
+                let threads = rayon::current_num_threads();
+                if h >= PARALLEL_DITHER_MIN_ROWS && threads > 1 {
+                    dither::floyd_steinberg_parallel(gray, w, threads);
+                } else {
+                    dither::floyd_steinberg(gray, w);
                 }
             }
         }
@@ -112,6 +136,111 @@ impl Bitmap {
         self.pix[self.pixel_loc_unchecked(w, h)]
     }
 
+    pub fn set_pixel(&mut self, w: u32, h: u32, black: bool) {
+        let idx = self.pixel_loc_unchecked(w, h);
+        self.pix[idx] = black;
+    }
+
+    /// 逐行排列的全部像素
+    pub fn pixels(&self) -> &[bool] {
+        &self.pix
+    }
+
+    /// 裁剪出 `(left, top)` 开始, 大小为 `w` x `h` 的区域
+    pub fn crop(&self, left: u32, top: u32, w: u32, h: u32) -> Result<Bitmap, BitmapError> {
+        if left as u64 + w as u64 > self.w as u64 || top as u64 + h as u64 > self.h as u64 {
+            return Err(BitmapError::OutOfBounds(w, h, left, top));
+        }
+        let mut pix = Vec::with_capacity(w as usize * h as usize);
+        for y in top..top + h {
+            let start = self.pixel_loc_unchecked(left, y);
+            pix.extend_from_slice(&self.pix[start..start + w as usize]);
+        }
+        Ok(Bitmap { w, h, pix })
+    }
+
+    /// 在四周填充白色像素, 尺寸超过 `u32` 时返回错误
+    pub fn pad(&self, top: u32, right: u32, bottom: u32, left: u32) -> Result<Bitmap, BitmapError> {
+        let w = left.checked_add(self.w).and_then(|w| w.checked_add(right));
+        let h = top.checked_add(self.h).and_then(|h| h.checked_add(bottom));
+        let (Some(w), Some(h)) = (w, h) else {
+            return Err(BitmapError::TooLarge(
+                left as u64 + self.w as u64 + right as u64,
+                top as u64 + self.h as u64 + bottom as u64,
+            ));
+        };
+        let mut out = Bitmap::new(w, h);
+        out.blit(self, left as i64, top as i64, BlitOp::Replace);
+        Ok(out)
+    }
+
+    /// 黑白反色
+    pub fn invert(&mut self) {
+        self.pix.par_iter_mut().for_each(|px| *px = !*px);
+    }
+
+    /// 把 `src` 叠加到 `(x, y)` 处, 超出边界的部分会被丢弃
+    pub fn blit(&mut self, src: &Bitmap, x: i64, y: i64, op: BlitOp) {
+        let x0 = x.max(0);
+        let y0 = y.max(0);
+        let x1 = (x + src.w as i64).min(self.w as i64);
+        let y1 = (y + src.h as i64).min(self.h as i64);
+        if x0 >= x1 || y0 >= y1 {
+            return;
+        }
+        for dy in y0..y1 {
+            let src_start = src.pixel_loc_unchecked((x0 - x) as u32, (dy - y) as u32);
+            let dst_start = self.pixel_loc_unchecked(x0 as u32, dy as u32);
+            let len = (x1 - x0) as usize;
+            let src_line = &src.pix[src_start..src_start + len];
+            let dst_line = &mut self.pix[dst_start..dst_start + len];
+            for (d, s) in dst_line.iter_mut().zip(src_line) {
+                *d = match op {
+                    BlitOp::Replace => *s,
+                    BlitOp::Or => *d || *s,
+                    BlitOp::And => *d && *s,
+                    BlitOp::Xor => *d ^ *s,
+                };
+            }
+        }
+    }
+
+    /// 从左到右拼接, 顶部对齐, 高度不足的部分填充白色, 总宽度超过 `u32` 时返回错误
+    pub fn concat_horizontal(items: &[Bitmap]) -> Result<Bitmap, BitmapError> {
+        let h = items.iter().map(|x| x.h).max().unwrap_or(0);
+        let w = items
+            .iter()
+            .try_fold(0u32, |w, x| w.checked_add(x.w))
+            .ok_or_else(|| {
+                BitmapError::TooLarge(items.iter().map(|x| x.w as u64).sum(), h as u64)
+            })?;
+        let mut out = Bitmap::new(w, h);
+        let mut x = 0;
+        for item in items {
+            out.blit(item, x, 0, BlitOp::Replace);
+            x += item.w as i64;
+        }
+        Ok(out)
+    }
+
+    /// 从上到下拼接, 左侧对齐, 宽度不足的部分填充白色, 总高度超过 `u32` 时返回错误
+    pub fn concat_vertical(items: &[Bitmap]) -> Result<Bitmap, BitmapError> {
+        let w = items.iter().map(|x| x.w).max().unwrap_or(0);
+        let h = items
+            .iter()
+            .try_fold(0u32, |h, x| h.checked_add(x.h))
+            .ok_or_else(|| {
+                BitmapError::TooLarge(w as u64, items.iter().map(|x| x.h as u64).sum())
+            })?;
+        let mut out = Bitmap::new(w, h);
+        let mut y = 0;
+        for item in items {
+            out.blit(item, 0, y, BlitOp::Replace);
+            y += item.h as i64;
+        }
+        Ok(out)
+    }
+
     pub fn get_line(&self, h: u32) -> Vec<bool> {
         let (start, end) = self.line_loc_unchecked(h);
         self.pix[start..end].to_vec()
@@ -151,7 +280,207 @@ impl Bitmap {
     /// returns [start..end] of a line
     pub fn line_loc_unchecked(&self, h: u32) -> (usize, usize) {
         let start = self.pixel_loc_unchecked(0, h);
-        let end = self.pixel_loc_unchecked(self.w - 1, h);
+        let end = start + self.w as usize;
         (start, end)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{Bitmap, BitmapError, BlitOp};
+
+    /// 简单的伪随机位图
+    fn noise(w: u32, h: u32, seed: u32) -> Bitmap {
+        let mut x = seed.wrapping_mul(2654435761).wrapping_add(1);
+        let pix = (0..w * h)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                x & 1 == 1
+            })
+            .collect();
+        Bitmap::from_raw(w, h, pix).unwrap()
+    }
+
+    #[test]
+    fn test_line_covers_full_width() {
+        for w in 1..=80 {
+            for h in 1..=4 {
+                let b = noise(w, h, w * 31 + h);
+                for y in 0..h {
+                    let line = b.get_line(y);
+                    assert_eq!(line.len(), w as usize, "unexcepted width at w={w} y={y}");
+                    for x in 0..w {
+                        assert_eq!(line[x as usize], b.get_pixel(x, y));
+                    }
+                    assert_eq!(b.is_line_empty(y), !line.iter().any(|x| *x));
+                }
+
+                // 只有最后一个像素是黑色
+                let mut b = Bitmap::new(w, h);
+                b.set_pixel(w - 1, h - 1, true);
+                let (start, _) = b.line_loc_unchecked(h - 1);
+                assert!(!b.is_line_empty(h - 1));
+                assert_eq!(
+                    b.first_black_pixel_in_line(h - 1),
+                    Some(start + w as usize - 1)
+                );
+                assert_eq!(
+                    b.last_black_pixel_in_line(h - 1),
+                    Some(start + w as usize - 1)
+                );
+                if h > 1 {
+                    assert!(!b.same_lines(h - 2, h - 1));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_from_raw() {
+        assert!(matches!(
+            Bitmap::from_raw(3, 2, vec![false; 5]),
+            Err(BitmapError::SizeMismatch(5, 3, 2))
+        ));
+        let b = Bitmap::from_raw(3, 2, vec![true, false, false, false, false, true]).unwrap();
+        assert!(b.get_pixel(0, 0));
+        assert!(b.get_pixel(2, 1));
+        assert!(!b.get_pixel(1, 1));
+    }
+
+    #[test]
+    fn test_crop_pad() {
+        let b = noise(17, 9, 7);
+        let padded = b.pad(1, 2, 3, 4).unwrap();
+        assert_eq!((padded.width(), padded.height()), (23, 13));
+        assert!(padded.is_line_empty(0));
+        assert!(padded.is_line_empty(12));
+        assert_eq!(padded.crop(4, 1, 17, 9).unwrap(), b);
+        assert!(matches!(
+            b.crop(10, 0, 8, 1),
+            Err(BitmapError::OutOfBounds(8, 1, 10, 0))
+        ));
+        assert_eq!(b.crop(0, 0, 0, 0).unwrap().pixels().len(), 0);
+        assert!(matches!(
+            b.pad(0, u32::MAX, 0, 0),
+            Err(BitmapError::TooLarge(..))
+        ));
+    }
+
+    #[test]
+    fn test_invert_blit() {
+        let mut b = noise(12, 5, 3);
+        let orig = b.clone();
+        b.invert();
+        assert!(b.pixels().iter().zip(orig.pixels()).all(|(a, b)| a != b));
+
+        let mut canvas = Bitmap::new(4, 4);
+        let stamp = Bitmap::from_raw(2, 2, vec![true; 4]).unwrap();
+        canvas.blit(&stamp, -1, 3, BlitOp::Or);
+        assert!(canvas.get_pixel(0, 3));
+        assert_eq!(canvas.pixels().iter().filter(|x| **x).count(), 1);
+        canvas.blit(&stamp, 0, 2, BlitOp::Xor);
+        assert!(canvas.get_pixel(1, 3));
+        assert!(!canvas.get_pixel(0, 3));
+        canvas.blit(&Bitmap::new(4, 4), 0, 0, BlitOp::And);
+        assert!(canvas.pixels().iter().all(|x| !*x));
+        canvas.blit(&stamp, 10, 10, BlitOp::Replace);
+        assert!(canvas.pixels().iter().all(|x| !*x));
+    }
+
+    #[test]
+    fn test_concat() {
+        let a = noise(5, 3, 1);
+        let b = noise(7, 6, 2);
+        let h = Bitmap::concat_horizontal(&[a.clone(), b.clone()]).unwrap();
+        assert_eq!((h.width(), h.height()), (12, 6));
+        assert_eq!(h.crop(0, 0, 5, 3).unwrap(), a);
+        assert_eq!(h.crop(5, 0, 7, 6).unwrap(), b);
+        assert!(h.crop(0, 3, 5, 3).unwrap().pixels().iter().all(|x| !*x));
+
+        let v = Bitmap::concat_vertical(&[a.clone(), b.clone()]).unwrap();
+        assert_eq!((v.width(), v.height()), (7, 9));
+        assert_eq!(v.crop(0, 0, 5, 3).unwrap(), a);
+        assert_eq!(v.crop(0, 3, 7, 6).unwrap(), b);
+
+        assert_eq!(Bitmap::concat_vertical(&[]).unwrap(), Bitmap::new(0, 0));
+
+        let wide = Bitmap::new(1 << 31, 0);
+        assert!(matches!(
+            Bitmap::concat_horizontal(&[wide.clone(), wide]),
+            Err(BitmapError::TooLarge(0x1_0000_0000, 0))
+        ));
+        let tall = Bitmap::new(0, u32::MAX);
+        assert!(matches!(
+            Bitmap::concat_vertical(&[tall, Bitmap::new(0, 1)]),
+            Err(BitmapError::TooLarge(0, 0x1_0000_0000))
+        ));
+    }
+
+    fn black(b: &Bitmap) -> usize {
+        b.pixels().iter().filter(|x| **x).count()
+    }
+
+    /// 随机尺寸和参数下的裁剪/填充/反色/拼接不变量
+    #[test]
+    fn test_properties() {
+        let mut x = 0x9e37_79b9u32;
+        let mut rand = |max: u32| {
+            x ^= x << 13;
+            x ^= x >> 17;
+            x ^= x << 5;
+            x % (max + 1)
+        };
+        for seed in 0..300 {
+            let b = noise(rand(40), rand(40), seed);
+            let (w, h) = (b.width(), b.height());
+
+            // 填充后裁回原来的区域得到原图, 填充的部分都是白色
+            let (top, right, bottom, left) = (rand(8), rand(8), rand(8), rand(8));
+            let padded = b.pad(top, right, bottom, left).unwrap();
+            assert_eq!(padded.width(), left + w + right);
+            assert_eq!(padded.height(), top + h + bottom);
+            assert_eq!(padded.crop(left, top, w, h).unwrap(), b);
+            assert_eq!(black(&padded), black(&b));
+
+            // 范围内的裁剪成功, 超出范围的失败
+            let (cl, ct) = (rand(w), rand(h));
+            let (cw, ch) = (rand(w - cl), rand(h - ct));
+            let cropped = b.crop(cl, ct, cw, ch).unwrap();
+            assert_eq!((cropped.width(), cropped.height()), (cw, ch));
+            for y in 0..ch {
+                for x in 0..cw {
+                    assert_eq!(cropped.get_pixel(x, y), b.get_pixel(cl + x, ct + y));
+                }
+            }
+            assert!(b.crop(cl, ct, w - cl + 1, ch).is_err());
+            assert!(b.crop(cl, ct, cw, h - ct + 1).is_err());
+
+            // 反色两次还原, 黑白像素数互换
+            let mut inv = b.clone();
+            inv.invert();
+            assert_eq!(black(&inv), (w * h) as usize - black(&b));
+            inv.invert();
+            assert_eq!(inv, b);
+
+            // 拼接后每一块都能裁回来, 其他部分是白色
+            let parts: Vec<Bitmap> = (0..rand(4))
+                .map(|i| noise(rand(20), rand(20), seed * 7 + i))
+                .collect();
+            let total = parts.iter().map(black).sum::<usize>();
+            let hz = Bitmap::concat_horizontal(&parts).unwrap();
+            let vt = Bitmap::concat_vertical(&parts).unwrap();
+            assert_eq!(hz.width(), parts.iter().map(|p| p.width()).sum::<u32>());
+            assert_eq!(vt.height(), parts.iter().map(|p| p.height()).sum::<u32>());
+            assert_eq!((black(&hz), black(&vt)), (total, total));
+            let (mut px, mut py) = (0, 0);
+            for p in &parts {
+                assert_eq!(hz.crop(px, 0, p.width(), p.height()).unwrap(), *p);
+                assert_eq!(vt.crop(0, py, p.width(), p.height()).unwrap(), *p);
+                px += p.width();
+                py += p.height();
+            }
+        }
+    }
+}
@@ -2,13 +2,18 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
+// 原有的 command 测试里用了字节字符数组和 0 开头的整数字面量
+#![cfg_attr(test, allow(clippy::byte_char_slices, clippy::zero_prefixed_literal))]
+
 pub mod backend;
+pub mod barcode;
 pub mod command;
 pub mod error_code;
 pub mod frontend;
 pub mod image_proc;
 pub mod info;
 pub mod param;
+pub mod pipeline;
 pub mod rle;
 pub mod scheduler;
 
@@ -0,0 +1,249 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! 标签纸校准: 一边走纸一边读取传感器, 测量标签长度和间隙
+//!
+//! `0x88` 返回的数据中哪个数值是间隙传感器还没有确认, 所以选择走纸过程中变化最大的那个
+//!
+//! 打印机只能保存间隙, 标签长度保存在 [LabelProfile] 文件里, 打印时再读取
+
+use serde_json::{json, Value};
+use thiserror::Error;
+
+use super::{
+    enable_high_commands, read_sensor, send_print_command,
+    sensor::{sensor_channels, SENSOR_HEAD},
+    PipelineError,
+};
+use crate::{
+    backend,
+    image_proc::{cmd_parser::PrintCommand, label::LabelGeometry},
+};
+
+/// 变化小于这个值时认为传感器没有信号
+const MIN_SWING: u16 = 16;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum CalibrationError {
+    #[error("sensor readings did not change, is the label stock loaded?")]
+    NoSignal,
+    #[error("no complete label and gap found, feed a longer distance")]
+    TooShort,
+    #[error("invalid label profile: {0}")]
+    InvalidProfile(String),
+}
+
+/// 校准结果, 单位都是点
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Calibration {
+    /// 标签长度
+    pub label_length: u32,
+    /// 间隙长度
+    pub gap: u32,
+    /// 使用的传感器数值序号
+    pub channel: usize,
+}
+
+impl Calibration {
+    /// 相邻两张标签开头之间的距离
+    pub fn pitch(&self) -> u32 {
+        self.label_length + self.gap
+    }
+
+    /// 按测量结果生成的标签尺寸
+    pub fn geometry(&self, width: u32) -> LabelGeometry {
+        LabelGeometry::new(width, self.label_length, self.gap)
+    }
+
+    /// 按打印机的分辨率 (dpi) 换算成毫米
+    pub fn profile(&self, dpi: u16) -> LabelProfile {
+        let mm = |dots: u32| dots as f32 / dpi.max(1) as f32 * 25.4;
+        LabelProfile {
+            label_length: mm(self.label_length),
+            gap: mm(self.gap),
+        }
+    }
+}
+
+/// 校准得到的标签纸参数, 单位都是毫米, 保存为 JSON:
+///
+/// ```json
+/// {"label_length": 30.0, "gap": 2.0}
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LabelProfile {
+    pub label_length: f32,
+    pub gap: f32,
+}
+
+impl LabelProfile {
+    pub fn to_json(&self) -> String {
+        json!({"label_length": self.label_length, "gap": self.gap}).to_string()
+    }
+
+    pub fn from_json(text: &str) -> Result<Self, CalibrationError> {
+        let invalid = |e: String| CalibrationError::InvalidProfile(e);
+        let v: Value = serde_json::from_str(text).map_err(|e| invalid(e.to_string()))?;
+        let field = |name: &str| {
+            v.get(name)
+                .and_then(Value::as_f64)
+                .filter(|x| *x >= 0.0)
+                .map(|x| x as f32)
+                .ok_or_else(|| invalid(format!("missing or invalid `{name}`")))
+        };
+        Ok(LabelProfile {
+            label_length: field("label_length")?,
+            gap: field("gap")?,
+        })
+    }
+}
+
+/// 分析传感器数据, `samples[i]` 是走纸 `i * step` 个点之后的读数
+///
+/// 第一段和最后一段不完整, 不参与计算; 标签总是比间隙长
+pub fn analyse(samples: &[Vec<u16>], step: u32) -> Result<Calibration, CalibrationError> {
+    let channels = samples.iter().map(Vec::len).min().unwrap_or(0);
+    let range = |c: usize| {
+        let values = samples.iter().map(|s| s[c]);
+        (values.clone().min().unwrap(), values.max().unwrap())
+    };
+    let (channel, (lo, hi)) = (0..channels)
+        .map(|c| (c, range(c)))
+        .max_by_key(|(_, (lo, hi))| hi - lo)
+        .ok_or(CalibrationError::NoSignal)?;
+    if hi - lo < MIN_SWING {
+        return Err(CalibrationError::NoSignal);
+    }
+
+    let mid = lo + (hi - lo) / 2;
+    let states: Vec<bool> = samples.iter().map(|s| s[channel] > mid).collect();
+    let runs: Vec<(bool, u32)> = states
+        .chunk_by(|a, b| a == b)
+        .map(|r| (r[0], r.len() as u32))
+        .collect();
+    if runs.len() < 4 {
+        return Err(CalibrationError::TooShort);
+    }
+    let full = &runs[1..runs.len() - 1];
+    let mean = |state: bool| {
+        let (sum, n) = full
+            .iter()
+            .filter(|r| r.0 == state)
+            .fold((0, 0), |(sum, n), r| (sum + r.1, n + 1));
+        sum * step / n
+    };
+    let (a, b) = (mean(true), mean(false));
+    Ok(Calibration {
+        label_length: a.max(b),
+        gap: a.min(b),
+        channel,
+    })
+}
+
+/// 走纸最多 `max_dots` 个点, 每走 `step` 个点读取一次传感器, 然后分析
+///
+/// 不会修改打印机设置, 需要的话用 [Calibration::geometry] 设置打印纸间隔
+pub async fn calibrate(
+    b: &backend::USBBackend,
+    step: u32,
+    max_dots: u32,
+) -> Result<Calibration, PipelineError> {
+    let step = step.max(1);
+    enable_high_commands(b).await?;
+    send_print_command(b, &PrintCommand::ResetPrinter).await?;
+    let mut samples = vec![];
+    let mut fed = 0;
+    loop {
+        samples.push(sensor_channels(&read_sensor(b, SENSOR_HEAD).await?));
+        if fed >= max_dots {
+            break;
+        }
+        send_print_command(b, &PrintCommand::FeedLines(step)).await?;
+        fed += step;
+    }
+    Ok(analyse(&samples, step)?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{analyse, calibrate, Calibration, CalibrationError, LabelProfile};
+    use crate::{backend::USBBackend, pipeline::fake_printer};
+
+    /// 走纸 `len` 个点, 每 `step` 个点一个读数
+    ///
+    /// 第 0 个数值是缓慢上升的温度, 第 1 个是间隙传感器
+    fn feed(start: u32, len: u32, step: u32, label: u32, gap: u32) -> Vec<Vec<u16>> {
+        (0..=len / step)
+            .map(|i| {
+                let pos = (start + i * step) % (label + gap);
+                let sensor = if pos < label { 900 } else { 200 };
+                vec![250 + (i / 4) as u16, sensor + (i % 3) as u16]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_analyse() {
+        // 203 dpi 时的 30 mm 标签, 3 mm 间隙
+        let samples = feed(100, 800, 8, 240, 24);
+        let c = analyse(&samples, 8).unwrap();
+        assert_eq!(
+            c,
+            Calibration {
+                label_length: 240,
+                gap: 24,
+                channel: 1,
+            }
+        );
+        assert_eq!(c.pitch(), 264);
+        assert_eq!(c.geometry(320).gap_setting(203), 300);
+
+        assert_eq!(
+            analyse(&feed(200, 200, 8, 240, 24), 8),
+            Err(CalibrationError::TooShort)
+        );
+        assert_eq!(
+            analyse(&vec![vec![100, 100]; 50], 8),
+            Err(CalibrationError::NoSignal)
+        );
+        assert_eq!(analyse(&[], 8), Err(CalibrationError::NoSignal));
+    }
+
+    #[test]
+    fn test_profile() {
+        let c = Calibration {
+            label_length: 354,
+            gap: 24,
+            channel: 1,
+        };
+        let p = c.profile(300);
+        assert!((p.label_length - 29.97).abs() < 0.01 && (p.gap - 2.03).abs() < 0.01);
+        assert_eq!(LabelProfile::from_json(&p.to_json()), Ok(p));
+        assert!(matches!(
+            LabelProfile::from_json("{\"gap\": 2}"),
+            Err(CalibrationError::InvalidProfile(_))
+        ));
+        assert!(matches!(
+            LabelProfile::from_json("[]"),
+            Err(CalibrationError::InvalidProfile(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_calibrate_enables_high_commands() {
+        let (b, rx) = USBBackend::mock();
+        let printer = fake_printer(rx, |op, _| match op {
+            0x80 => vec![0x7f],
+            0x88 => vec![0x01, 0x01, 0x2c, 0x03, 0x84],
+            _ => vec![0],
+        });
+        // 读数不变, 找不到间隙, 这里只关心命令顺序
+        assert!(calibrate(&b, 10, 20).await.is_err());
+        drop(b);
+        let log = printer.await.unwrap();
+        let ops: Vec<u8> = log.iter().filter_map(|x| x.get(1).copied()).collect();
+        assert_eq!(ops[0], 0x80);
+        assert_eq!(ops.iter().filter(|x| **x == 0x88).count(), 3);
+    }
+}
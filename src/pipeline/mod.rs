@@ -0,0 +1,554 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+pub mod battery;
+pub mod calibrate;
+pub mod sensor;
+pub mod source;
+pub mod statistics;
+pub mod thermal;
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use num_traits::FromPrimitive;
+use thiserror::Error;
+use tracing::debug;
+
+use crate::{
+    backend,
+    command::{self, HostCommand},
+    error_code::PrinterErrorCode,
+    image_proc::{
+        cmd_parser::{EncodeStats, LineEncoder, PrintCommand, PrintCommandError},
+        dither::RowDitherer,
+        label::LabelGeometry,
+        Bitmap, DitherMode,
+    },
+};
+use battery::{read_battery, BatteryLimits};
+use source::RowSource;
+use thermal::{ThermalGuard, ThermalLimits};
+
+#[derive(Error, Debug)]
+pub enum PipelineError {
+    #[error("backend closed")]
+    BackendClosed,
+    #[error("printer did not respond")]
+    NoResponse,
+    #[error("printer did not enable commands above 0x80, replied `{0:02x?}`")]
+    HighCommands(Vec<u8>),
+    #[error("printer error: `{0:?}`")]
+    Printer(PrinterErrorCode),
+    #[error("invalid font: {0}")]
+    InvalidFont(String),
+    #[error(transparent)]
+    Command(#[from] PrintCommandError),
+    #[error(transparent)]
+    Calibration(#[from] calibrate::CalibrationError),
+    #[error(transparent)]
+    Sensor(#[from] sensor::SensorError),
+    #[error(transparent)]
+    Statistics(#[from] statistics::StatisticsError),
+    #[error("battery low ({percent}%, {voltage:.2} V), connect the charger before printing")]
+    LowBattery { percent: u8, voltage: f32 },
+}
+
+/// 流式打印命令生成: 来源 -> 抖动 -> 打印命令
+///
+/// 每次只从来源读取需要的行, 内存占用和图片高度无关
+pub struct StreamEncoder<S> {
+    source: S,
+    ditherer: RowDitherer,
+    encoder: LineEncoder,
+    breakpoint: u32,
+    geometry: Option<LabelGeometry>,
+    queue: VecDeque<PrintCommand>,
+    lines: u32,
+    done: bool,
+}
+
+impl<S: RowSource> StreamEncoder<S> {
+    /// - source: 来源
+    /// - mode: 抖动方式
+    /// - bp: 每隔多少行插入一个断点命令
+    pub fn new(source: S, mode: DitherMode, bp: u32) -> Self {
+        let w = source.width();
+        StreamEncoder {
+            source,
+            ditherer: RowDitherer::new(w, mode),
+            encoder: LineEncoder::new(w, bp),
+            breakpoint: bp,
+            geometry: None,
+            queue: VecDeque::new(),
+            lines: 0,
+            done: false,
+        }
+    }
+
+    /// 按标签尺寸打印: 开始时设置标签宽度, 每行水平居中, 超出标签高度的行被丢弃
+    ///
+    /// 需要在取出任何命令之前调用
+    pub fn with_geometry(mut self, geometry: LabelGeometry) -> Self {
+        self.encoder = LineEncoder::new(geometry.line_width(), self.breakpoint);
+        self.queue = geometry.commands().into();
+        self.geometry = Some(geometry);
+        self
+    }
+
+    /// 已经编码的行数
+    pub fn lines(&self) -> u32 {
+        self.lines
+    }
+
+    /// 编码统计
+    pub fn stats(&self) -> EncodeStats {
+        self.encoder.stats()
+    }
+
+    fn push_line(&mut self, line: Vec<bool>) {
+        let line = match &self.geometry {
+            Some(g) if g.is_full(self.lines) => return,
+            Some(g) => g.place_line(&line),
+            None => line,
+        };
+        self.lines += 1;
+        self.queue.extend(self.encoder.push_line(line));
+    }
+}
+
+impl<S: RowSource> Iterator for StreamEncoder<S> {
+    type Item = PrintCommand;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(c) = self.queue.pop_front() {
+                return Some(c);
+            }
+            if self.done {
+                return None;
+            }
+            // 标签已经打满, 不用再读取来源
+            let full = self.geometry.is_some_and(|g| g.is_full(self.lines));
+            match (!full).then(|| self.source.next_row()).flatten() {
+                Some(row) => {
+                    if let Some(line) = self.ditherer.push_row(&row) {
+                        self.push_line(line);
+                    }
+                }
+                None => {
+                    self.done = true;
+                    if let Some(line) = self.ditherer.finish() {
+                        self.push_line(line);
+                    }
+                    self.queue.extend(self.encoder.finish());
+                }
+            }
+        }
+    }
+}
+
+/// 把来源全部读出来, 用于预览
+pub fn collect_bitmap<S: RowSource>(mut source: S, mode: DitherMode) -> Bitmap {
+    let w = source.width();
+    let mut ditherer = RowDitherer::new(w, mode);
+    let mut pix = Vec::new();
+    while let Some(row) = source.next_row() {
+        if let Some(line) = ditherer.push_row(&row) {
+            pix.extend(line);
+        }
+    }
+    if let Some(line) = ditherer.finish() {
+        pix.extend(line);
+    }
+    let h = pix.len() / (w as usize).max(1);
+    Bitmap::from_raw(w, h as u32, pix).unwrap()
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StreamStats {
+    /// 打印的行数
+    pub lines: u32,
+    /// 发送的打印命令字节数
+    pub bytes: usize,
+    /// 编码统计, 不包括初始化和出纸命令
+    pub encode: EncodeStats,
+    /// 因为打印头温度等待的时间
+    pub throttled: Duration,
+}
+
+/// 读取打印机状态, 打印机报错时返回错误
+pub async fn check_status(b: &backend::USBBackend) -> Result<u8, PipelineError> {
+    let stat = read_status(b).await?;
+    if let Some(e) = PrinterErrorCode::from_u8(stat) {
+        return Err(PipelineError::Printer(e));
+    }
+    Ok(stat)
+}
+
+/// 读取打印机状态字节
+pub async fn read_status(b: &backend::USBBackend) -> Result<u8, PipelineError> {
+    let stat = *query(b, HostCommand::GetPrinterStatus, vec![])
+        .await?
+        .first()
+        .ok_or(PipelineError::NoResponse)?;
+    debug!("status: {stat}");
+    Ok(stat)
+}
+
+/// 读取传感器数据 (`0x88`), 返回的第 0 个字节是子命令
+pub async fn read_sensor(b: &backend::USBBackend, sub: u8) -> Result<Vec<u8>, PipelineError> {
+    let payload = query(b, HostCommand::GetSensorStatus, vec![sub]).await?;
+    debug!("sensor {sub:#04x}: {payload:02x?}");
+    Ok(payload)
+}
+
+/// 发送一条需要回复的命令, 返回回复的数据
+///
+/// `0x80` 以上的命令会先激活, 见 [enable_high_commands]
+pub async fn query(
+    b: &backend::USBBackend,
+    c: HostCommand,
+    payload: Vec<u8>,
+) -> Result<Vec<u8>, PipelineError> {
+    if c as u16 & 0xff >= 0x80 {
+        enable_high_commands(b).await?;
+    }
+    query_raw(b, c, payload).await
+}
+
+/// 激活 `0x80` 以上的命令: 发送 `0x80 0x7f`, 打印机回复 `0x7f`
+///
+/// 每个连接只需要发送一次, 之后直接返回
+pub async fn enable_high_commands(b: &backend::USBBackend) -> Result<(), PipelineError> {
+    b.high_commands
+        .get_or_try_init(|| async {
+            let reply = query_raw(b, HostCommand::EnableHighCommand, vec![0x7f]).await?;
+            if reply.first() != Some(&0x7f) {
+                return Err(PipelineError::HighCommands(reply));
+            }
+            debug!("enabled high commands");
+            Ok(())
+        })
+        .await?;
+    Ok(())
+}
+
+/// 开始一次打印前的准备: 检查打印机状态, 然后激活 `0x80` 以上的命令
+pub async fn begin_job(b: &backend::USBBackend) -> Result<(), PipelineError> {
+    check_status(b).await?;
+    enable_high_commands(b).await
+}
+
+async fn query_raw(
+    b: &backend::USBBackend,
+    c: HostCommand,
+    payload: Vec<u8>,
+) -> Result<Vec<u8>, PipelineError> {
+    let (cmd, chan) =
+        backend::Command::with_response(command::Command::new_host(c).package(payload, false));
+    b.push(cmd)
+        .await
+        .map_err(|_| PipelineError::BackendClosed)?;
+    let chan = chan
+        .await
+        .map_err(|_| PipelineError::BackendClosed)?
+        .ok_or(PipelineError::NoResponse)?;
+    // 如果收不到东西，那一定是打印机 buffer 炸了
+    let resp = chan.await.map_err(|_| PipelineError::NoResponse)?;
+    Ok(resp.get_payload())
+}
+
+/// 测试用的打印机: 记录收到的每个数据包, 需要回复的命令按 `reply(命令类型, 参数)` 回复
+#[cfg(test)]
+pub(crate) fn fake_printer(
+    mut rx: tokio::sync::mpsc::Receiver<backend::Command>,
+    reply: impl Fn(u8, &[u8]) -> Vec<u8> + Send + 'static,
+) -> tokio::task::JoinHandle<Vec<Vec<u8>>> {
+    tokio::spawn(async move {
+        let mut log = vec![];
+        while let Some(c) = rx.recv().await {
+            match c {
+                backend::Command::WithResponse(data, tx) => {
+                    let (op, args) = (data[1], &data[3..data.len() - 1]);
+                    let buf = command::package_with_header((0x1f, op), &reply(op, args), false);
+                    let (resp, _) = command::Command::parse_device_command(buf).unwrap();
+                    let (resp_tx, resp_rx) = tokio::sync::oneshot::channel();
+                    resp_tx.send(resp).ok();
+                    tx.send(Some(resp_rx)).ok();
+                    log.push(data);
+                }
+                backend::Command::WithoutResponse(data, tx) => {
+                    tx.send(true).ok();
+                    log.push(data);
+                }
+                backend::Command::Reset(tx) => {
+                    tx.send(true).ok();
+                }
+            }
+        }
+        log
+    })
+}
+
+/// 发送一条打印命令, 断点命令会等待打印机返回状态
+pub async fn send_print_command(
+    b: &backend::USBBackend,
+    c: &PrintCommand,
+) -> Result<usize, PipelineError> {
+    let Some(bufs) = c.parse()? else {
+        check_status(b).await?;
+        return Ok(0);
+    };
+    let mut bytes = 0;
+    for buf in bufs {
+        bytes += buf.len();
+        let (cmd, _) = backend::Command::without_response(buf);
+        b.push(cmd)
+            .await
+            .map_err(|_| PipelineError::BackendClosed)?;
+    }
+    Ok(bytes)
+}
+
+/// 探测结果
+#[derive(Debug, Clone)]
+pub struct ProbeReport {
+    /// 发送的数据
+    pub sent: Vec<u8>,
+    /// 之后读到的状态, 超时为 `None`
+    pub status: Option<u8>,
+    /// 从发送到读到状态的时间
+    pub elapsed: Duration,
+}
+
+/// 发送一条命令并读取打印机状态, 用于逆向未知命令
+///
+/// 未知命令可能让打印机卡住, 所以读取状态有超时
+pub async fn probe(
+    b: &backend::USBBackend,
+    c: &PrintCommand,
+    timeout: Duration,
+) -> Result<ProbeReport, PipelineError> {
+    let sent = c.parse()?.unwrap_or_default().concat();
+    let start = Instant::now();
+    let (cmd, chan) = backend::Command::without_response(sent.clone());
+    b.push(cmd)
+        .await
+        .map_err(|_| PipelineError::BackendClosed)?;
+    chan.await.map_err(|_| PipelineError::BackendClosed)?;
+    let status = match tokio::time::timeout(timeout, read_status(b)).await {
+        Ok(Ok(x)) => Some(x),
+        Ok(Err(PipelineError::NoResponse)) | Err(_) => None,
+        Ok(Err(e)) => return Err(e),
+    };
+    Ok(ProbeReport {
+        sent,
+        status,
+        elapsed: start.elapsed(),
+    })
+}
+
+/// 打印完内容之后的动作
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Finish {
+    /// 定位到下一张纸 (标签间隙或者撕纸位置)
+    #[default]
+    NextPaper,
+    /// 停在内容后面, 下一张紧接着打印
+    Stop,
+}
+
+/// 打印选项
+#[derive(Debug, Clone, Default)]
+pub struct PrintOptions {
+    /// 打印头过热保护, `None` 表示不检查温度
+    pub thermal: Option<ThermalLimits>,
+    /// 开始打印前检查电量, `None` 表示不检查
+    pub battery: Option<BatteryLimits>,
+    /// 内容之后额外走纸的行数
+    pub feed: u32,
+    /// 最后的动作
+    pub finish: Finish,
+}
+
+/// 流式打印一张纸
+///
+/// 后端的命令队列是有界的, 发送不过来时会暂停读取来源
+pub async fn print_stream<S: RowSource>(
+    b: &backend::USBBackend,
+    encoder: StreamEncoder<S>,
+) -> Result<StreamStats, PipelineError> {
+    print_stream_with(b, encoder, &PrintOptions::default()).await
+}
+
+/// 按照选项流式打印一张纸, 见 [print_stream]
+pub async fn print_stream_with<S: RowSource>(
+    b: &backend::USBBackend,
+    mut encoder: StreamEncoder<S>,
+    options: &PrintOptions,
+) -> Result<StreamStats, PipelineError> {
+    begin_job(b).await?;
+    if let Some(limits) = &options.battery {
+        limits.check(read_battery(b, &limits.curve).await?.as_ref())?;
+    }
+    let mut guard = options.thermal.map(ThermalGuard::new);
+    let mut stats = StreamStats::default();
+    stats.bytes += send_print_command(b, &PrintCommand::ResetPrinter).await?;
+    for c in encoder.by_ref() {
+        if c != PrintCommand::Breakpoint {
+            stats.bytes += send_print_command(b, &c).await?;
+            continue;
+        }
+        let Some(guard) = &mut guard else {
+            check_status(b).await?;
+            continue;
+        };
+        // 打印机已经过热时先冷却, 然后继续
+        match check_status(b).await {
+            Err(PipelineError::Printer(PrinterErrorCode::TphTooHot)) => guard.overheated(),
+            x => {
+                x?;
+            }
+        }
+        guard.check(b).await?;
+    }
+    if options.feed > 0 {
+        stats.bytes += send_print_command(b, &PrintCommand::FeedLines(options.feed)).await?;
+    }
+    if options.finish == Finish::NextPaper {
+        stats.bytes += send_print_command(b, &PrintCommand::NextPaper).await?;
+    }
+    stats.lines = encoder.lines();
+    stats.encode = encoder.stats();
+    stats.throttled = guard.map(|g| g.waited()).unwrap_or_default();
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod test {
+    use image::GrayImage;
+    use tiny_skia::Pixmap;
+
+    use super::{
+        collect_bitmap, fake_printer, read_sensor, read_status,
+        source::{GrayImageSource, PixmapSource, RowSource},
+        PipelineError, StreamEncoder,
+    };
+    use crate::{
+        backend::USBBackend,
+        image_proc::{
+            cmd_parser::{BitmapParser, PrintCommand},
+            cmd_render::render_commands,
+            label::LabelGeometry,
+            Bitmap, DitherMode,
+        },
+    };
+
+    fn sample(w: u32, h: u32) -> GrayImage {
+        GrayImage::from_fn(w, h, |x, y| {
+            let v = match (y / 9) % 4 {
+                0 => 255,
+                1 => (x * 255 / w) as u8,
+                2 => 0,
+                _ => ((x * 7 + y * 13) % 256) as u8,
+            };
+            image::Luma([v])
+        })
+    }
+
+    #[test]
+    fn test_stream_encoder_matches_parser() {
+        let im = sample(96, 300);
+        for mode in [DitherMode::Threshold, DitherMode::FloydSteinberg] {
+            let b = Bitmap::from_gray_image(&im, mode);
+            let expected: Vec<PrintCommand> = BitmapParser::new(b.clone(), 50).collect();
+            let mut e = StreamEncoder::new(GrayImageSource::new(im.clone()), mode, 50);
+            let got: Vec<PrintCommand> = e.by_ref().collect();
+            let data: Vec<u8> = got
+                .iter()
+                .filter_map(|c| c.parse().unwrap())
+                .flatten()
+                .flatten()
+                .collect();
+            let pages = render_commands(96, &data).unwrap();
+            assert!(pages[0] == b, "unexcepted result: {mode:?}");
+            // 断点位置和 BitmapParser 相同
+            let bp =
+                |x: &[PrintCommand]| x.iter().filter(|c| **c == PrintCommand::Breakpoint).count();
+            assert_eq!(bp(&got), bp(&expected));
+            assert_eq!(e.stats().bytes, data.len());
+            assert_eq!(e.lines(), 300);
+        }
+    }
+
+    fn strips() -> impl Iterator<Item = Pixmap> {
+        let mut a = Pixmap::new(8, 2).unwrap();
+        a.fill(tiny_skia::Color::BLACK);
+        let mut b = Pixmap::new(4, 3).unwrap();
+        b.fill(tiny_skia::Color::WHITE);
+        [a, b].into_iter()
+    }
+
+    #[test]
+    fn test_pixmap_source() {
+        let mut s = PixmapSource::new(8, strips());
+        assert_eq!(s.width(), 8);
+        let rows: Vec<Vec<u8>> = std::iter::from_fn(|| s.next_row()).collect();
+        assert_eq!(rows.len(), 5);
+        assert_eq!(rows[0], vec![0; 8]);
+        assert_eq!(rows[4], vec![255; 4]);
+
+        let bm = collect_bitmap(PixmapSource::new(8, strips()), DitherMode::Threshold);
+        assert_eq!((bm.width(), bm.height()), (8, 5));
+        assert!(bm.get_pixel(7, 1));
+        assert!(!bm.get_pixel(7, 2));
+    }
+
+    #[test]
+    fn test_stream_encoder_geometry() {
+        let im = sample(96, 300);
+        let g = LabelGeometry::new(64, 120, 16).centred(128);
+        let mode = DitherMode::Threshold;
+        let got: Vec<PrintCommand> = StreamEncoder::new(GrayImageSource::new(im.clone()), mode, 0)
+            .with_geometry(g)
+            .collect();
+        assert_eq!(got[0], PrintCommand::LabelWidth(96));
+        let data: Vec<u8> = got
+            .iter()
+            .filter_map(|c| c.parse().unwrap())
+            .flatten()
+            .flatten()
+            .collect();
+        let pages = render_commands(96, &data).unwrap();
+        let expected = g.fit(&collect_bitmap(GrayImageSource::new(im), mode));
+        assert_eq!(expected.height(), 120);
+        assert!(pages[0] == expected);
+    }
+
+    #[tokio::test]
+    async fn test_enable_high_commands() {
+        let (b, rx) = USBBackend::mock();
+        let printer = fake_printer(rx, |op, _| if op == 0x80 { vec![0x7f] } else { vec![0] });
+        read_status(&b).await.unwrap();
+        read_sensor(&b, 0x01).await.unwrap();
+        read_sensor(&b, 0x01).await.unwrap();
+        drop(b);
+        // 第一条 0x88 之前激活一次, 0x70 不需要
+        let log = printer.await.unwrap();
+        let ops: Vec<u8> = log.iter().map(|x| x[1]).collect();
+        assert_eq!(ops, [0x70, 0x80, 0x88, 0x88]);
+        assert_eq!(log[1], [0x1f, 0x80, 0x01, 0x7f, 0xff]);
+
+        // 打印机不同意
+        let (b, rx) = USBBackend::mock();
+        let _printer = fake_printer(rx, |_, _| vec![0]);
+        assert!(matches!(
+            read_sensor(&b, 0x01).await,
+            Err(PipelineError::HighCommands(x)) if x == [0]
+        ));
+    }
+}
@@ -0,0 +1,134 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! 传感器数据 (`0x88`)
+//!
+//! - 子命令 `0x01`: 打印头温度, 后面还有几个大端 u16 数值, 其中之一是间隙传感器
+//! - 子命令 `0x02`: 电池电压和充电状态, 没有电池的型号返回的数据比较短
+
+use std::{ops::ControlFlow, time::Duration};
+
+use thiserror::Error;
+
+use super::{read_sensor, PipelineError};
+use crate::backend;
+
+/// 读取打印头温度的子命令
+pub const SENSOR_HEAD: u8 = 0x01;
+/// 读取电池电压和充电状态的子命令
+pub const SENSOR_BATTERY: u8 = 0x02;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum SensorError {
+    #[error("malformed sensor response: {0:02x?}")]
+    Malformed(Vec<u8>),
+}
+
+/// 一次读取的传感器数据
+#[derive(Debug, Clone, PartialEq)]
+pub struct SensorReading {
+    /// 打印头温度 (°C)
+    pub head_temperature: f32,
+    /// 电池电压 (V), 没有电池时为 `None`
+    pub battery_voltage: Option<f32>,
+    /// 充电状态, 含义还没有确认
+    pub charge_status: Option<u8>,
+    /// 温度后面的原始数值, 其中之一是间隙传感器
+    pub gap_sensor: Vec<u16>,
+}
+
+impl SensorReading {
+    /// 解析子命令 `0x01` 和 `0x02` 的回复
+    pub fn parse(head: &[u8], battery: &[u8]) -> Result<Self, SensorError> {
+        let channels = sensor_channels(head);
+        if head.first() != Some(&SENSOR_HEAD) || channels.is_empty() {
+            return Err(SensorError::Malformed(head.to_vec()));
+        }
+        if battery.first() != Some(&SENSOR_BATTERY) {
+            return Err(SensorError::Malformed(battery.to_vec()));
+        }
+        let battery_voltage = battery
+            .get(7..9)
+            .map(|x| u16::from_be_bytes([x[0], x[1]]) as f32 * 0.01);
+        Ok(SensorReading {
+            head_temperature: channels[0] as i16 as f32 * 0.1,
+            battery_voltage,
+            charge_status: battery.get(10).copied(),
+            gap_sensor: channels[1..].to_vec(),
+        })
+    }
+}
+
+/// 把 `0x88` 返回的数据拆成大端 u16 数值, 跳过第 0 个字节 (子命令)
+pub fn sensor_channels(payload: &[u8]) -> Vec<u16> {
+    payload
+        .get(1..)
+        .unwrap_or_default()
+        .chunks_exact(2)
+        .map(|x| u16::from_be_bytes([x[0], x[1]]))
+        .collect()
+}
+
+/// 读取全部传感器
+pub async fn read_sensors(b: &backend::USBBackend) -> Result<SensorReading, PipelineError> {
+    let head = read_sensor(b, SENSOR_HEAD).await?;
+    let battery = read_sensor(b, SENSOR_BATTERY).await?;
+    Ok(SensorReading::parse(&head, &battery)?)
+}
+
+/// 每隔 `interval` 读取一次传感器, 直到 `f` 返回 [ControlFlow::Break]
+pub async fn poll_sensors<T>(
+    b: &backend::USBBackend,
+    interval: Duration,
+    mut f: impl FnMut(&SensorReading) -> ControlFlow<T>,
+) -> Result<T, PipelineError> {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        if let ControlFlow::Break(x) = f(&read_sensors(b).await?) {
+            return Ok(x);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{sensor_channels, SensorError, SensorReading};
+
+    #[test]
+    fn test_parse() {
+        let head = [0x01, 0x01, 0x2c, 0x03, 0x20, 0x00, 0x10];
+        let battery = [0x02, 0, 0, 0, 0, 0, 0, 0x01, 0xa4, 0, 0x01];
+        let r = SensorReading::parse(&head, &battery).unwrap();
+        assert_eq!(r.head_temperature, 30.0);
+        assert!((r.battery_voltage.unwrap() - 4.2).abs() < 1e-6);
+        assert_eq!(r.charge_status, Some(1));
+        assert_eq!(r.gap_sensor, vec![800, 16]);
+
+        // 没有电池, 温度为负数
+        let r = SensorReading::parse(&[0x01, 0xff, 0xce], &[0x02, 0x00]).unwrap();
+        assert_eq!(r.head_temperature, -5.0);
+        assert_eq!(r.battery_voltage, None);
+        assert_eq!(r.charge_status, None);
+        assert!(r.gap_sensor.is_empty());
+
+        assert_eq!(
+            SensorReading::parse(&[0x01], &[0x02]),
+            Err(SensorError::Malformed(vec![0x01]))
+        );
+        assert_eq!(
+            SensorReading::parse(&head, &[0x01]),
+            Err(SensorError::Malformed(vec![0x01]))
+        );
+    }
+
+    #[test]
+    fn test_sensor_channels() {
+        assert_eq!(
+            sensor_channels(&[0x01, 0x01, 0x2c, 0x00, 0x10, 0xff]),
+            vec![300, 16]
+        );
+        assert_eq!(sensor_channels(&[]), Vec::<u16>::new());
+    }
+}
@@ -0,0 +1,257 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::collections::VecDeque;
+
+use image::GrayImage;
+use tiny_skia::{FillRule, Paint, PathBuilder, Pixmap, Transform};
+use ttf_parser::{Face, OutlineBuilder};
+
+use super::PipelineError;
+use crate::image_proc::pixel_luma;
+
+/// 逐行产生灰度数据的来源, 0 为黑色, 255 为白色
+pub trait RowSource {
+    /// 每行的点数
+    fn width(&self) -> u32;
+
+    /// 下一行, 没有更多内容时返回 `None`
+    ///
+    /// 长度不足宽度的部分视为白色, 超出的部分会被丢弃
+    fn next_row(&mut self) -> Option<Vec<u8>>;
+}
+
+impl<S: RowSource + ?Sized> RowSource for Box<S> {
+    fn width(&self) -> u32 {
+        (**self).width()
+    }
+
+    fn next_row(&mut self) -> Option<Vec<u8>> {
+        (**self).next_row()
+    }
+}
+
+/// 已经解码的灰度图片
+pub struct GrayImageSource {
+    im: GrayImage,
+    cursor: u32,
+}
+
+impl GrayImageSource {
+    pub fn new(im: GrayImage) -> Self {
+        GrayImageSource { im, cursor: 0 }
+    }
+}
+
+impl RowSource for GrayImageSource {
+    fn width(&self) -> u32 {
+        self.im.width()
+    }
+
+    fn next_row(&mut self) -> Option<Vec<u8>> {
+        if self.cursor >= self.im.height() {
+            return None;
+        }
+        let w = self.im.width() as usize;
+        let start = self.cursor as usize * w;
+        self.cursor += 1;
+        Some(self.im.as_raw()[start..start + w].to_vec())
+    }
+}
+
+/// 一串依次拼接的 [Pixmap], 比如逐页/逐段渲染的 Typst 文档
+///
+/// 每段用完后立即释放, 同一时间只保存一段
+pub struct PixmapSource<I> {
+    width: u32,
+    strips: I,
+    current: Option<Pixmap>,
+    cursor: u32,
+}
+
+impl<I: Iterator<Item = Pixmap>> PixmapSource<I> {
+    /// - width: 每行的点数, 和每段的宽度不同时会被裁剪或者补白
+    pub fn new(width: u32, strips: I) -> Self {
+        PixmapSource {
+            width,
+            strips,
+            current: None,
+            cursor: 0,
+        }
+    }
+}
+
+impl<I: Iterator<Item = Pixmap>> RowSource for PixmapSource<I> {
+    fn width(&self) -> u32 {
+        self.width
+    }
+
+    fn next_row(&mut self) -> Option<Vec<u8>> {
+        loop {
+            if let Some(pm) = &self.current {
+                if self.cursor < pm.height() {
+                    let w = pm.width() as usize;
+                    let start = self.cursor as usize * w;
+                    self.cursor += 1;
+                    return Some(
+                        pm.pixels()[start..start + w]
+                            .iter()
+                            .map(|px| pixel_luma(*px))
+                            .collect(),
+                    );
+                }
+            }
+            self.current = Some(self.strips.next()?);
+            self.cursor = 0;
+        }
+    }
+}
+
+/// 把文本逐行渲染出来, 适合打印日志之类的连续内容
+///
+/// 超出宽度的文本会自动换行
+pub struct TextSource<I> {
+    font: Vec<u8>,
+    width: u32,
+    size: f32,
+    lines: I,
+    rows: VecDeque<Vec<u8>>,
+}
+
+impl<I: Iterator<Item = String>> TextSource<I> {
+    /// - font: TrueType/OpenType 字体文件
+    /// - width: 每行的点数
+    /// - size: 字号 (点数)
+    /// - lines: 要打印的文本, 每个元素是一行
+    pub fn new(font: Vec<u8>, width: u32, size: f32, lines: I) -> Result<Self, PipelineError> {
+        Face::parse(&font, 0).map_err(|e| PipelineError::InvalidFont(e.to_string()))?;
+        Ok(TextSource {
+            font,
+            width,
+            size,
+            lines,
+            rows: VecDeque::new(),
+        })
+    }
+
+    fn render_line(&mut self, text: &str) {
+        // 已经在 new() 检查过
+        let face = Face::parse(&self.font, 0).unwrap();
+        let scale = self.size / face.units_per_em() as f32;
+        let ascender = face.ascender() as f32 * scale;
+        let line_height =
+            ((face.ascender() - face.descender() + face.line_gap()) as f32 * scale).ceil() as u32;
+        let line_height = line_height.max(1);
+
+        let mut glyphs = Vec::new();
+        for c in text.chars() {
+            let c = if c == '\t' { ' ' } else { c };
+            let id = face.glyph_index(c).unwrap_or_default();
+            let advance = face.glyph_hor_advance(id).unwrap_or_default() as f32 * scale;
+            glyphs.push((id, advance));
+        }
+
+        // 按宽度断行, 空行也要占一行
+        let mut visual_lines = vec![vec![]];
+        let mut x = 0.0;
+        for g in glyphs {
+            if x + g.1 > self.width as f32 && x > 0.0 {
+                visual_lines.push(vec![]);
+                x = 0.0;
+            }
+            visual_lines.last_mut().unwrap().push(g);
+            x += g.1;
+        }
+
+        let mut paint = Paint::default();
+        paint.set_color_rgba8(0, 0, 0, 255);
+        for glyphs in visual_lines {
+            let Some(mut pm) = Pixmap::new(self.width.max(1), line_height) else {
+                continue;
+            };
+            pm.fill(tiny_skia::Color::WHITE);
+            let mut sink = GlyphSink {
+                pb: PathBuilder::new(),
+                x: 0.0,
+                baseline: ascender,
+                scale,
+            };
+            for (id, advance) in glyphs {
+                face.outline_glyph(id, &mut sink);
+                sink.x += advance;
+            }
+            if let Some(path) = sink.pb.finish() {
+                pm.fill_path(
+                    &path,
+                    &paint,
+                    FillRule::Winding,
+                    Transform::identity(),
+                    None,
+                );
+            }
+            let w = pm.width() as usize;
+            for row in pm.pixels().chunks(w) {
+                self.rows
+                    .push_back(row.iter().map(|px| pixel_luma(*px)).collect());
+            }
+        }
+    }
+}
+
+impl<I: Iterator<Item = String>> RowSource for TextSource<I> {
+    fn width(&self) -> u32 {
+        self.width
+    }
+
+    fn next_row(&mut self) -> Option<Vec<u8>> {
+        while self.rows.is_empty() {
+            let line = self.lines.next()?;
+            self.render_line(&line);
+        }
+        self.rows.pop_front()
+    }
+}
+
+/// 把字形轮廓转换为 [PathBuilder] 中的路径
+struct GlyphSink {
+    pb: PathBuilder,
+    x: f32,
+    baseline: f32,
+    scale: f32,
+}
+
+impl GlyphSink {
+    fn map(&self, x: f32, y: f32) -> (f32, f32) {
+        (self.x + x * self.scale, self.baseline - y * self.scale)
+    }
+}
+
+impl OutlineBuilder for GlyphSink {
+    fn move_to(&mut self, x: f32, y: f32) {
+        let (x, y) = self.map(x, y);
+        self.pb.move_to(x, y);
+    }
+
+    fn line_to(&mut self, x: f32, y: f32) {
+        let (x, y) = self.map(x, y);
+        self.pb.line_to(x, y);
+    }
+
+    fn quad_to(&mut self, x1: f32, y1: f32, x: f32, y: f32) {
+        let (x1, y1) = self.map(x1, y1);
+        let (x, y) = self.map(x, y);
+        self.pb.quad_to(x1, y1, x, y);
+    }
+
+    fn curve_to(&mut self, x1: f32, y1: f32, x2: f32, y2: f32, x: f32, y: f32) {
+        let (x1, y1) = self.map(x1, y1);
+        let (x2, y2) = self.map(x2, y2);
+        let (x, y) = self.map(x, y);
+        self.pb.cubic_to(x1, y1, x2, y2, x, y);
+    }
+
+    fn close(&mut self) {
+        self.pb.close();
+    }
+}
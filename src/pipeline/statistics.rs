@@ -0,0 +1,141 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! 打印统计 (`0x73`), 打印机出厂以来的累计数值; 以及分辨率 (`0x71`) 和打印宽度 (`0x72`)
+
+use thiserror::Error;
+
+use super::{query, PipelineError};
+use crate::{backend, command::HostCommand};
+
+#[derive(Error, Debug, PartialEq)]
+pub enum StatisticsError {
+    #[error("malformed statistics response: {0:02x?}")]
+    Malformed(Vec<u8>),
+}
+
+/// 累计统计, 行数的单位是点
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PrintStatistics {
+    /// 走纸行数
+    pub work_lines: u32,
+    /// 打印的行数
+    pub print_lines: u32,
+    /// 空白行数
+    pub null_lines: u32,
+    /// 打印的张数
+    pub print_pages: u32,
+}
+
+impl PrintStatistics {
+    /// 解析回复, 四个大端 u32
+    pub fn parse(payload: &[u8]) -> Result<Self, StatisticsError> {
+        let values: Vec<u32> = payload
+            .chunks_exact(4)
+            .map(|x| u32::from_be_bytes([x[0], x[1], x[2], x[3]]))
+            .collect();
+        let [work_lines, print_lines, null_lines, print_pages, ..] = values[..] else {
+            return Err(StatisticsError::Malformed(payload.to_vec()));
+        };
+        Ok(PrintStatistics {
+            work_lines,
+            print_lines,
+            null_lines,
+            print_pages,
+        })
+    }
+}
+
+/// 打印宽度 (`0x72`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PrintWidth {
+    /// 打印头的点数
+    pub head_dots: u16,
+    /// 出纸宽度, 单位 0.1 mm
+    pub paper: u16,
+}
+
+impl PrintWidth {
+    /// 解析回复, 两个大端 u16, 后面的字节还不知道是什么
+    pub fn parse(payload: &[u8]) -> Result<Self, StatisticsError> {
+        match payload[..] {
+            [a, b, c, d, ..] => Ok(PrintWidth {
+                head_dots: u16::from_be_bytes([a, b]),
+                paper: u16::from_be_bytes([c, d]),
+            }),
+            _ => Err(StatisticsError::Malformed(payload.to_vec())),
+        }
+    }
+
+    /// 出纸宽度 (mm)
+    pub fn paper_mm(&self) -> f32 {
+        self.paper as f32 / 10.0
+    }
+}
+
+/// 把行数换算成纸的长度 (m)
+pub fn lines_to_metres(lines: u32, dpi: u16) -> f64 {
+    lines as f64 / dpi.max(1) as f64 * 0.0254
+}
+
+/// 读取打印统计
+pub async fn statistics(b: &backend::USBBackend) -> Result<PrintStatistics, PipelineError> {
+    let payload = query(b, HostCommand::ReadStatistics, vec![]).await?;
+    Ok(PrintStatistics::parse(&payload)?)
+}
+
+/// 读取打印分辨率 (`0x71`), 大端 u16
+pub async fn read_dpi(b: &backend::USBBackend) -> Result<u16, PipelineError> {
+    let payload = query(b, HostCommand::ReadDpi, vec![]).await?;
+    match payload[..] {
+        [hi, lo, ..] => Ok(u16::from_be_bytes([hi, lo])),
+        _ => Err(StatisticsError::Malformed(payload).into()),
+    }
+}
+
+/// 读取打印宽度 (`0x72`)
+pub async fn read_print_width(b: &backend::USBBackend) -> Result<PrintWidth, PipelineError> {
+    let payload = query(b, HostCommand::ReadPrintWidth, vec![]).await?;
+    Ok(PrintWidth::parse(&payload)?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{lines_to_metres, PrintStatistics, PrintWidth, StatisticsError};
+
+    #[test]
+    fn test_parse() {
+        let payload = [
+            0x00, 0x01, 0xb6, 0x4b, 0x00, 0x01, 0x76, 0xce, 0x00, 0x00, 0x71, 0x58, 0x00, 0x00,
+            0x00, 0xde,
+        ];
+        assert_eq!(
+            PrintStatistics::parse(&payload),
+            Ok(PrintStatistics {
+                work_lines: 112203,
+                print_lines: 95950,
+                null_lines: 29016,
+                print_pages: 222,
+            })
+        );
+        assert_eq!(
+            PrintStatistics::parse(&payload[..12]),
+            Err(StatisticsError::Malformed(payload[..12].to_vec()))
+        );
+    }
+
+    #[test]
+    fn test_print_width() {
+        let width = PrintWidth::parse(&[0x02, 0x40, 0x02, 0x3a, 0, 0, 0, 0, 0]).unwrap();
+        assert_eq!(width.head_dots, 576);
+        assert_eq!(width.paper_mm(), 57.0);
+        assert!(PrintWidth::parse(&[0x02]).is_err());
+    }
+
+    #[test]
+    fn test_metres() {
+        assert!((lines_to_metres(112203, 300) - 9.4998).abs() < 1e-3);
+        assert!((lines_to_metres(8000, 203) - 1.0010).abs() < 1e-3);
+    }
+}
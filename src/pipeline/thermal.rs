@@ -0,0 +1,242 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! 打印头过热保护: 在断点检查时读取打印头温度, 温度高时放慢或者暂停, 冷却后自动继续
+
+use std::time::{Duration, Instant};
+
+use thiserror::Error;
+use tracing::{info, warn};
+
+use super::{enable_high_commands, sensor::read_sensors, PipelineError};
+use crate::{backend, error_code::PrinterErrorCode};
+
+#[derive(Error, Debug, PartialEq)]
+pub enum ThermalError {
+    #[error(
+        "resume temperature {resume_below} °C must be below the pause temperature {pause_above} °C"
+    )]
+    InvertedLimits { pause_above: f32, resume_below: f32 },
+}
+
+/// 温度阈值 (°C)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ThermalLimits {
+    /// 超过这个温度时, 每个断点后等待 `slow_delay`
+    pub slow_above: f32,
+    /// 超过这个温度时暂停, 直到降到 `resume_below` 以下
+    pub pause_above: f32,
+    pub resume_below: f32,
+    pub slow_delay: Duration,
+    /// 暂停时读取温度的间隔
+    pub poll_interval: Duration,
+    /// 暂停超过这个时间时放弃打印
+    pub max_pause: Duration,
+}
+
+impl Default for ThermalLimits {
+    fn default() -> Self {
+        ThermalLimits {
+            slow_above: 55.0,
+            pause_above: 65.0,
+            resume_below: 50.0,
+            slow_delay: Duration::from_millis(300),
+            poll_interval: Duration::from_secs(2),
+            max_pause: Duration::from_secs(300),
+        }
+    }
+}
+
+/// 各型号的阈值, 按设备名或者序列号的前缀匹配, 没有匹配时使用默认值
+///
+/// 固件报 `TphTooHot` 的温度还没有测出来, 这里的数值都偏保守
+const MODEL_LIMITS: &[(&str, ThermalLimits)] = &[(
+    // 电池供电的型号, 打印头散热比较差
+    "DP",
+    ThermalLimits {
+        slow_above: 50.0,
+        pause_above: 60.0,
+        resume_below: 45.0,
+        slow_delay: Duration::from_millis(500),
+        poll_interval: Duration::from_secs(2),
+        max_pause: Duration::from_secs(300),
+    },
+)];
+
+impl ThermalLimits {
+    /// 按型号选择阈值, `name` 可以是设备名 (`DP27P`) 或者序列号 (`DP27P-Y4094C023`)
+    pub fn for_model(name: &str) -> Self {
+        MODEL_LIMITS
+            .iter()
+            .find(|(prefix, _)| name.starts_with(prefix))
+            .map(|(_, x)| *x)
+            .unwrap_or_default()
+    }
+
+    /// 修改暂停和继续的温度, 继续的温度必须低于暂停的温度, 否则暂停后永远不会继续
+    pub fn with_pause(mut self, pause_above: f32, resume_below: f32) -> Result<Self, ThermalError> {
+        // NaN 无法比较, 也会被拒绝
+        if resume_below.partial_cmp(&pause_above) != Some(std::cmp::Ordering::Less) {
+            return Err(ThermalError::InvertedLimits {
+                pause_above,
+                resume_below,
+            });
+        }
+        self.pause_above = pause_above;
+        self.resume_below = resume_below;
+        Ok(self)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ThermalAction {
+    Continue,
+    /// 等待一段时间再继续
+    Slow(Duration),
+    /// 暂停, 直到冷却
+    Pause,
+}
+
+/// 过热保护状态
+pub struct ThermalGuard {
+    limits: ThermalLimits,
+    paused: bool,
+    /// 暂停和放慢的总时间
+    waited: Duration,
+}
+
+impl ThermalGuard {
+    pub fn new(limits: ThermalLimits) -> Self {
+        ThermalGuard {
+            limits,
+            paused: false,
+            waited: Duration::ZERO,
+        }
+    }
+
+    /// 因为温度等待的总时间
+    pub fn waited(&self) -> Duration {
+        self.waited
+    }
+
+    /// 根据温度决定下一步, 暂停后要降到 `resume_below` 以下才继续
+    pub fn decide(&mut self, temperature: f32) -> ThermalAction {
+        let l = &self.limits;
+        if temperature >= l.pause_above || (self.paused && temperature >= l.resume_below) {
+            self.paused = true;
+            return ThermalAction::Pause;
+        }
+        self.paused = false;
+        if temperature >= l.slow_above {
+            ThermalAction::Slow(l.slow_delay)
+        } else {
+            ThermalAction::Continue
+        }
+    }
+
+    /// 打印机已经报告过热, 冷却前不再发送数据
+    pub fn overheated(&mut self) {
+        self.paused = true;
+    }
+
+    /// 在断点处调用: 读取温度, 需要时等待
+    pub async fn check(&mut self, b: &backend::USBBackend) -> Result<(), PipelineError> {
+        // 读温度的 `0x88` 需要先激活
+        enable_high_commands(b).await?;
+        let start = Instant::now();
+        let mut paused = false;
+        loop {
+            let t = read_sensors(b).await?.head_temperature;
+            match self.decide(t) {
+                ThermalAction::Continue => break,
+                ThermalAction::Slow(d) => {
+                    tokio::time::sleep(d).await;
+                    break;
+                }
+                ThermalAction::Pause => {
+                    if start.elapsed() >= self.limits.max_pause {
+                        return Err(PipelineError::Printer(PrinterErrorCode::TphTooHot));
+                    }
+                    if !paused {
+                        warn!("print head at {t:.1} °C, pausing");
+                    }
+                    paused = true;
+                    tokio::time::sleep(self.limits.poll_interval).await;
+                }
+            }
+        }
+        if paused {
+            info!("print head cooled down, resuming");
+        }
+        self.waited += start.elapsed();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{ThermalAction, ThermalError, ThermalGuard, ThermalLimits};
+    use crate::{backend::USBBackend, pipeline::fake_printer};
+
+    #[test]
+    fn test_decide() {
+        let limits = ThermalLimits::default();
+        let mut g = ThermalGuard::new(limits);
+        assert_eq!(g.decide(30.0), ThermalAction::Continue);
+        assert_eq!(g.decide(56.0), ThermalAction::Slow(limits.slow_delay));
+        assert_eq!(g.decide(66.0), ThermalAction::Pause);
+        // 冷却到 resume_below 以下才继续
+        assert_eq!(g.decide(58.0), ThermalAction::Pause);
+        assert_eq!(g.decide(51.0), ThermalAction::Pause);
+        assert_eq!(g.decide(49.0), ThermalAction::Continue);
+        assert_eq!(g.decide(56.0), ThermalAction::Slow(limits.slow_delay));
+
+        g.overheated();
+        assert_eq!(g.decide(52.0), ThermalAction::Pause);
+        assert_eq!(g.decide(40.0), ThermalAction::Continue);
+        assert_eq!(g.waited(), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_for_model() {
+        assert_eq!(ThermalLimits::for_model("DT20"), ThermalLimits::default());
+        assert_eq!(
+            ThermalLimits::for_model("DP27P-Y4094C023").pause_above,
+            60.0
+        );
+    }
+
+    #[test]
+    fn test_with_pause() {
+        let limits = ThermalLimits::default().with_pause(70.0, 55.0).unwrap();
+        assert_eq!((limits.pause_above, limits.resume_below), (70.0, 55.0));
+        for (pause, resume) in [(50.0, 60.0), (60.0, 60.0), (f32::NAN, 50.0)] {
+            assert!(matches!(
+                ThermalLimits::default().with_pause(pause, resume),
+                Err(ThermalError::InvertedLimits { .. })
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_check() {
+        let (b, rx) = USBBackend::mock();
+        // 打印头 30 °C, 没有电池
+        let printer = fake_printer(rx, |op, args| match (op, args) {
+            (0x80, _) => vec![0x7f],
+            (0x88, [0x01]) => vec![0x01, 0x01, 0x2c],
+            (0x88, [sub]) => vec![*sub],
+            _ => vec![0],
+        });
+        let mut g = ThermalGuard::new(ThermalLimits::default());
+        g.check(&b).await.unwrap();
+        g.check(&b).await.unwrap();
+        drop(b);
+        let log = printer.await.unwrap();
+        let ops: Vec<u8> = log.iter().map(|x| x[1]).collect();
+        assert_eq!(ops, [0x80, 0x88, 0x88, 0x88, 0x88]);
+    }
+}
@@ -2,156 +2,139 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-// wip
-
-/// RLE encode processing function
-#[allow(clippy::ptr_arg, clippy::needless_return)]
-pub fn m304a(arr: &mut Vec<i8>, dzint: &mut i32, b: i8, mut i: i32, line_bytes: i32) -> bool {
-    while i >= 63 {
-        if *dzint + 2 > line_bytes {
-            return false;
-        }
+//! SDK 里的 RLE 压缩, 格式和 PCX 相同:
+//!
+//! - 最高两位是 `11` 的字节表示重复, 低 6 位是次数 (1~63), 下一个字节是要重复的值
+//! - 其他字节原样输出
+//!
+//! 打印机上对应的压缩打印命令还没有确认, 所以目前只用于估算压缩后的数据量
+
+use thiserror::Error;
 
-        arr[{
-            let x = *dzint;
-            *dzint = x + 1;
-            x as usize
-        }] = -1;
+/// 每个重复标记最多表示的次数
+const MAX_RUN: usize = 63;
+const RUN_FLAG: u8 = 0b1100_0000;
 
-        arr[{
-            let x = *dzint;
-            *dzint = x + 1;
-            x as usize
-        }] = b;
+#[derive(Error, Debug, PartialEq)]
+pub enum RleError {
+    #[error("missing value after run marker at offset {0}")]
+    Truncated(usize),
+}
 
-        i -= 63;
+/// 压缩
+pub fn encode(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    for run in data.chunk_by(|a, b| a == b) {
+        push_run(&mut out, run[0], run.len());
     }
+    out
+}
 
-    match i {
-        1 => {
-            if (b as u8) > 192 {
-                if *dzint + 2 <= line_bytes {
-                    arr[{
-                        let x = *dzint;
-                        *dzint = x + 1;
-                        x as usize
-                    }] = -63;
-
-                    arr[{
-                        let x = *dzint;
-                        *dzint = x + 1;
-                        x as usize
-                    }] = b;
-                } else {
-                    return false;
-                }
-            } else {
-                if *dzint < line_bytes {
-                    arr[{
-                        let x = *dzint;
-                        *dzint = x + 1;
-                        x as usize
-                    }] = b;
-                    return true;
-                }
-                return false;
-            }
-        }
-        2 => {
-            if *dzint + 2 <= line_bytes {
-                if (b as u8) > 192 {
-                    arr[{
-                        let x = *dzint;
-                        *dzint = x + 1;
-                        x as usize
-                    }] = -62;
-
-                    arr[{
-                        let x = *dzint;
-                        *dzint = x + 1;
-                        x as usize
-                    }] = b;
-                } else {
-                    arr[{
-                        let x = *dzint;
-                        *dzint = x + 1;
-                        x as usize
-                    }] = b;
-
-                    arr[{
-                        let x = *dzint;
-                        *dzint = x + 1;
-                        x as usize
-                    }] = b;
-                }
-                return false;
-            }
-        }
-        _ => {
-            if i > 0 {
-                if *dzint + 2 <= line_bytes {
-                    arr[{
-                        let x = *dzint;
-                        *dzint = x + 1;
-                        x as usize
-                    }] = (i | 192) as i8;
-
-                    arr[{
-                        let x = *dzint;
-                        *dzint = x + 1;
-                        x as usize
-                    }] = b;
-                } else {
-                    return false;
-                }
-            }
-            return true;
+/// 压缩后的字节数, 不分配内存
+pub fn encoded_len(data: &[u8]) -> usize {
+    data.chunk_by(|a, b| a == b)
+        .map(|run| run_len(run[0], run.len()))
+        .sum()
+}
+
+/// 解压
+pub fn decode(data: &[u8]) -> Result<Vec<u8>, RleError> {
+    let mut out = Vec::with_capacity(data.len() * 2);
+    let mut iter = data.iter().enumerate();
+    while let Some((idx, b)) = iter.next() {
+        if b & RUN_FLAG == RUN_FLAG {
+            let (_, value) = iter.next().ok_or(RleError::Truncated(idx))?;
+            let count = (b & !RUN_FLAG) as usize;
+            out.extend(std::iter::repeat_n(*value, count));
+        } else {
+            out.push(*b);
         }
     }
-    false
+    Ok(out)
 }
 
-/// RLE encode
-#[allow(clippy::ptr_arg, clippy::needless_return)]
-pub fn m305a(arr: Vec<i8>, i: i32, arr2: &mut Vec<i8>, line_bytes: i32) -> i32 {
-    if i > 0 {
-        let mut dzint = 0;
-        let mut b = arr[0];
-        let mut i2 = 1;
-        for i3 in 1..i {
-            if arr[i3 as usize] == b {
-                i2 += 1;
-            } else {
-                if !m304a(arr2, &mut dzint, b, i2, line_bytes) {
-                    return 0;
-                }
-                b = arr[i3 as usize];
-                i2 = 1;
-            }
-        }
-        if !m304a(arr2, &mut dzint, b, i2, line_bytes) {
-            return 0;
-        }
-        return dzint;
-    } else {
-        return 0;
+fn push_run(out: &mut Vec<u8>, value: u8, mut count: usize) {
+    while count >= MAX_RUN {
+        out.extend([RUN_FLAG | MAX_RUN as u8, value]);
+        count -= MAX_RUN;
+    }
+    // 会被当作重复标记的值必须转义
+    let escape = value & RUN_FLAG == RUN_FLAG;
+    match count {
+        0 => {}
+        1 | 2 if !escape => out.extend(std::iter::repeat_n(value, count)),
+        _ => out.extend([RUN_FLAG | count as u8, value]),
     }
 }
 
-/// RLE5 encode
-pub fn m307a() {}
+fn run_len(value: u8, count: usize) -> usize {
+    let escape = value & RUN_FLAG == RUN_FLAG;
+    let rest = match count % MAX_RUN {
+        0 => 0,
+        r @ (1 | 2) if !escape => r,
+        _ => 2,
+    };
+    count / MAX_RUN * 2 + rest
+}
 
 #[cfg(test)]
 mod test {
-    use super::m305a;
+    use super::{decode, encode, encoded_len, RleError};
+
+    #[test]
+    fn test_rle_encode() {
+        let x = encode(&[0, 111, 1, 2, 2, 2, 2, 3, 4, 4, 5, 5, 5, 6]);
+        assert_eq!(
+            x,
+            vec![0, 111, 1, 0xc4, 2, 3, 4, 4, 0xc3, 5, 6],
+            "unexcepted result: {:02x?}",
+            x
+        );
+
+        let x = encode(&[0xc0, 0xff, 0xff, 0xbf]);
+        assert_eq!(
+            x,
+            vec![0xc1, 0xc0, 0xc2, 0xff, 0xbf],
+            "unexcepted result: {:02x?}",
+            x
+        );
+
+        let x = encode(&[0; 128]);
+        assert_eq!(
+            x,
+            vec![0xff, 0x00, 0xff, 0x00, 0x00, 0x00],
+            "unexcepted result: {:02x?}",
+            x
+        );
+
+        assert_eq!(encode(&[]), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn test_rle_round_trip() {
+        let mut x: u32 = 0x0604_1989;
+        for len in 0..300 {
+            let data: Vec<u8> = (0..len)
+                .map(|i| {
+                    x ^= x << 13;
+                    x ^= x >> 17;
+                    x ^= x << 5;
+                    // 多一些重复
+                    if (i / 5) % 2 == 0 {
+                        0xc0 | (len % 4) as u8
+                    } else {
+                        x as u8
+                    }
+                })
+                .collect();
+            let e = encode(&data);
+            assert_eq!(e.len(), encoded_len(&data), "unexcepted length: {len}");
+            assert_eq!(decode(&e).unwrap(), data, "round trip failed: {len}");
+        }
+    }
 
     #[test]
-    fn test_rle_m305a() {
-        let arr1: Vec<i8> = vec![0, 111, 1, 2, 2, 2, 2, 3, 4, 4, 5, 5, 5, 6];
-        let arr1_len = arr1.len();
-        let mut arr2: Vec<i8> = Vec::new();
-        arr2.resize(arr1.len(), 0);
-        m305a(arr1, arr1_len as i32, &mut arr2, 32);
-        println!("{arr2:?}");
+    fn test_rle_decode_error() {
+        assert_eq!(decode(&[1, 2, 0xc3]), Err(RleError::Truncated(2)));
     }
 }